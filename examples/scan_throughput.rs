@@ -0,0 +1,102 @@
+//! Measures how fast log files are scanned, comparing reading owned records
+//! line by line with scanning a memory-mapped file lazily and reading the
+//! columns of a cached file.
+//!
+//! Generates a logfmt log of the given size (in MiB, 2048 by default) and
+//! runs each scan over it:
+//!
+//!     cargo run --release --example scan_throughput -- 4096
+//!
+//! Pass an existing file as second argument to scan it instead of
+//! generating one (its size argument is then ignored).
+
+extern crate log_ql;
+extern crate tempfile;
+
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use log_ql::cache::Cache;
+use log_ql::context::QueryContext;
+use log_ql::functions::Value;
+use log_ql::mapped::MappedFile;
+use log_ql::record::Fields;
+use log_ql::source;
+
+const LEVELS: [&str; 4] = ["info", "info", "warn", "error"];
+
+fn generate(path: &Path, megabytes: u64) {
+    let mut out = BufWriter::new(File::create(path).unwrap());
+    let mut written = 0;
+    let mut n: u64 = 0;
+    while written < megabytes * 1024 * 1024 {
+        let line = format!(
+            "ts=2018-03-04T10:{:02}:{:02}Z level={} status={} latency={} request_id=req-{:08} msg=\"GET /api/items/{} completed\"\n",
+            (n / 60) % 60, n % 60, LEVELS[(n % 4) as usize], if n.is_multiple_of(50) { 503 } else { 200 }, n % 997, n, n % 1000
+        );
+        out.write_all(line.as_bytes()).unwrap();
+        written += line.len() as u64;
+        n += 1;
+    }
+}
+
+fn measure<F: FnOnce() -> u64>(name: &str, bytes: u64, scan: F) {
+    let start = Instant::now();
+    let matches = scan();
+    let seconds = start.elapsed().as_secs_f64();
+    println!("{:<32} {:>10} matches {:>8.2}s {:>10.1} MiB/s", name, matches, seconds, bytes as f64 / 1024.0 / 1024.0 / seconds);
+}
+
+fn main() {
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let megabytes = arguments.first().map(|size| size.parse().expect("size in MiB")).unwrap_or(2048);
+
+    let directory = tempfile::TempDir::new().unwrap();
+    let path = match arguments.get(1) {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => {
+            let path = directory.path().join("bench.log");
+            println!("Generating {} MiB of logs in {:?}", megabytes, path);
+            generate(&path, megabytes);
+            path
+        }
+    };
+    let name = path.to_string_lossy().into_owned();
+    let bytes = path.metadata().unwrap().len();
+    let error = Value::Str("error".into());
+
+    measure("owned records", bytes, || {
+        source::open(std::slice::from_ref(&name)).unwrap()
+            .filter(|record| record.as_ref().unwrap().get("level") == error)
+            .count() as u64
+    });
+
+    measure("mapped, lazily parsed records", bytes, || {
+        let file = MappedFile::open(&path).unwrap();
+        file.records()
+            .filter(|record| record.as_ref().unwrap().get("level") == error)
+            .count() as u64
+    });
+
+    let context = QueryContext::new();
+    measure("query with WHERE", bytes, || {
+        let result = context.execute(&format!("SELECT count(*) FROM '{}' WHERE level = 'error'", name)).unwrap();
+        result.rows[0][0].as_integer().unwrap() as u64
+    });
+    measure("query with LIKE", bytes, || {
+        let result = context.execute(&format!("SELECT count(*) FROM '{}' WHERE msg LIKE '%items/42 %'", name)).unwrap();
+        result.rows[0][0].as_integer().unwrap() as u64
+    });
+
+    let mut cached = QueryContext::new();
+    cached.set_cache(Cache::new(directory.path().join("cache")));
+    for name_of_scan in ["query converting into the cache", "query with WHERE from the cache"] {
+        measure(name_of_scan, bytes, || {
+            let result = cached.execute(&format!("SELECT count(*) FROM '{}' WHERE level = 'error'", name)).unwrap();
+            result.rows[0][0].as_integer().unwrap() as u64
+        });
+    }
+}
@@ -0,0 +1,216 @@
+//! A typed form of parsed statements, with a field for each clause of a
+//! query instead of the positional children of `ASTNode`, whose `LogResult`
+//! keeps the condition as its left child and the limit as its right one.
+//!
+//! Both forms convert into each other, so code written against `ASTNode`
+//! keeps working:
+//!
+//! ```
+//! use std::convert::TryFrom;
+//! use log_ql::ast::Query;
+//! use log_ql::parser::Parser;
+//!
+//! let ast = Parser::new("SELECT msg FROM 'app.log' WHERE level = 'error' LIMIT 10".into()).parse().unwrap();
+//! let query = Query::try_from(&ast).unwrap();
+//! assert_eq!(query.from, vec!("app.log".to_string()));
+//! assert_eq!(query.limit.map(|limit| limit.rows), Some(10));
+//! ```
+
+use std::convert::TryFrom;
+
+use parser::{ASTNode, Expression, GrammarItem, LimitDirection, SelectField, SortKey, Span, WhereComparator};
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum Statement {
+    Query(Query),
+    /// `EXPLAIN` of `query`, which is run first with `ANALYZE`.
+    Explain { analyze: bool, query: Query }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Query {
+    pub select: Vec<SelectField>,
+    /// The files read, `source::STDIN` standing for the standard input.
+    pub from: Vec<String>,
+    pub where_clause: Option<WhereClause>,
+    /// The expressions `GROUP BY` aggregates rows by.
+    pub group_by: Vec<Expression>,
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<Limit>,
+    /// Whether the query keeps following its files (`FOLLOW` or `TAIL`).
+    pub follow: bool,
+    pub span: Span
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct WhereClause {
+    pub field: Expression,
+    pub mode: WhereComparator,
+    pub value: Expression,
+    pub span: Span
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Limit {
+    pub rows: usize,
+    pub direction: LimitDirection,
+    pub span: Span
+}
+
+impl<'a> TryFrom<&'a ASTNode> for Statement {
+    type Error = String;
+
+    fn try_from(ast: &'a ASTNode) -> Result<Statement, String> {
+        match ast.entry {
+            GrammarItem::Explain { analyze } => {
+                let query = ast.left.as_ref().ok_or("Expected a query to explain")?;
+                Ok(Statement::Explain { analyze, query: Query::try_from(&**query)? })
+            },
+            _ => Ok(Statement::Query(Query::try_from(ast)?))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a ASTNode> for Query {
+    type Error = String;
+
+    fn try_from(ast: &'a ASTNode) -> Result<Query, String> {
+        if ast.entry != GrammarItem::Query {
+            return Err(format!("Expected a query, got {:?}", ast.entry));
+        }
+        let (select, from) = match ast.left.as_ref().map(|node| &node.entry) {
+            Some(GrammarItem::LogFile { fields, filenames }) => (fields.clone(), filenames.clone()),
+            _ => return Err("Expected query to select from a log file".into())
+        };
+
+        let mut query = Query { select, from, where_clause: None, group_by: vec!(), order_by: vec!(), limit: None, follow: false, span: ast.span };
+        if let Some(ref log_result) = ast.right {
+            match log_result.entry {
+                GrammarItem::LogResult { follow, ref group_by, ref order_by } => {
+                    query.follow = follow;
+                    query.group_by = group_by.clone();
+                    query.order_by = order_by.clone();
+                },
+                ref entry => return Err(format!("Expected the clauses of a query, got {:?}", entry))
+            }
+            for node in log_result.left.iter().chain(log_result.right.iter()) {
+                match node.entry {
+                    GrammarItem::Condition { ref field, ref mode, ref value } => {
+                        query.where_clause = Some(WhereClause { field: field.clone(), mode: mode.clone(), value: value.clone(), span: node.span });
+                    },
+                    GrammarItem::Limit { number_of_rows, ref direction } => {
+                        query.limit = Some(Limit { rows: number_of_rows, direction: direction.clone(), span: node.span });
+                    },
+                    ref entry => return Err(format!("Expected a condition or a limit, got {:?}", entry))
+                }
+            }
+        }
+        Ok(query)
+    }
+}
+
+impl From<Statement> for ASTNode {
+    fn from(statement: Statement) -> ASTNode {
+        match statement {
+            Statement::Query(query) => ASTNode::from(query),
+            Statement::Explain { analyze, query } => {
+                let span = query.span;
+                let mut ast = ASTNode::new(GrammarItem::Explain { analyze }, Some(Box::new(ASTNode::from(query))), None);
+                ast.span = span;
+                ast
+            }
+        }
+    }
+}
+
+/// Builds the shape the parser builds, without the spans of the select list
+/// and of the clauses together, which `Query` does not keep.
+impl From<Query> for ASTNode {
+    fn from(query: Query) -> ASTNode {
+        let log_file = ASTNode::new(GrammarItem::LogFile { fields: query.select, filenames: query.from }, None, None);
+
+        let condition = query.where_clause.map(|clause| {
+            let mut node = ASTNode::new(GrammarItem::Condition { field: clause.field, mode: clause.mode, value: clause.value }, None, None);
+            node.span = clause.span;
+            Box::new(node)
+        });
+        let limit = query.limit.map(|limit| {
+            let mut node = ASTNode::new(GrammarItem::Limit { number_of_rows: limit.rows, direction: limit.direction }, None, None);
+            node.span = limit.span;
+            Box::new(node)
+        });
+        let log_result = if condition.is_some() || limit.is_some() || query.follow || !query.group_by.is_empty() || !query.order_by.is_empty() {
+            Some(Box::new(ASTNode::new(GrammarItem::LogResult { follow: query.follow, group_by: query.group_by, order_by: query.order_by }, condition, limit)))
+        } else {
+            None
+        };
+
+        let mut ast = ASTNode::new(GrammarItem::Query, Some(Box::new(log_file)), log_result);
+        ast.span = query.span;
+        ast
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+
+    fn parse(query: &str) -> ASTNode {
+        Parser::new(query.into()).parse().unwrap()
+    }
+
+    #[test]
+    fn it_converts_parsed_queries_into_typed_ones() {
+        let text = "SELECT a AS b FROM 'app.log', STDIN WHERE a > 1 ORDER BY a DESC LIMIT LAST 3";
+        let query = Query::try_from(&parse(text)).unwrap();
+        assert_eq!(query.select, vec!(SelectField::new(Expression::Field("a".into()), Some("b".into()))));
+        assert_eq!(query.from, vec!("app.log".to_string(), "-".to_string()));
+        let where_clause = query.where_clause.clone().unwrap();
+        assert_eq!((where_clause.field, where_clause.mode, where_clause.value), (Expression::Field("a".into()), WhereComparator::GreaterThan, Expression::Number(1)));
+        assert_eq!(&text[where_clause.span.start..where_clause.span.end], "WHERE a > 1");
+        assert_eq!(query.order_by, vec!(SortKey { expression: Expression::Field("a".into()), descending: true }));
+        let limit = query.limit.clone().unwrap();
+        assert_eq!((limit.rows, limit.direction), (3, LimitDirection::Last));
+        assert!(!query.follow);
+        assert_eq!(query.span, Span { start: 0, end: text.len(), line: 1, column: 1 });
+
+        assert_eq!(Statement::try_from(&parse(text)), Ok(Statement::Query(query)));
+    }
+
+    #[test]
+    fn it_converts_typed_statements_back_into_the_same_shape() {
+        for text in &[
+            "SELECT * FROM 'app.log'",
+            "SELECT a FROM 'app.log' LIMIT 5",
+            "SELECT a FROM 'app.log' WHERE a LIKE 'x%' FOLLOW",
+            "SELECT a, count(*) FROM 'app.log' GROUP BY a",
+            "EXPLAIN ANALYZE SELECT count(*) FROM 'app.log' WHERE a = 1"
+        ] {
+            let statement = Statement::try_from(&parse(text)).unwrap();
+            let ast = ASTNode::from(statement.clone());
+            assert_eq!(ast.is_follow(), parse(text).is_follow());
+            assert_eq!(Statement::try_from(&ast), Ok(statement), "{}", text);
+        }
+    }
+
+    #[test]
+    fn it_fails_on_trees_that_are_not_queries() {
+        let condition = ASTNode::new(GrammarItem::Condition {
+            field: Expression::Field("a".into()),
+            mode: WhereComparator::StrictEquals,
+            value: Expression::Number(1)
+        }, None, None);
+        assert!(Query::try_from(&condition).is_err());
+        assert!(Query::try_from(&ASTNode::new(GrammarItem::Query, None, None)).is_err());
+        assert!(Statement::try_from(&ASTNode::new(GrammarItem::Explain { analyze: false }, None, None)).is_err());
+    }
+}
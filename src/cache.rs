@@ -0,0 +1,749 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use memmap2::Mmap;
+
+use encoding::{self, Decoder, Fingerprint, FnvBuildHasher};
+use functions::Value;
+use record::{Fields, Record, FILE_FIELD, LINE_FIELD};
+use source::FileRecords;
+
+const MAGIC: &[u8; 4] = b"LQCC";
+const VERSION: u32 = 2;
+
+/// Columns with at most this many distinct values may be dictionary encoded.
+const DICTIONARY_LIMIT: usize = 1 << 16;
+
+/// Bytes of each column kept in memory while converting a log, the rest
+/// being moved to the spill file.
+const SEGMENT_BUFFER: usize = 16 * 1024;
+
+const NULL: u8 = 0;
+const STR: u8 = 1;
+const INTEGER: u8 = 2;
+const FLOAT: u8 = 3;
+const FALSE: u8 = 4;
+const TRUE: u8 = 5;
+const TIMESTAMP: u8 = 6;
+
+/// A directory of log files converted into columns, so they are parsed once
+/// rather than by every query.
+///
+/// The first query on a log converts it and later queries read only the
+/// columns of the fields they use. Fields with few distinct values, like
+/// `level`, are stored as a dictionary of their values and a small code per
+/// record. A cached log is converted again once it changed (its size,
+/// modification time or content), which makes caching worthwhile for logs
+/// that are no longer written to, like rotated or compressed ones.
+#[derive(Clone)]
+pub struct Cache {
+    directory: PathBuf
+}
+
+impl Cache {
+    pub fn new(directory: PathBuf) -> Cache {
+        Cache { directory }
+    }
+
+    /// Opens the cached columns of the log at `path`, converting it first if
+    /// it is not cached yet or changed since. Returns `None` if the log
+    /// changed while it was converted.
+    pub fn open(&self, path: &Path) -> Result<Option<CachedFile>, String> {
+        let source = fs::canonicalize(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+        let cache_path = self.directory.join(format!("{:016x}.lqc", encoding::fnv1a(source.to_string_lossy().as_bytes())));
+        let name = Arc::new(path.to_string_lossy().into_owned());
+
+        let fingerprint = Fingerprint::of(path)?;
+        if let Some(file) = CachedFile::open(&cache_path, name.clone())? {
+            if file.source == source && file.fingerprint == fingerprint {
+                return Ok(Some(file));
+            }
+        }
+
+        // Written under a name of its own and moved into place, so concurrent
+        // queries never read a partially written file.
+        fs::create_dir_all(&self.directory).map_err(|err| format!("Cannot create {:?}: {}", self.directory, err))?;
+        let partial_path = cache_path.with_extension(format!("{}.partial", process::id()));
+        match convert(path, &source, &fingerprint, &partial_path).and_then(|_| Fingerprint::of(path)) {
+            Ok(ref current) if *current == fingerprint => {},
+            changed => {
+                let _ = fs::remove_file(&partial_path);
+                return changed.map(|_| None);
+            }
+        }
+        fs::rename(&partial_path, &cache_path).map_err(|err| format!("Cannot write {:?}: {}", cache_path, err))?;
+        CachedFile::open(&cache_path, name)
+    }
+}
+
+/// Converts the log at `path` into a cache file at `destination`.
+fn convert(path: &Path, source: &Path, fingerprint: &Fingerprint, destination: &Path) -> Result<(), String> {
+    let error = |err: io::Error| format!("Cannot write {:?}: {}", destination, err);
+    let mut columns = ColumnsBuilder::new(Spill::create(destination.with_extension("spill")).map_err(error)?);
+    for record in FileRecords::open(path)? {
+        columns.add(&record?).map_err(error)?;
+    }
+
+    let mut data = vec!();
+    encoding::put_header(&mut data, MAGIC, VERSION);
+    fingerprint.write(&mut data);
+    encoding::put_bytes(&mut data, source.to_string_lossy().as_bytes());
+    let mut out = BufWriter::new(File::create(destination).map_err(error)?);
+    out.write_all(&data)
+        .and_then(|_| columns.write(&mut out))
+        .and_then(|_| out.flush())
+        .map_err(error)
+}
+
+/// A scratch file the columns of a log being converted are moved to as they
+/// grow, so converting a log takes a few buffers per column rather than
+/// memory for all of its values. Removed once the conversion is done.
+struct Spill {
+    path: PathBuf,
+    file: File,
+    length: u64
+}
+
+impl Spill {
+    fn create(path: PathBuf) -> io::Result<Spill> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        Ok(Spill { path, file, length: 0 })
+    }
+
+    /// Appends `bytes`, returning where they were stored.
+    fn append(&mut self, bytes: &[u8]) -> io::Result<(u64, usize)> {
+        self.file.seek(SeekFrom::Start(self.length))?;
+        self.file.write_all(bytes)?;
+        self.length += bytes.len() as u64;
+        Ok((self.length - bytes.len() as u64, bytes.len()))
+    }
+
+    fn read(&mut self, (offset, length): (u64, usize), bytes: &mut Vec<u8>) -> io::Result<()> {
+        bytes.resize(length, 0);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(bytes)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Bytes written in order, kept in the spill file in chunks but for the last
+/// `SEGMENT_BUFFER` of them.
+#[derive(Default)]
+struct Segment {
+    chunks: Vec<(u64, usize)>,
+    buffer: Vec<u8>,
+    length: usize
+}
+
+impl Segment {
+    fn push(&mut self, bytes: &[u8], spill: &mut Spill) -> io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        self.length += bytes.len();
+        if self.buffer.len() >= SEGMENT_BUFFER {
+            self.chunks.push(spill.append(&self.buffer)?);
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn copy_to(&self, out: &mut dyn Write, spill: &mut Spill) -> io::Result<()> {
+        let mut chunk = vec!();
+        for &location in &self.chunks {
+            spill.read(location, &mut chunk)?;
+            out.write_all(&chunk)?;
+        }
+        out.write_all(&self.buffer)
+    }
+}
+
+/// Codes stored with the smallest width that fits all of them so far, and
+/// rewritten wider when a larger one is pushed.
+struct CodesBuilder {
+    width: usize,
+    segment: Segment
+}
+
+impl Default for CodesBuilder {
+    fn default() -> CodesBuilder {
+        CodesBuilder { width: 1, segment: Segment::default() }
+    }
+}
+
+impl CodesBuilder {
+    fn push(&mut self, code: u64, spill: &mut Spill) -> io::Result<()> {
+        let width = code_width(code);
+        if width > self.width {
+            self.widen(width, spill)?;
+        }
+        self.segment.push(&code.to_le_bytes()[..self.width], spill)
+    }
+
+    fn widen(&mut self, width: usize, spill: &mut Spill) -> io::Result<()> {
+        let narrow = mem::take(&mut self.segment);
+        let narrow_width = mem::replace(&mut self.width, width);
+        let mut chunk = vec!();
+        for &location in &narrow.chunks {
+            spill.read(location, &mut chunk)?;
+            self.push_narrow(&chunk, narrow_width, spill)?;
+        }
+        self.push_narrow(&narrow.buffer, narrow_width, spill)
+    }
+
+    fn push_narrow(&mut self, codes: &[u8], width: usize, spill: &mut Spill) -> io::Result<()> {
+        let mut bytes = [0; 8];
+        for code in codes.chunks(width) {
+            bytes[..width].copy_from_slice(code);
+            self.segment.push(&bytes[..self.width], spill)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the codes as `Codes::read` reads them.
+    fn copy_to(&self, out: &mut dyn Write, spill: &mut Spill) -> io::Result<()> {
+        out.write_all(&(self.width as u64).to_le_bytes())?;
+        self.segment.copy_to(out, spill)
+    }
+}
+
+/// Collects the records of a log column by column.
+struct ColumnsBuilder {
+    spill: Spill,
+    rows: usize,
+    lines: CodesBuilder,
+    /// The columns of each record in the order of its fields.
+    shapes: Vec<Vec<usize>>,
+    shape_codes: HashMap<Vec<usize>, u64, FnvBuildHasher>,
+    record_shapes: CodesBuilder,
+    /// The length of each record's text, shifted left by one, with the lowest
+    /// bit set for records without fields.
+    sizes: CodesBuilder,
+    columns: Vec<ColumnBuilder>,
+    /// The columns of each field name, one per occurrence, as a record can repeat a field.
+    by_field: HashMap<String, Vec<usize>>
+}
+
+impl ColumnsBuilder {
+    fn new(spill: Spill) -> ColumnsBuilder {
+        ColumnsBuilder {
+            spill,
+            rows: 0,
+            lines: CodesBuilder::default(),
+            shapes: vec!(),
+            shape_codes: HashMap::default(),
+            record_shapes: CodesBuilder::default(),
+            sizes: CodesBuilder::default(),
+            columns: vec!(),
+            by_field: HashMap::new()
+        }
+    }
+
+    fn add(&mut self, record: &Record) -> io::Result<()> {
+        let row = self.rows;
+        let mut shape = vec!();
+        for (name, value) in &record.fields {
+            let occurrence = shape.iter().filter(|&&column: &&usize| self.columns[column].name == *name).count();
+            let column = match self.by_field.get(name).and_then(|columns| columns.get(occurrence)) {
+                Some(&column) => column,
+                None => {
+                    self.by_field.entry(name.clone()).or_default().push(self.columns.len());
+                    self.columns.push(ColumnBuilder::new(name));
+                    self.columns.len() - 1
+                }
+            };
+            self.columns[column].push(row, value, &mut self.spill)?;
+            shape.push(column);
+        }
+
+        let code = match self.shape_codes.get(&shape) {
+            Some(&code) => code,
+            None => {
+                let code = self.shapes.len() as u64;
+                self.shape_codes.insert(shape.clone(), code);
+                self.shapes.push(shape);
+                code
+            }
+        };
+        self.record_shapes.push(code, &mut self.spill)?;
+        self.lines.push(record.line as u64, &mut self.spill)?;
+        self.sizes.push((record.length as u64) << 1 | record.unparsed as u64, &mut self.spill)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn write(mut self, out: &mut dyn Write) -> io::Result<()> {
+        let rows = self.rows;
+        out.write_all(&(rows as u64).to_le_bytes())?;
+        self.lines.copy_to(out, &mut self.spill)?;
+        self.record_shapes.copy_to(out, &mut self.spill)?;
+        self.sizes.copy_to(out, &mut self.spill)?;
+
+        let mut data = vec!();
+        encoding::put_u64(&mut data, self.shapes.len() as u64);
+        for shape in &self.shapes {
+            encoding::put_u64(&mut data, shape.len() as u64);
+            for &column in shape {
+                encoding::put_u64(&mut data, column as u64);
+            }
+        }
+        encoding::put_u64(&mut data, self.columns.len() as u64);
+        out.write_all(&data)?;
+
+        for column in self.columns {
+            column.write(out, rows, &mut self.spill)?;
+        }
+        Ok(())
+    }
+}
+
+/// The values of one field, kept both plainly and as dictionary codes until
+/// the field turns out to have too many distinct values.
+struct ColumnBuilder {
+    name: String,
+    rows: usize,
+    plain: Segment,
+    dictionary: Option<Dictionary>,
+    /// Reused to encode values.
+    buffer: Vec<u8>
+}
+
+/// The distinct encoded values of a column and the code of each record's value.
+struct Dictionary {
+    values: HashMap<Vec<u8>, u64, FnvBuildHasher>,
+    codes: CodesBuilder
+}
+
+impl ColumnBuilder {
+    fn new(name: &str) -> ColumnBuilder {
+        ColumnBuilder {
+            name: name.to_string(),
+            rows: 0,
+            plain: Segment::default(),
+            dictionary: Some(Dictionary { values: HashMap::default(), codes: CodesBuilder::default() }),
+            buffer: vec!()
+        }
+    }
+
+    /// Sets the value of the record `row`, which records before it lacking
+    /// the field have as NULL.
+    fn push(&mut self, row: usize, value: &Value, spill: &mut Spill) -> io::Result<()> {
+        while self.rows < row {
+            self.push_encoded(&[NULL], spill)?;
+        }
+        let mut encoded = mem::take(&mut self.buffer);
+        encoded.clear();
+        put_value(&mut encoded, value);
+        self.push_encoded(&encoded, spill)?;
+        self.buffer = encoded;
+        Ok(())
+    }
+
+    fn push_encoded(&mut self, encoded: &[u8], spill: &mut Spill) -> io::Result<()> {
+        self.rows += 1;
+        self.plain.push(encoded, spill)?;
+        if let Some(Dictionary { values, codes }) = &mut self.dictionary {
+            let next_code = values.len() as u64;
+            let code = match values.get(encoded) {
+                Some(&code) => code,
+                None => *values.entry(encoded.to_vec()).or_insert(next_code)
+            };
+            codes.push(code, spill)?;
+            if values.len() > DICTIONARY_LIMIT {
+                self.dictionary = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the column in whichever encoding is smaller.
+    fn write(mut self, out: &mut dyn Write, rows: usize, spill: &mut Spill) -> io::Result<()> {
+        while self.rows < rows {
+            self.push_encoded(&[NULL], spill)?;
+        }
+        let mut data = vec!();
+        encoding::put_bytes(&mut data, self.name.as_bytes());
+
+        if let Some(Dictionary { values, codes }) = self.dictionary {
+            if values.keys().map(Vec::len).sum::<usize>() + codes.width * rows < self.plain.length {
+                let mut entries: Vec<(&Vec<u8>, &u64)> = values.iter().collect();
+                entries.sort_by_key(|&(_, &code)| code);
+                encoding::put_u64(&mut data, entries.len() as u64);
+                for (encoded, _) in entries {
+                    data.extend_from_slice(encoded);
+                }
+                out.write_all(&data)?;
+                return codes.copy_to(out, spill);
+            }
+        }
+
+        // A plain column is told apart from a dictionary by having no entries.
+        encoding::put_u64(&mut data, 0);
+        encoding::put_u64(&mut data, self.plain.length as u64);
+        out.write_all(&data)?;
+        self.plain.copy_to(out, spill)
+    }
+}
+
+/// The number of bytes needed for codes up to `max`.
+fn code_width(max: u64) -> usize {
+    [1, 2, 4].iter().copied().find(|&width| max < 1 << (8 * width)).unwrap_or(8)
+}
+
+fn put_value(data: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::Null => data.push(NULL),
+        Value::Str(ref s) => {
+            data.push(STR);
+            encoding::put_bytes(data, s.as_bytes());
+        },
+        Value::Integer(i) => {
+            data.push(INTEGER);
+            data.extend_from_slice(&i.to_le_bytes());
+        },
+        Value::Float(f) => {
+            data.push(FLOAT);
+            data.extend_from_slice(&f.to_bits().to_le_bytes());
+        },
+        Value::Boolean(b) => data.push(if b { TRUE } else { FALSE }),
+        Value::Timestamp(timestamp) => {
+            data.push(TIMESTAMP);
+            data.extend_from_slice(&timestamp.timestamp().to_le_bytes());
+            encoding::put_u64(data, timestamp.timestamp_subsec_nanos() as u64);
+        }
+    }
+}
+
+fn read_value(decoder: &mut Decoder) -> Result<Value, String> {
+    let tag = decoder.bytes(1)?[0];
+    Ok(match tag {
+        NULL => Value::Null,
+        STR => Value::Str(decoder.string()?),
+        INTEGER => Value::Integer(decoder.u64()? as i64),
+        FLOAT => Value::Float(f64::from_bits(decoder.u64()?)),
+        FALSE => Value::Boolean(false),
+        TRUE => Value::Boolean(true),
+        TIMESTAMP => {
+            let (seconds, nanoseconds) = (decoder.u64()? as i64, decoder.u64()? as u32);
+            Value::Timestamp(Utc.timestamp_opt(seconds, nanoseconds).single().ok_or("invalid timestamp")?)
+        },
+        _ => return Err(format!("unknown value type {}", tag))
+    })
+}
+
+/// Codes of a fixed width stored from `offset` in a cache file.
+struct Codes {
+    offset: usize,
+    width: usize
+}
+
+impl Codes {
+    fn read(decoder: &mut Decoder, rows: usize) -> Result<Codes, String> {
+        let width = decoder.usize()?;
+        if ![1, 2, 4, 8].contains(&width) {
+            return Err(format!("invalid code width {}", width));
+        }
+        let offset = decoder.position();
+        decoder.bytes(width.checked_mul(rows).ok_or("too many rows")?)?;
+        Ok(Codes { offset, width })
+    }
+
+    fn get(&self, data: &[u8], row: usize) -> u64 {
+        let mut bytes = [0; 8];
+        let start = self.offset + row * self.width;
+        bytes[..self.width].copy_from_slice(&data[start..start + self.width]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+enum Column {
+    Dictionary { values: Vec<Value>, codes: Codes },
+    /// Values are read in the order of the records, so the column remembers
+    /// the row and the offset it was last read at.
+    Plain { start: usize, end: usize, cursor: RefCell<(usize, usize)> }
+}
+
+impl Column {
+    fn value(&self, data: &[u8], row: usize) -> Value {
+        match self {
+            Column::Dictionary { values, codes } => values.get(codes.get(data, row) as usize).cloned().unwrap_or(Value::Null),
+            Column::Plain { start, end, cursor } => {
+                let mut cursor = cursor.borrow_mut();
+                if cursor.0 > row {
+                    *cursor = (0, *start);
+                }
+                let mut decoder = Decoder::new(&data[cursor.1..*end]);
+                while cursor.0 < row {
+                    if read_value(&mut decoder).is_err() {
+                        *cursor = (0, *start);
+                        return Value::Null;
+                    }
+                    cursor.0 += 1;
+                }
+                cursor.1 += decoder.position();
+                read_value(&mut decoder).unwrap_or(Value::Null)
+            }
+        }
+    }
+}
+
+/// A log converted by a `Cache`, whose columns are read as they are used.
+pub struct CachedFile {
+    name: Arc<String>,
+    source: PathBuf,
+    fingerprint: Fingerprint,
+    data: Mmap,
+    rows: usize,
+    lines: Codes,
+    record_shapes: Codes,
+    sizes: Codes,
+    shapes: Vec<Vec<usize>>,
+    names: Vec<String>,
+    columns: Vec<Column>,
+    /// The first column of each field name.
+    by_name: HashMap<String, usize>
+}
+
+impl CachedFile {
+    /// Reads the cache file at `path`. Returns `None` if there is none or if
+    /// it was written by another version.
+    fn open(path: &Path, name: Arc<String>) -> Result<Option<CachedFile>, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Cannot open {:?}: {}", path, err))
+        };
+        // Safety: cache files are replaced by renaming new ones over them, never
+        // modified in place, so the mapped file does not change.
+        let data = unsafe { Mmap::map(&file) }.map_err(|err| format!("Cannot map {:?}: {}", path, err))?;
+        Ok(CachedFile::decode(data, name).ok())
+    }
+
+    fn decode(data: Mmap, name: Arc<String>) -> Result<CachedFile, String> {
+        let mut decoder = Decoder::new(&data);
+        decoder.header(MAGIC, VERSION)?;
+        let fingerprint = Fingerprint::read(&mut decoder)?;
+        let source = PathBuf::from(decoder.string()?);
+
+        let rows = decoder.usize()?;
+        let lines = Codes::read(&mut decoder, rows)?;
+        let record_shapes = Codes::read(&mut decoder, rows)?;
+        let sizes = Codes::read(&mut decoder, rows)?;
+
+        let mut shapes = vec!();
+        for _ in 0..decoder.u64()? {
+            let mut shape = vec!();
+            for _ in 0..decoder.u64()? {
+                shape.push(decoder.usize()?);
+            }
+            shapes.push(shape);
+        }
+
+        let (mut names, mut columns, mut by_name) = (vec!(), vec!(), HashMap::new());
+        for column in 0..decoder.usize()? {
+            let name = decoder.string()?;
+            let entries = decoder.u64()?;
+            if entries == 0 {
+                let length = decoder.usize()?;
+                let start = decoder.position();
+                decoder.bytes(length)?;
+                columns.push(Column::Plain { start, end: start + length, cursor: RefCell::new((0, start)) });
+            } else {
+                let mut values = vec!();
+                for _ in 0..entries {
+                    values.push(read_value(&mut decoder)?);
+                }
+                columns.push(Column::Dictionary { values, codes: Codes::read(&mut decoder, rows)? });
+            }
+            by_name.entry(name.clone()).or_insert(column);
+            names.push(name);
+        }
+
+        if (0..rows).any(|row| record_shapes.get(&data, row) as usize >= shapes.len()) ||
+            shapes.iter().flatten().any(|&column| column >= columns.len()) {
+            return Err("invalid shape".into());
+        }
+
+        Ok(CachedFile { name, source, fingerprint, data, rows, lines, record_shapes, sizes, shapes, names, columns, by_name })
+    }
+
+    pub fn records<'a>(&'a self) -> CachedRecords<'a> {
+        CachedRecords { file: self, row: 0 }
+    }
+}
+
+/// The records of a `CachedFile` in the order of the log.
+pub struct CachedRecords<'a> {
+    file: &'a CachedFile,
+    row: usize
+}
+
+impl<'a> Iterator for CachedRecords<'a> {
+    type Item = Result<CachedRecord<'a>, String>;
+
+    fn next(&mut self) -> Option<Result<CachedRecord<'a>, String>> {
+        if self.row >= self.file.rows {
+            return None;
+        }
+        self.row += 1;
+        Some(Ok(CachedRecord { file: self.file, row: self.row - 1 }))
+    }
+}
+
+/// A record of a cached log, reading its fields from the columns.
+pub struct CachedRecord<'a> {
+    file: &'a CachedFile,
+    row: usize
+}
+
+impl<'a> CachedRecord<'a> {
+    fn line(&self) -> usize {
+        self.file.lines.get(&self.file.data, self.row) as usize
+    }
+
+    pub fn to_record(&self) -> Record {
+        let (length, unparsed) = self.size();
+        Record {
+            fields: self.pairs(),
+            file: self.file.name.clone(),
+            line: self.line(),
+            length,
+            unparsed
+        }
+    }
+}
+
+impl<'a> Fields for CachedRecord<'a> {
+    fn get(&self, name: &str) -> Value {
+        match name {
+            FILE_FIELD => Value::Str(self.file.name.to_string()),
+            LINE_FIELD => Value::Integer(self.line() as i64),
+            _ => match self.file.by_name.get(name) {
+                Some(&column) => self.file.columns[column].value(&self.file.data, self.row),
+                None => Value::Null
+            }
+        }
+    }
+
+    fn pairs(&self) -> Vec<(String, Value)> {
+        let shape = &self.file.shapes[self.file.record_shapes.get(&self.file.data, self.row) as usize];
+        shape.iter()
+            .map(|&column| (self.file.names[column].clone(), self.file.columns[column].value(&self.file.data, self.row)))
+            .collect()
+    }
+
+    fn size(&self) -> (usize, bool) {
+        let size = self.file.sizes.get(&self.file.data, self.row);
+        ((size >> 1) as usize, size & 1 == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    use source;
+
+    fn write(path: &Path, content: &str) {
+        File::create(path).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+
+    fn records(path: &Path) -> Vec<Record> {
+        source::open(&[path.to_string_lossy().into_owned()]).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn it_reads_the_same_records_from_the_cache() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        let mut content = String::new();
+        for n in 0..300 {
+            content.push_str(&format!("ts=2018-03-04T10:00:{:02}Z level={} n={} latency={}.5 request_id=req-{}\n", n % 60, ["info", "warn", "error"][n % 3], n, n, n));
+            if n % 50 == 0 {
+                content.push_str(&format!("  at main.rs:{}\n{{\"n\": {}, \"ok\": true, \"tags\": [\"a\"], \"n\": null}}\nplain text line\ndup=1 dup=2\n\n", n, n));
+            }
+        }
+        write(&path, &content);
+
+        let cache = Cache::new(directory.path().join("cache"));
+        let cached = cache.open(&path).unwrap().unwrap();
+        let expected = records(&path);
+        assert_eq!(cached.records().map(|record| record.unwrap().to_record()).collect::<Vec<Record>>(), expected);
+
+        // Reading a column out of order starts over rather than returning the wrong values.
+        let all: Vec<CachedRecord> = cached.records().map(Result::unwrap).collect();
+        assert_eq!(all[10].get("n"), expected[10].get("n"));
+        assert_eq!(all[3].get("n"), expected[3].get("n"));
+        assert_eq!(all[3].get("_line"), Value::Integer(expected[3].line as i64));
+
+        let level = cached.by_name["level"];
+        let request_id = cached.by_name["request_id"];
+        assert!(matches!(cached.columns[level], Column::Dictionary { .. }));
+        assert!(matches!(cached.columns[request_id], Column::Plain { .. }));
+    }
+
+    #[test]
+    fn it_moves_large_columns_to_disk_while_converting() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        let mut content = String::new();
+        for n in 0..5000 {
+            content.push_str(&format!("n={} k={}", n, n % 1000));
+            if n % 100 == 0 {
+                content.push_str(" sparse=yes");
+            }
+            content.push('\n');
+        }
+        write(&path, &content);
+
+        let cache = Cache::new(directory.path().join("cache"));
+        let cached = cache.open(&path).unwrap().unwrap();
+        assert_eq!(cached.records().map(|record| record.unwrap().to_record()).collect::<Vec<Record>>(), records(&path));
+        assert!(matches!(cached.columns[cached.by_name["k"]], Column::Dictionary { codes: Codes { width: 2, .. }, .. }));
+        assert_eq!(cached.lines.width, 2);
+        assert_eq!(fs::read_dir(directory.path().join("cache")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn it_converts_logs_again_after_they_changed() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        write(&path, "n=1\n");
+
+        let cache = Cache::new(directory.path().join("cache"));
+        assert_eq!(cache.open(&path).unwrap().unwrap().records().count(), 1);
+        assert_eq!(cache.open(&path).unwrap().unwrap().records().count(), 1);
+
+        write(&path, "n=1\nn=2\n");
+        let cached = cache.open(&path).unwrap().unwrap();
+        assert_eq!(cached.records().map(|record| record.unwrap().get("n")).collect::<Vec<Value>>(), vec!(Value::Integer(1), Value::Integer(2)));
+        assert_eq!(fs::read_dir(directory.path().join("cache")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn it_ignores_invalid_cache_files() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        write(&path, "n=1\n");
+        let cache = Cache::new(directory.path().join("cache"));
+        cache.open(&path).unwrap();
+
+        let cache_file = fs::read_dir(directory.path().join("cache")).unwrap().next().unwrap().unwrap().path();
+        let data = fs::read(&cache_file).unwrap();
+        fs::write(&cache_file, &data[..data.len() - 3]).unwrap();
+        assert_eq!(cache.open(&path).unwrap().unwrap().records().count(), 1);
+        assert_eq!(fs::read(&cache_file).unwrap(), data);
+    }
+}
@@ -0,0 +1,165 @@
+use cache::Cache;
+use executor::{Executor, ResultSet};
+use functions::{Accumulator, FunctionRegistry, Signature, Value};
+use parser::Parser;
+
+/// Settings shared by every query run through it, most notably the functions
+/// available to those queries. Register user-defined functions here before
+/// creating parsers with `QueryContext::parser`.
+#[derive(Clone)]
+#[derive(Default)]
+pub struct QueryContext {
+    functions: FunctionRegistry,
+    cache: Option<Cache>
+}
+
+impl QueryContext {
+    pub fn new() -> QueryContext {
+        QueryContext::default()
+    }
+
+    pub fn register_scalar<F>(&mut self, name: &str, signature: Signature, function: F)
+        where F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static {
+        self.functions.register_scalar(name, signature, function);
+    }
+
+    pub fn register_aggregate<F>(&mut self, name: &str, signature: Signature, accumulator: F)
+        where F: Fn() -> Box<dyn Accumulator> + Send + Sync + 'static {
+        self.functions.register_aggregate(name, signature, accumulator);
+    }
+
+    /// Keeps the logs queried converted into columns in `cache`, see `Cache`.
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(cache);
+    }
+
+    pub fn functions(&self) -> &FunctionRegistry {
+        &self.functions
+    }
+
+    pub fn parser(&self, query: String) -> Parser {
+        Parser::with_functions(query, self.functions.clone())
+    }
+
+    pub fn executor(&self) -> Executor {
+        let mut executor = Executor::new(self.functions.clone());
+        if let Some(cache) = &self.cache {
+            executor.set_cache(cache.clone());
+        }
+        executor
+    }
+
+    /// Parses and runs `query` against the log files it selects from.
+    pub fn execute(&self, query: &str) -> Result<ResultSet, String> {
+        let ast = self.parser(query.into()).parse()?;
+        self.executor().execute(&ast)
+    }
+
+    /// Parses the statements of `script`, separated by `;`, and runs them one
+    /// after the other, returning the result of each. Nothing is run if any
+    /// statement cannot be parsed.
+    pub fn execute_script(&self, script: &str) -> Result<Vec<ResultSet>, String> {
+        let statements = self.parser(script.into()).parse_script()?;
+        let executor = self.executor();
+        statements.iter().map(|ast| executor.execute(ast)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    use functions::DataType;
+    use parser::{Expression, GrammarItem, SelectField};
+
+    struct Distinct {
+        seen: Vec<Value>
+    }
+
+    impl Accumulator for Distinct {
+        fn update(&mut self, arguments: &[Value]) -> Result<(), String> {
+            if !self.seen.contains(&arguments[0]) {
+                self.seen.push(arguments[0].clone());
+            }
+            Ok(())
+        }
+
+        fn state(&self) -> Vec<Value> {
+            self.seen.clone()
+        }
+
+        fn merge(&mut self, state: &[Value]) -> Result<(), String> {
+            for value in state {
+                self.update(std::slice::from_ref(value))?;
+            }
+            Ok(())
+        }
+
+        fn finish(&self) -> Result<Value, String> {
+            Ok(Value::Integer(self.seen.len() as i64))
+        }
+    }
+
+    fn context() -> QueryContext {
+        let mut context = QueryContext::new();
+        context.register_scalar("tenant_of", Signature::new(vec!(DataType::String), DataType::String), |args| {
+            Ok(Value::Str(args[0].as_str()?.split('-').next().unwrap_or("").to_string()))
+        });
+        context.register_aggregate("count_distinct", Signature::new(vec!(DataType::Any), DataType::Integer), || {
+            Box::new(Distinct { seen: vec!() })
+        });
+        context
+    }
+
+    #[test]
+    fn it_parses_queries_calling_user_defined_functions() {
+        let ast = context().parser("SELECT tenant_of(request), count_distinct(user) FROM 'app.log'".into()).parse().unwrap();
+        let expected_fields = vec!(
+            SelectField::new(Expression::FunctionCall { name: "tenant_of".into(), arguments: vec!(Expression::Field("request".into())) }, None),
+            SelectField::new(Expression::FunctionCall { name: "count_distinct".into(), arguments: vec!(Expression::Field("user".into())) }, None)
+        );
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: expected_fields });
+    }
+
+    #[test]
+    fn it_type_checks_calls_to_user_defined_functions() {
+        assert!(context().parser("SELECT tenant_of(42) FROM 'app.log'".into()).parse().is_err());
+        assert!(context().parser("SELECT tenant_of(request, user) FROM 'app.log'".into()).parse().is_err());
+    }
+
+    #[test]
+    fn it_does_not_know_user_defined_functions_without_context() {
+        assert!(Parser::new("SELECT tenant_of(request) FROM 'app.log'".into()).parse().is_err());
+    }
+
+    #[test]
+    fn it_evaluates_user_defined_functions() {
+        let context = context();
+        let functions = context.functions();
+        assert_eq!(functions.call("tenant_of", &[Value::Str("acme-1234".into())]), Ok(Value::Str("acme".into())));
+
+        let mut accumulator = functions.create_accumulator("count_distinct").unwrap();
+        for user in &["a", "b", "a"] {
+            accumulator.update(&[Value::Str(user.to_string())]).unwrap();
+        }
+        assert_eq!(accumulator.finish(), Ok(Value::Integer(2)));
+    }
+
+    #[test]
+    fn it_runs_each_statement_of_a_script() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        fs::write(&path, "level=info n=1\nlevel=error n=2\nlevel=error n=3\n").unwrap();
+
+        let script = format!("SELECT n FROM '{0}' WHERE level = 'error';\nSELECT count(*) AS total FROM '{0}';", path.display());
+        let results = context().execute_script(&script).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].rows, vec!(vec!(Value::Integer(2)), vec!(Value::Integer(3))));
+        assert_eq!(results[1].columns, vec!("total".to_string()));
+        assert_eq!(results[1].rows, vec!(vec!(Value::Integer(3))));
+
+        assert!(context().execute_script(&format!("SELECT n FROM '{}'; SELECT", path.display())).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! The binary format shared by the files written next to or about logs,
+//! indexes and cached columns: little-endian integers, length-prefixed
+//! strings and the fingerprint of the log they were built from.
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Bytes hashed at the start and at the end of a log to notice rewrites
+/// that keep its size and modification time.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Identifies the content of a log, so files built from an older version of
+/// it are not used.
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Fingerprint {
+    pub length: u64,
+    modified: (u64, u32),
+    sample: u64
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> Result<Fingerprint, String> {
+        let error = |err: io::Error| format!("Cannot read {:?}: {}", path, err);
+        let mut file = File::open(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+        let metadata = file.metadata().map_err(error)?;
+        let modified = metadata.modified().map_err(error)?
+            .duration_since(UNIX_EPOCH)
+            .map(|since| (since.as_secs(), since.subsec_nanos()))
+            .unwrap_or((0, 0));
+
+        let length = metadata.len();
+        let mut sample = vec!();
+        (&mut file).take(SAMPLE_SIZE).read_to_end(&mut sample).map_err(error)?;
+        if length > SAMPLE_SIZE {
+            file.seek(SeekFrom::Start(length.saturating_sub(SAMPLE_SIZE).max(SAMPLE_SIZE))).map_err(error)?;
+            file.take(SAMPLE_SIZE).read_to_end(&mut sample).map_err(error)?;
+        }
+
+        Ok(Fingerprint { length, modified, sample: fnv1a(&sample) })
+    }
+
+    pub fn write(&self, data: &mut Vec<u8>) {
+        for number in [self.length, self.modified.0, self.modified.1 as u64, self.sample] {
+            put_u64(data, number);
+        }
+    }
+
+    pub fn read(decoder: &mut Decoder) -> Result<Fingerprint, String> {
+        Ok(Fingerprint {
+            length: decoder.u64()?,
+            modified: (decoder.u64()?, decoder.u64()? as u32),
+            sample: decoder.u64()?
+        })
+    }
+}
+
+/// Starts a file with its `magic` bytes and format `version`.
+pub fn put_header(data: &mut Vec<u8>, magic: &[u8; 4], version: u32) {
+    data.extend_from_slice(magic);
+    put_u64(data, version as u64);
+}
+
+pub fn put_u64(data: &mut Vec<u8>, number: u64) {
+    data.extend_from_slice(&number.to_le_bytes());
+}
+
+pub fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(data, bytes.len() as u64);
+    data.extend_from_slice(bytes);
+}
+
+/// Reads data written with the `put_` functions, failing on truncated data
+/// rather than panicking.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Checks the header written by `put_header`.
+    pub fn header(&mut self, magic: &[u8; 4], version: u32) -> Result<(), String> {
+        if self.bytes(magic.len())? != magic {
+            return Err("unknown format".into());
+        }
+        match self.u64()? {
+            found if found == version as u64 => Ok(()),
+            found => Err(format!("unsupported version {}", found))
+        }
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.position..self.position.saturating_add(length)).ok_or("unexpected end")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?).map_err(|err| err.to_string())
+    }
+
+    /// Reads bytes written by `put_bytes`.
+    pub fn prefixed(&mut self) -> Result<&'a [u8], String> {
+        let length = self.usize()?;
+        self.bytes(length)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.prefixed()?.to_vec()).map_err(|err| err.to_string())
+    }
+}
+
+/// FNV-1a as a `Hasher`, much faster than the default one for the short
+/// keys of tables built while writing these files.
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(FNV_OFFSET)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = bytes.iter().fold(self.0, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME));
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// FNV-1a, which unlike the hasher of the standard library is guaranteed to
+/// stay the same across Rust versions, as the files written outlive the binary.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
@@ -0,0 +1,128 @@
+//! Errors of the lexer and the parser, which both report where in the text
+//! of a query they went wrong.
+
+use std::error::Error;
+use std::fmt;
+
+/// Where something is in the text of a query: the byte offsets `start..end`
+/// and the 1-based line and column, counted in characters, of `start`.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+#[derive(Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize
+}
+
+/// Why a query could not be parsed, with the span of the text at fault.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum ParseError {
+    /// A character that does not start any token.
+    UnexpectedChar { found: char, span: Span },
+    /// A number too large or too small to be represented.
+    InvalidNumber { text: String, span: Span },
+    /// A number that is not written correctly, like `0x` or `0x1G`.
+    MalformedNumber { text: String, span: Span },
+    /// A number followed by a unit that is not a size or duration.
+    UnknownUnit { unit: String, span: Span },
+    /// A string whose closing quote is missing.
+    UnterminatedString { span: Span },
+    /// A `/*` comment without its closing `*/`.
+    UnterminatedComment { span: Span },
+    /// A token the grammar does not allow at this point, `expected` telling
+    /// what it does allow, and `hint` what was probably meant.
+    UnexpectedToken { expected: String, found: String, span: Span, hint: Option<String> },
+    /// The query ended while `expected` was still missing.
+    UnexpectedEnd { expected: String, span: Span },
+    /// A query that is well-formed but not valid, like one calling a function
+    /// with arguments of the wrong type or using aggregates in `WHERE`.
+    InvalidQuery { message: String, span: Span }
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match *self {
+            ParseError::UnexpectedChar { span, .. } | ParseError::InvalidNumber { span, .. } | ParseError::MalformedNumber { span, .. } |
+            ParseError::UnknownUnit { span, .. } | ParseError::UnterminatedString { span } | ParseError::UnterminatedComment { span } |
+            ParseError::UnexpectedToken { span, .. } | ParseError::UnexpectedEnd { span, .. } |
+            ParseError::InvalidQuery { span, .. } => span
+        }
+    }
+
+    /// A suggestion for fixing the query, if the error looks like a common mistake.
+    pub fn hint(&self) -> Option<&str> {
+        match *self {
+            ParseError::UnexpectedChar { found: '!', .. } => Some("use `!=` or `<>` to compare for inequality"),
+            ParseError::InvalidNumber { .. } => Some("integers range from -9223372036854775808 to 9223372036854775807"),
+            ParseError::UnknownUnit { .. } => {
+                Some("sizes in bytes take B, KB, MB, GB, TB, KiB, MiB, GiB or TiB, durations in milliseconds ns, us, ms, s, m, h or d")
+            },
+            ParseError::UnexpectedToken { ref hint, .. } => hint.as_ref().map(String::as_str),
+            _ => None
+        }
+    }
+
+    /// Renders the error for someone reading `query`, the text that failed to
+    /// parse: the message, the line at fault with the span underlined, and
+    /// the hint if there is one.
+    ///
+    /// ```text
+    /// Expected the end of the query, got `WHERE` at line 1, column 27
+    ///   |
+    /// 1 | SELECT a FROM 'x' LIMIT 3 WHERE a = 1
+    ///   |                           ^^^^^
+    ///   = hint: `LIMIT` must come after `WHERE`
+    /// ```
+    pub fn render(&self, query: &str) -> String {
+        let span = self.span();
+        let start = span.start.min(query.len());
+        let line_start = query[..start].rfind('\n').map(|index| index + 1).unwrap_or(0);
+        let line_end = query[start..].find('\n').map(|index| start + index).unwrap_or(query.len());
+        // Spans reaching past the line, like the one of the whole query, are underlined up to its end.
+        let width = query[start..span.end.clamp(start, line_end)].chars().count().max(1);
+
+        // Tabs are kept so the carets line up with the span whatever the tab width.
+        let indent: String = query[line_start..start].chars().map(|ch| if ch == '\t' { '\t' } else { ' ' }).collect();
+
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let mut rendered = format!("{}\n{} |\n{} | {}\n{} | {}{}",
+            self, gutter, number, query[line_start..line_end].trim_end_matches('\r'), gutter, indent, "^".repeat(width));
+        if let Some(hint) = self.hint() {
+            rendered.push_str(&format!("\n{} = hint: {}", gutter, hint));
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnexpectedChar { found, span } => write!(f, "Unexpected character {:?} at line {}, column {}", found, span.line, span.column),
+            ParseError::InvalidNumber { ref text, span } => write!(f, "Number {} is out of range at line {}, column {}", text, span.line, span.column),
+            ParseError::MalformedNumber { ref text, span } => write!(f, "Malformed number {} at line {}, column {}", text, span.line, span.column),
+            ParseError::UnknownUnit { ref unit, span } => write!(f, "Unknown unit {:?} at line {}, column {}", unit, span.line, span.column),
+            ParseError::UnterminatedString { span } => write!(f, "Unterminated string at line {}, column {}", span.line, span.column),
+            ParseError::UnterminatedComment { span } => write!(f, "Unterminated comment at line {}, column {}", span.line, span.column),
+            ParseError::UnexpectedToken { ref expected, ref found, span, .. } => {
+                write!(f, "Expected {}, got {} at line {}, column {}", expected, found, span.line, span.column)
+            },
+            ParseError::UnexpectedEnd { ref expected, .. } => write!(f, "Expected {}, got the end of the query", expected),
+            ParseError::InvalidQuery { ref message, .. } => write!(f, "{}", message)
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Lets callers that report errors as strings keep using `?` on parse results.
+impl From<ParseError> for String {
+    fn from(err: ParseError) -> String {
+        err.to_string()
+    }
+}
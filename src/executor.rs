@@ -0,0 +1,1296 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ast::Statement;
+use cache::{Cache, CachedFile};
+use explain::{self, Analysis, Statistics};
+use follow::Follower;
+use plan::{Access, LogicalPlan, PhysicalPlan, Sort, PARALLEL_CHUNK_SIZE};
+
+use functions::{Accumulator, FunctionRegistry, Value};
+use parser::{ASTNode, ArithmeticOperator, Expression, LimitDirection, SelectField, SortKey, WhereComparator};
+use record::{Fields, LineRecord, Record};
+use source::{self, MergedRecords};
+
+/// How long following waits before checking the files for new records again.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The rows produced by a query, each holding one value per column.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>
+}
+
+/// A projected row along with the values of its `ORDER BY` keys.
+type SortedRow = (Vec<Value>, Vec<(String, Value)>);
+
+pub struct Executor {
+    functions: FunctionRegistry,
+    cache: Option<Cache>,
+    /// Collected from every thread while running a query for `EXPLAIN ANALYZE`.
+    statistics: Option<Mutex<Statistics>>
+}
+
+impl Executor {
+    pub fn new(functions: FunctionRegistry) -> Executor {
+        Executor {
+            functions,
+            cache: None,
+            statistics: None
+        }
+    }
+
+    /// Reads logs from their columns in `cache`, converting them on first use.
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(cache);
+    }
+
+    /// Runs the query of `ast`. For `EXPLAIN` the result has a single `plan`
+    /// column holding one line of the description per row.
+    pub fn execute(&self, ast: &ASTNode) -> Result<ResultSet, String> {
+        if ast.is_explain() {
+            return self.explain(ast);
+        }
+
+        let plan = self.plan(ast)?;
+        if plan.follow {
+            return Err("FOLLOW queries never finish, run them with Executor::follow".into());
+        }
+
+        let rows = self.rows(&plan)?;
+        Ok(build_result_set(&plan.fields, rows))
+    }
+
+    fn plan(&self, ast: &ASTNode) -> Result<PhysicalPlan, String> {
+        PhysicalPlan::new(&LogicalPlan::from_ast(ast, &self.functions)?, self.cache.is_some())
+    }
+
+    /// Describes the plan of the query below `EXPLAIN`, running it first for `EXPLAIN ANALYZE`.
+    fn explain(&self, ast: &ASTNode) -> Result<ResultSet, String> {
+        let (analyze, query) = match Statement::try_from(ast)? {
+            Statement::Explain { analyze, query } => (analyze, query),
+            Statement::Query(_) => return Err("Expected EXPLAIN".into())
+        };
+        let started = Instant::now();
+        let logical = LogicalPlan::from_query(&query, &self.functions)?;
+        let physical = PhysicalPlan::new(&logical, self.cache.is_some())?;
+        let planning = started.elapsed();
+
+        let analysis = if analyze {
+            if physical.follow {
+                return Err("EXPLAIN ANALYZE cannot run FOLLOW queries, which never finish".into());
+            }
+            let analyzing = Executor {
+                functions: self.functions.clone(),
+                cache: self.cache.clone(),
+                statistics: Some(Mutex::new(Statistics::default()))
+            };
+            let started = Instant::now();
+            let rows = analyzing.rows(&physical)?.len();
+            let execution = started.elapsed();
+            let statistics = analyzing.statistics.map(|statistics| statistics.into_inner().unwrap_or_else(|err| err.into_inner())).unwrap_or_default();
+            Some(Analysis { statistics, rows, planning, execution })
+        } else {
+            None
+        };
+
+        Ok(ResultSet {
+            columns: vec!("plan".into()),
+            rows: explain::describe(&logical, &physical, analysis.as_ref())?.into_iter().map(|line| vec!(Value::Str(line))).collect()
+        })
+    }
+
+    /// Runs `ast` in follow mode, whether or not it ends in `FOLLOW`, passing
+    /// each matching record to `emit` as soon as it is appended to the files.
+    /// `emit` returns false to stop following.
+    ///
+    /// With `LIMIT LAST n` the last `n` matching records already in the files
+    /// are emitted first, with `LIMIT n` following stops after `n` records.
+    /// Following `STDIN` reads it until it is closed.
+    pub fn follow<F>(&self, ast: &ASTNode, mut emit: F) -> Result<(), String>
+        where F: FnMut(Vec<(String, Value)>) -> bool {
+        let plan = self.plan(ast)?;
+        if plan.aggregate {
+            return Err("Aggregate functions cannot be used with FOLLOW".into());
+        }
+        if plan.sort.is_some() {
+            return Err("ORDER BY cannot be used with FOLLOW".into());
+        }
+
+        let mut remaining = match plan.limit {
+            Some((number_of_rows, LimitDirection::First)) => Some(number_of_rows),
+            _ => None
+        };
+        if remaining == Some(0) {
+            return Ok(());
+        }
+
+        if plan.sources.iter().any(|name| name == source::STDIN) {
+            if plan.sources.len() > 1 {
+                return Err("STDIN cannot be followed together with files".into());
+            }
+            if let Some((_, LimitDirection::Last)) = plan.limit {
+                return Err("LIMIT LAST cannot be used when following STDIN".into());
+            }
+            for record in source::open(&plan.sources)? {
+                if !self.emit_if_matching(&plan, &record?, &mut remaining, &mut emit)? {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+
+        // Start following before reading the existing records so nothing
+        // appended in between is missed.
+        let mut follower = Follower::open(&source::resolve(&plan.sources)?)?;
+
+        if let Some((_, LimitDirection::Last)) = plan.limit {
+            for row in self.rows(&plan)? {
+                if !emit(row) {
+                    return Ok(());
+                }
+            }
+        }
+
+        loop {
+            for record in follower.poll()? {
+                if !self.emit_if_matching(&plan, &record, &mut remaining, &mut emit)? {
+                    return Ok(());
+                }
+            }
+            thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+
+    /// Passes `record` on to `emit` if it matches, returning whether following should go on.
+    fn emit_if_matching<F>(&self, plan: &PhysicalPlan, record: &Record, remaining: &mut Option<usize>, emit: &mut F) -> Result<bool, String>
+        where F: FnMut(Vec<(String, Value)>) -> bool {
+        if *remaining == Some(0) {
+            return Ok(false);
+        }
+        if self.matches(plan, record)? {
+            if !emit(self.project(&plan.fields, record)?) {
+                return Ok(false);
+            }
+            *remaining = remaining.map(|remaining| remaining - 1);
+        }
+        Ok(*remaining != Some(0))
+    }
+
+    fn rows(&self, plan: &PhysicalPlan) -> Result<Vec<Vec<(String, Value)>>, String> {
+        match plan.access {
+            Access::Backwards => {
+                if let Some(records) = source::open_reversed(&plan.sources)? {
+                    // The last rows are the first ones found when reading from the end.
+                    let first = plan.limit.as_ref().map(|&(number_of_rows, _)| (number_of_rows, LimitDirection::First));
+                    let mut rows = self.select(plan, records, first.as_ref())?;
+                    rows.reverse();
+                    return Ok(rows);
+                }
+            },
+            Access::Cached => {
+                if let Some(files) = self.open_cached(plan)? {
+                    if files.len() == 1 {
+                        return self.select(plan, files[0].records(), plan.limit.as_ref());
+                    }
+                    let inputs = files.iter()
+                        .map(|file| file.records().map(|record| record.map(|record| record.to_record())))
+                        .collect();
+                    return self.select(plan, MergedRecords::new(inputs), plan.limit.as_ref());
+                }
+            },
+            Access::Indexed { ref index, threads } => {
+                if let Some(file) = source::map(&plan.sources)? {
+                    return self.select_chunks(plan, index.chunks(&file, plan.condition(), threads));
+                }
+            },
+            Access::Mapped { threads } => {
+                if let Some(file) = source::map(&plan.sources)? {
+                    return self.select_chunks(plan, file.chunks(threads, PARALLEL_CHUNK_SIZE));
+                }
+            },
+            Access::Sequential => {}
+        }
+        self.select(plan, source::open(&plan.sources)?, plan.limit.as_ref())
+    }
+
+    /// Opens the cached columns of the files, if there is a cache. Returns
+    /// `None` for the standard input, which is not cached, and when a file
+    /// changed while it was converted.
+    fn open_cached(&self, plan: &PhysicalPlan) -> Result<Option<Vec<CachedFile>>, String> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(None)
+        };
+
+        let mut files = vec!();
+        for path in source::resolve(&plan.sources)? {
+            if path == Path::new(source::STDIN) {
+                return Ok(None);
+            }
+            match cache.open(&path)? {
+                Some(file) => files.push(file),
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(files))
+    }
+
+    fn select<R: Fields, I: Iterator<Item = Result<R, String>>>(&self, plan: &PhysicalPlan, records: I, limit: Option<&(usize, LimitDirection)>) -> Result<Vec<Vec<(String, Value)>>, String> {
+        let matching = self.filter(plan, records);
+
+        if plan.aggregate {
+            let calls = self.aggregate_calls(plan)?;
+            let groups = self.accumulate(plan, &calls, matching)?;
+            apply_limit(self.finish_aggregates(plan, &calls, groups)?.into_iter().map(Ok), limit)
+        } else if let Some(ref sort) = plan.sort {
+            let sorted = self.sort_rows(sort, matching.map(|record| self.sorted_row(plan, sort, &record?)))?;
+            apply_limit(sorted.into_iter().map(|(_, row)| Ok(row)), limit)
+        } else {
+            apply_limit(matching.map(|record| self.project(&plan.fields, &record?)), limit)
+        }
+    }
+
+    /// Like `select`, scanning each chunk of a mapped file on a thread of its own.
+    /// With an index, chunks only hold the blocks that may match.
+    ///
+    /// Every thread applies the limit to its own rows, which are then joined
+    /// in the order of the chunks and limited again, so the result is the same
+    /// as when scanning the file in one go. Sorted rows are sorted again once
+    /// joined, and aggregates are computed per chunk and the partial states
+    /// of each group merged at the end.
+    fn select_chunks<'r, I>(&self, plan: &PhysicalPlan, mut chunks: Vec<I>) -> Result<Vec<Vec<(String, Value)>>, String>
+        where I: Iterator<Item = Result<LineRecord<'r>, String>> + Send {
+        if chunks.len() == 1 {
+            return self.select(plan, chunks.remove(0), plan.limit.as_ref());
+        }
+
+        if plan.aggregate {
+            let calls = self.aggregate_calls(plan)?;
+            let partials = thread::scope(|scope| {
+                let calls = &calls;
+                let workers: Vec<_> = chunks.into_iter()
+                    .map(|chunk| scope.spawn(move || self.accumulate(plan, calls, self.filter(plan, chunk))))
+                    .collect();
+                workers.into_iter().map(join).collect::<Result<Vec<_>, String>>()
+            })?;
+
+            // Groups are merged in the order of the chunks, so they keep the order they were first seen in.
+            let mut groups = self.groups(plan, &calls)?;
+            for partial in partials {
+                for (key, accumulators) in partial.keys.into_iter().zip(partial.accumulators) {
+                    let merged = groups.get(key, || self.create_accumulators(&calls))?;
+                    for (accumulator, other) in merged.iter_mut().zip(accumulators) {
+                        accumulator.merge(&other.state())?;
+                    }
+                }
+            }
+            apply_limit(self.finish_aggregates(plan, &calls, groups)?.into_iter().map(Ok), plan.limit.as_ref())
+        } else if let Some(ref sort) = plan.sort {
+            let parts = thread::scope(|scope| {
+                let workers: Vec<_> = chunks.into_iter()
+                    .map(|chunk| scope.spawn(move || self.sort_rows(sort, self.filter(plan, chunk).map(|record| self.sorted_row(plan, sort, &record?)))))
+                    .collect();
+                workers.into_iter().map(join).collect::<Result<Vec<_>, String>>()
+            })?;
+            let sorted = self.sort_rows(sort, parts.into_iter().flatten().map(Ok))?;
+            apply_limit(sorted.into_iter().map(|(_, row)| Ok(row)), plan.limit.as_ref())
+        } else {
+            let parts = thread::scope(|scope| {
+                let workers: Vec<_> = chunks.into_iter()
+                    .map(|chunk| scope.spawn(move || apply_limit(self.filter(plan, chunk).map(|record| self.project(&plan.fields, &record?)), plan.limit.as_ref())))
+                    .collect();
+                workers.into_iter().map(join).collect::<Result<Vec<_>, String>>()
+            })?;
+            apply_limit(parts.into_iter().flatten().map(Ok), plan.limit.as_ref())
+        }
+    }
+
+    /// Keeps the records matching the query's `WHERE` clause. Records whose
+    /// text lacks the literals the clause needs are dropped before parsing them.
+    fn filter<'s, R: Fields, I: Iterator<Item = Result<R, String>>>(&'s self, plan: &'s PhysicalPlan, records: I) -> Matching<'s, I> {
+        Matching {
+            executor: self,
+            plan,
+            records,
+            statistics: self.statistics.as_ref().map(|_| Statistics::default())
+        }
+    }
+
+    fn matches(&self, plan: &PhysicalPlan, record: &dyn Fields) -> Result<bool, String> {
+        match plan.condition() {
+            Some((field, mode, value)) => {
+                let left = self.evaluate(field, record, &[])?;
+                let right = self.evaluate(value, record, &[])?;
+                Ok(compare(&left, mode, &right))
+            },
+            None => Ok(true)
+        }
+    }
+
+    fn project(&self, fields: &[SelectField], record: &dyn Fields) -> Result<Vec<(String, Value)>, String> {
+        let mut row = vec!();
+        for field in fields {
+            match field.expression {
+                Expression::Wildcard => row.extend(record.pairs()),
+                ref expression => row.push((column_name(field), self.evaluate(expression, record, &[])?))
+            }
+        }
+        Ok(row)
+    }
+
+    /// Projects `record` along with the values of the `ORDER BY` keys, which
+    /// may read fields that are not selected.
+    fn sorted_row(&self, plan: &PhysicalPlan, sort: &Sort, record: &dyn Fields) -> Result<SortedRow, String> {
+        let mut keys = vec!();
+        for key in &sort.keys {
+            keys.push(self.evaluate(&key.expression, record, &[])?);
+        }
+        Ok((keys, self.project(&plan.fields, record)?))
+    }
+
+    /// Sorts `rows` by their keys, keeping rows with equal keys in the order they
+    /// were read. When the sort only has to keep its `top` rows, the rest are
+    /// dropped whenever twice as many have been read, bounding the memory used.
+    fn sort_rows<I: Iterator<Item = Result<SortedRow, String>>>(&self, sort: &Sort, rows: I) -> Result<Vec<SortedRow>, String> {
+        let mut sorting = Duration::ZERO;
+        let mut sort_and_trim = |rows: &mut Vec<SortedRow>| {
+            let started = Instant::now();
+            sort_and_trim(sort, rows);
+            sorting += started.elapsed();
+        };
+
+        let mut sorted = vec!();
+        for row in rows {
+            sorted.push(row?);
+            if sort.top.as_ref().is_some_and(|&(number_of_rows, _)| sorted.len() > 2 * number_of_rows.max(1)) {
+                sort_and_trim(&mut sorted);
+            }
+        }
+        sort_and_trim(&mut sorted);
+
+        if let Some(statistics) = self.statistics.as_ref() {
+            statistics.lock().unwrap_or_else(|err| err.into_inner()).sorting += sorting;
+        }
+        Ok(sorted)
+    }
+
+    /// The distinct aggregate calls of the selected fields. Every selected field
+    /// has to be computed from aggregates and the expressions of `GROUP BY`.
+    fn aggregate_calls<'p>(&self, plan: &'p PhysicalPlan) -> Result<Vec<&'p Expression>, String> {
+        let mut calls = vec!();
+        for field in &plan.fields {
+            if !self.collect_aggregate_calls(&field.expression, &plan.group_by, &mut calls) {
+                return Err(if plan.group_by.is_empty() {
+                    format!("{} must be computed from aggregate functions when other fields are aggregated", column_name(field))
+                } else {
+                    format!("{} must be computed from aggregate functions or GROUP BY expressions", column_name(field))
+                });
+            }
+        }
+        Ok(calls)
+    }
+
+    fn create_accumulators(&self, calls: &[&Expression]) -> Result<Vec<Box<dyn Accumulator>>, String> {
+        let mut accumulators = vec!();
+        for call in calls {
+            if let Expression::FunctionCall { ref name, .. } = **call {
+                accumulators.push(self.functions.create_accumulator(name)?);
+            }
+        }
+        Ok(accumulators)
+    }
+
+    /// No groups yet, except without `GROUP BY`, where all records fall into
+    /// a single group that exists even if none of them match.
+    fn groups(&self, plan: &PhysicalPlan, calls: &[&Expression]) -> Result<Groups, String> {
+        let mut groups = Groups { keys: vec!(), accumulators: vec!(), positions: HashMap::new() };
+        if plan.group_by.is_empty() {
+            groups.get(vec!(), || self.create_accumulators(calls))?;
+        }
+        Ok(groups)
+    }
+
+    /// Folds all `records` into one accumulator per aggregate call for each group.
+    fn accumulate<R: Fields, I: Iterator<Item = Result<R, String>>>(&self, plan: &PhysicalPlan, calls: &[&Expression], records: I) -> Result<Groups, String> {
+        let mut groups = self.groups(plan, calls)?;
+        for record in records {
+            let record = record?;
+            let mut key = vec!();
+            for expression in &plan.group_by {
+                key.push(self.evaluate(expression, &record, &[])?);
+            }
+            let accumulators = groups.get(key, || self.create_accumulators(calls))?;
+            for (call, accumulator) in calls.iter().zip(accumulators.iter_mut()) {
+                let mut values = vec!();
+                if let Expression::FunctionCall { ref arguments, .. } = **call {
+                    for argument in arguments {
+                        values.push(self.evaluate(argument, &record, &[])?);
+                    }
+                }
+                accumulator.update(&values)?;
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Computes a row per group of an aggregating query from its folded accumulators.
+    fn finish_aggregates(&self, plan: &PhysicalPlan, calls: &[&Expression], groups: Groups) -> Result<Vec<Vec<(String, Value)>>, String> {
+        if let Some(statistics) = self.statistics.as_ref() {
+            statistics.lock().unwrap_or_else(|err| err.into_inner()).groups += groups.keys.len();
+        }
+
+        let empty = Record { fields: vec!(), file: Default::default(), line: 0, length: 0, unparsed: false };
+        let mut rows = vec!();
+        for (key, accumulators) in groups.keys.into_iter().zip(groups.accumulators) {
+            let mut computed: Vec<(&Expression, Value)> = plan.group_by.iter().zip(key).collect();
+            for (call, accumulator) in calls.iter().zip(accumulators.iter()) {
+                computed.push((call, accumulator.finish()?));
+            }
+
+            let mut row = vec!();
+            for field in &plan.fields {
+                row.push((column_name(field), self.evaluate(&field.expression, &empty, &computed)?));
+            }
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    /// Collects the aggregate calls in `expression`, returning false if it reads a
+    /// field outside of them and of the expressions of `group_by`.
+    fn collect_aggregate_calls<'e>(&self, expression: &'e Expression, group_by: &[Expression], calls: &mut Vec<&'e Expression>) -> bool {
+        if group_by.contains(expression) {
+            return true;
+        }
+        match *expression {
+            Expression::FunctionCall { ref name, .. } if self.functions.is_aggregate(name) => {
+                if !calls.contains(&expression) {
+                    calls.push(expression);
+                }
+                true
+            },
+            Expression::Wildcard | Expression::Field(_) => false,
+            Expression::Str(_) | Expression::Number(_) | Expression::Float(_) => true,
+            Expression::Negate(ref operand) => self.collect_aggregate_calls(operand, group_by, calls),
+            Expression::BinaryOperation { ref left, ref right, .. } | Expression::Comparison { ref left, ref right, .. } => {
+                self.collect_aggregate_calls(left, group_by, calls) && self.collect_aggregate_calls(right, group_by, calls)
+            },
+            Expression::FunctionCall { ref arguments, .. } => arguments.iter().all(|argument| self.collect_aggregate_calls(argument, group_by, calls)),
+            Expression::Case { ref branches, ref else_result } => {
+                branches.iter().all(|(condition, result)| {
+                    self.collect_aggregate_calls(condition, group_by, calls) && self.collect_aggregate_calls(result, group_by, calls)
+                }) && else_result.iter().all(|result| self.collect_aggregate_calls(result, group_by, calls))
+            }
+        }
+    }
+
+    /// Computes `expression` for `record`. Expressions already computed are looked up
+    /// in `computed`, which holds the final values of aggregate calls and the key of
+    /// the group once all records have been folded.
+    fn evaluate(&self, expression: &Expression, record: &dyn Fields, computed: &[(&Expression, Value)]) -> Result<Value, String> {
+        if let Some((_, value)) = computed.iter().find(|&&(computed, _)| computed == expression) {
+            return Ok(value.clone());
+        }
+        match *expression {
+            // `*` only appears as an argument of aggregates like `count(*)`, where it stands for the record itself.
+            Expression::Wildcard => Ok(Value::Boolean(true)),
+            Expression::Field(ref name) => Ok(record.get(name)),
+            Expression::Str(ref s) => Ok(Value::Str(s.clone())),
+            Expression::Number(number) => Ok(Value::Integer(number)),
+            Expression::Float(number) => Ok(Value::Float(number)),
+            Expression::Negate(ref operand) => {
+                match self.evaluate(operand, record, computed)? {
+                    Value::Integer(i) => Ok(i.checked_neg().map(Value::Integer).unwrap_or(Value::Null)),
+                    other => Ok(numeric(&other).map(|n| Value::Float(-n)).unwrap_or(Value::Null))
+                }
+            },
+            Expression::BinaryOperation { ref left, ref operator, ref right } => {
+                let left = self.evaluate(left, record, computed)?;
+                let right = self.evaluate(right, record, computed)?;
+                Ok(calculate(&left, operator, &right))
+            },
+            Expression::Comparison { ref left, ref mode, ref right } => {
+                let left = self.evaluate(left, record, computed)?;
+                let right = self.evaluate(right, record, computed)?;
+                Ok(Value::Boolean(compare(&left, mode, &right)))
+            },
+            Expression::FunctionCall { ref name, ref arguments } => {
+                let mut values = vec!();
+                for argument in arguments {
+                    values.push(self.evaluate(argument, record, computed)?);
+                }
+                self.functions.call(name, &values)
+            },
+            Expression::Case { ref branches, ref else_result } => {
+                for (condition, result) in branches {
+                    if self.evaluate(condition, record, computed)? == Value::Boolean(true) {
+                        return self.evaluate(result, record, computed);
+                    }
+                }
+                match *else_result {
+                    Some(ref else_result) => self.evaluate(else_result, record, computed),
+                    None => Ok(Value::Null)
+                }
+            }
+        }
+    }
+}
+
+/// The accumulators of each group of an aggregating query, in the order the
+/// groups were first seen.
+struct Groups {
+    keys: Vec<Vec<Value>>,
+    accumulators: Vec<Vec<Box<dyn Accumulator>>>,
+    /// The position of each key in `keys`, by its `Debug` form as values cannot be hashed.
+    positions: HashMap<String, usize>
+}
+
+impl Groups {
+    /// The accumulators of the group of `key`, from `create` if it is new.
+    fn get<F>(&mut self, key: Vec<Value>, create: F) -> Result<&mut Vec<Box<dyn Accumulator>>, String>
+        where F: FnOnce() -> Result<Vec<Box<dyn Accumulator>>, String> {
+        let text = format!("{:?}", key);
+        let position = match self.positions.get(&text) {
+            Some(&position) => position,
+            None => {
+                self.accumulators.push(create()?);
+                self.keys.push(key);
+                self.positions.insert(text, self.keys.len() - 1);
+                self.keys.len() - 1
+            }
+        };
+        Ok(&mut self.accumulators[position])
+    }
+}
+
+/// The records matching the condition of a plan, see `Executor::filter`.
+struct Matching<'s, I> {
+    executor: &'s Executor,
+    plan: &'s PhysicalPlan,
+    records: I,
+    /// Collected for `EXPLAIN ANALYZE` and added to the executor's once the records are dropped.
+    statistics: Option<Statistics>
+}
+
+impl<'s, R: Fields, I: Iterator<Item = Result<R, String>>> Iterator for Matching<'s, I> {
+    type Item = Result<R, String>;
+
+    fn next(&mut self) -> Option<Result<R, String>> {
+        loop {
+            let started = self.statistics.as_ref().map(|_| Instant::now());
+            let record = match self.records.next()? {
+                Ok(record) => record,
+                Err(err) => return Some(Err(err))
+            };
+            if let (Some(statistics), Some(started)) = (self.statistics.as_mut(), started) {
+                statistics.scanning += started.elapsed();
+                statistics.records += 1;
+                let (length, unparsed) = record.size();
+                statistics.bytes += length;
+                if unparsed {
+                    statistics.unparsed += 1;
+                }
+            }
+
+            if self.plan.prefilter.as_ref().zip(record.text()).is_some_and(|(prefilter, text)| !prefilter.may_match(text.as_bytes())) {
+                if let Some(statistics) = self.statistics.as_mut() {
+                    statistics.prefiltered += 1;
+                }
+                continue;
+            }
+
+            let started = self.statistics.as_ref().map(|_| Instant::now());
+            let matches = self.executor.matches(self.plan, &record);
+            if let (Some(statistics), Some(started)) = (self.statistics.as_mut(), started) {
+                statistics.filtering += started.elapsed();
+                if matches == Ok(true) {
+                    statistics.matched += 1;
+                }
+            }
+
+            match matches {
+                Ok(true) => return Some(Ok(record)),
+                Ok(false) => {},
+                Err(err) => return Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<'s, I> Drop for Matching<'s, I> {
+    fn drop(&mut self) {
+        if let (Some(statistics), Some(total)) = (self.statistics.take(), self.executor.statistics.as_ref()) {
+            total.lock().unwrap_or_else(|err| err.into_inner()).add(&statistics);
+        }
+    }
+}
+
+fn column_name(field: &SelectField) -> String {
+    match (&field.alias, &field.expression) {
+        (Some(alias), _) | (None, Expression::Field(alias)) => alias.clone(),
+        (None, expression) => expression.to_string()
+    }
+}
+
+fn join<T>(worker: thread::ScopedJoinHandle<Result<T, String>>) -> Result<T, String> {
+    worker.join().unwrap_or_else(|_| Err("A thread scanning the file panicked".into()))
+}
+
+fn apply_limit<I: Iterator<Item = Result<Vec<(String, Value)>, String>>>(rows: I, limit: Option<&(usize, LimitDirection)>) -> Result<Vec<Vec<(String, Value)>>, String> {
+    match limit {
+        None => rows.collect(),
+        Some(&(number_of_rows, LimitDirection::First)) => rows.take(number_of_rows).collect(),
+        Some(&(number_of_rows, LimitDirection::Last)) => {
+            let mut last = VecDeque::with_capacity(number_of_rows);
+            for row in rows {
+                let row = row?;
+                if number_of_rows == 0 {
+                    continue;
+                }
+                if last.len() == number_of_rows {
+                    last.pop_front();
+                }
+                last.push_back(row);
+            }
+            Ok(last.into_iter().collect())
+        }
+    }
+}
+
+fn sort_and_trim(sort: &Sort, rows: &mut Vec<SortedRow>) {
+    rows.sort_by(|(left, _), (right, _)| compare_sort_keys(&sort.keys, left, right));
+    match sort.top {
+        Some((number_of_rows, LimitDirection::First)) => rows.truncate(number_of_rows),
+        Some((number_of_rows, LimitDirection::Last)) => {
+            let excess = rows.len().saturating_sub(number_of_rows);
+            rows.drain(..excess);
+        },
+        None => {}
+    }
+}
+
+fn compare_sort_keys(keys: &[SortKey], left: &[Value], right: &[Value]) -> Ordering {
+    keys.iter().zip(left.iter().zip(right))
+        .map(|(key, (left, right))| if key.descending { sort_order(left, right).reverse() } else { sort_order(left, right) })
+        .find(|&ordering| ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Orders values for `ORDER BY`. Unlike `order`, any two values are ordered:
+/// NULL first, then booleans, numbers, timestamps and strings.
+fn sort_order(left: &Value, right: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match *value {
+            Value::Null => 0,
+            Value::Boolean(_) => 1,
+            Value::Integer(_) | Value::Float(_) => 2,
+            Value::Timestamp(_) => 3,
+            Value::Str(_) => 4
+        }
+    }
+
+    match (left, right) {
+        (Value::Boolean(left), Value::Boolean(right)) => left.cmp(right),
+        (Value::Integer(left), Value::Integer(right)) => left.cmp(right),
+        (Value::Integer(_) | Value::Float(_), Value::Integer(_) | Value::Float(_)) => {
+            left.as_float().unwrap_or(0.0).total_cmp(&right.as_float().unwrap_or(0.0))
+        },
+        (Value::Timestamp(left), Value::Timestamp(right)) => left.cmp(right),
+        (Value::Str(left), Value::Str(right)) => left.cmp(right),
+        _ => rank(left).cmp(&rank(right))
+    }
+}
+
+/// Lines up the rows by column. Selecting `*` yields every field seen in any
+/// record, so columns are the union of all field names in order of appearance.
+fn build_result_set(fields: &[SelectField], rows: Vec<Vec<(String, Value)>>) -> ResultSet {
+    if !fields.iter().any(|field| field.expression == Expression::Wildcard) {
+        return ResultSet {
+            columns: fields.iter().map(column_name).collect(),
+            rows: rows.into_iter().map(|row| row.into_iter().map(|(_, value)| value).collect()).collect()
+        };
+    }
+
+    let mut columns: Vec<String> = vec!();
+    for row in &rows {
+        for (name, _) in row {
+            if !columns.contains(name) {
+                columns.push(name.clone());
+            }
+        }
+    }
+
+    let rows = rows.into_iter().map(|row| {
+        columns.iter().map(|column| {
+            row.iter().find(|&(name, _)| name == column).map(|(_, value)| value.clone()).unwrap_or(Value::Null)
+        }).collect()
+    }).collect();
+
+    ResultSet { columns, rows }
+}
+
+/// Reads `value` as a number, accepting numeric strings as found in text logs.
+fn numeric(value: &Value) -> Option<f64> {
+    match *value {
+        Value::Integer(_) | Value::Float(_) | Value::Str(_) => value.as_float().ok(),
+        _ => None
+    }
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match *value {
+        Value::Integer(i) => Some(i),
+        Value::Str(ref s) => s.trim().parse::<i64>().ok(),
+        _ => None
+    }
+}
+
+/// Arithmetic on two values. Anything that is not a number yields NULL
+/// rather than aborting the scan, as does division by zero.
+fn calculate(left: &Value, operator: &ArithmeticOperator, right: &Value) -> Value {
+    if let (Some(left), Some(right), false) = (integer(left), integer(right), *operator == ArithmeticOperator::Divide) {
+        let result = match *operator {
+            ArithmeticOperator::Add => left.checked_add(right),
+            ArithmeticOperator::Subtract => left.checked_sub(right),
+            _ => left.checked_mul(right)
+        };
+        return result.map(Value::Integer).unwrap_or(Value::Null);
+    }
+
+    match (numeric(left), numeric(right)) {
+        (Some(left), Some(right)) => {
+            match *operator {
+                ArithmeticOperator::Add => Value::Float(left + right),
+                ArithmeticOperator::Subtract => Value::Float(left - right),
+                ArithmeticOperator::Multiply => Value::Float(left * right),
+                ArithmeticOperator::Divide if right == 0.0 => Value::Null,
+                ArithmeticOperator::Divide => Value::Float(left / right)
+            }
+        },
+        _ => Value::Null
+    }
+}
+
+/// Orders two values, converting strings to the type of the other side where
+/// needed, since fields of text logs are read as strings.
+fn order(left: &Value, right: &Value) -> Option<Ordering> {
+    if let Some(ordering) = left.partial_cmp(right) {
+        return Some(ordering);
+    }
+
+    match (left, right) {
+        (Value::Str(_), Value::Integer(_)) | (Value::Str(_), Value::Float(_)) |
+        (Value::Integer(_), Value::Str(_)) | (Value::Float(_), Value::Str(_)) => {
+            numeric(left)?.partial_cmp(&numeric(right)?)
+        },
+        (Value::Str(_), Value::Timestamp(_)) | (Value::Timestamp(_), Value::Str(_)) => {
+            left.as_timestamp().ok()?.partial_cmp(&right.as_timestamp().ok()?)
+        },
+        (Value::Str(s), Value::Boolean(b)) | (Value::Boolean(b), Value::Str(s)) => {
+            if s.eq_ignore_ascii_case(&b.to_string()) { Some(Ordering::Equal) } else { None }
+        },
+        _ => None
+    }
+}
+
+/// Evaluates a comparison. Comparisons involving NULL are never true.
+pub fn compare(left: &Value, mode: &WhereComparator, right: &Value) -> bool {
+    if left.is_null() || right.is_null() {
+        return false;
+    }
+
+    match *mode {
+        WhereComparator::Like => {
+            match (left.as_str(), right.as_str()) {
+                (Ok(value), Ok(pattern)) => like(&value, &pattern),
+                _ => false
+            }
+        },
+        WhereComparator::NotEquals => order(left, right) != Some(Ordering::Equal),
+        ref mode => {
+            match order(left, right) {
+                Some(ordering) => match *mode {
+                    WhereComparator::StrictEquals => ordering == Ordering::Equal,
+                    WhereComparator::LessThan => ordering == Ordering::Less,
+                    WhereComparator::LessThanOrEquals => ordering != Ordering::Greater,
+                    WhereComparator::GreaterThan => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less
+                },
+                None => false
+            }
+        }
+    }
+}
+
+/// SQL `LIKE` matching where `%` matches any sequence of characters and `_` any single character.
+pub fn like(value: &str, pattern: &str) -> bool {
+    let value: Vec<char> = value.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+
+    let (mut v, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '_' || pattern[p] == value[v]) {
+            v += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '%' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&ch| ch == '%')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use index;
+    use mapped::MappedFile;
+    use parser::Parser;
+
+    struct Logs {
+        directory: TempDir
+    }
+
+    impl Logs {
+        fn new() -> Logs {
+            Logs { directory: TempDir::new().unwrap() }
+        }
+
+        fn write(&self, name: &str, lines: &[&str]) -> &Logs {
+            let mut file = File::create(self.directory.path().join(name)).unwrap();
+            for line in lines {
+                writeln!(file, "{}", line).unwrap();
+            }
+            self
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.directory.path().join(name).to_string_lossy().into_owned()
+        }
+
+        fn query_ast(&self, query: &str) -> ASTNode {
+            Parser::new(query.replace("$DIR", &self.directory.path().to_string_lossy())).parse().unwrap()
+        }
+
+        fn query(&self, query: &str) -> Result<ResultSet, String> {
+            let query = query.replace("$DIR", &self.directory.path().to_string_lossy());
+            let ast = Parser::new(query).parse()?;
+            Executor::new(FunctionRegistry::new()).execute(&ast)
+        }
+    }
+
+    fn app_log() -> Logs {
+        let logs = Logs::new();
+        logs.write("app.log", &[
+            "ts=2018-03-04T10:00:00Z level=info status=200 latency=120 msg=\"GET /\"",
+            "ts=2018-03-04T10:00:01Z level=warn status=404 latency=15 msg=\"GET /missing\"",
+            "ts=2018-03-04T10:00:02Z level=error status=503 latency=3000 msg=\"upstream timeout\"",
+            "ts=2018-03-04T10:00:03Z level=info status=200 latency=80 msg=\"GET /health\""
+        ]);
+        logs
+    }
+
+    fn strings(values: &[&str]) -> Vec<Value> {
+        values.iter().map(|value| Value::Str(value.to_string())).collect()
+    }
+
+    fn integers(values: &[i64]) -> Vec<Vec<Value>> {
+        values.iter().map(|&value| vec!(Value::Integer(value))).collect()
+    }
+
+    #[test]
+    fn it_selects_fields_matching_the_where_clause() {
+        let result = app_log().query("SELECT msg, status FROM '$DIR/app.log' WHERE level = 'info'").unwrap();
+        assert_eq!(result.columns, vec!("msg", "status"));
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Str("GET /".into()), Value::Integer(200)),
+            vec!(Value::Str("GET /health".into()), Value::Integer(200))
+        ));
+    }
+
+    #[test]
+    fn it_compares_numeric_fields_numerically() {
+        let result = app_log().query("SELECT status FROM '$DIR/app.log' WHERE status >= 400").unwrap();
+        assert_eq!(result.rows, integers(&[404, 503]));
+        let quoted = app_log().query("SELECT status FROM '$DIR/app.log' WHERE status = '404'").unwrap();
+        assert_eq!(quoted.rows, integers(&[404]));
+    }
+
+    #[test]
+    fn it_matches_like_patterns() {
+        let result = app_log().query("SELECT msg FROM '$DIR/app.log' WHERE msg LIKE 'GET /%h'").unwrap();
+        assert_eq!(result.rows, vec!(strings(&["GET /health"])));
+        assert!(like("upstream timeout", "%time%"));
+        assert!(like("abc", "a_c"));
+        assert!(!like("abc", "a_"));
+    }
+
+    #[test]
+    fn it_computes_expressions_with_aliases() {
+        let result = app_log().query("SELECT upper(level) AS sev, latency / 1000 AS seconds, CASE WHEN status >= 500 THEN 'server' WHEN status >= 400 THEN 'client' ELSE 'ok' END AS category FROM '$DIR/app.log' LIMIT 3").unwrap();
+        assert_eq!(result.columns, vec!("sev", "seconds", "category"));
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Str("INFO".into()), Value::Float(0.12), Value::Str("ok".into())),
+            vec!(Value::Str("WARN".into()), Value::Float(0.015), Value::Str("client".into())),
+            vec!(Value::Str("ERROR".into()), Value::Float(3.0), Value::Str("server".into()))
+        ));
+    }
+
+    #[test]
+    fn it_yields_null_for_integer_arithmetic_that_overflows() {
+        let logs = Logs::new();
+        logs.write("numbers.log", &["n=-9223372036854775808", "n=7"]);
+        let result = logs.query("SELECT -n, 9223372036854775807 + n AS sum FROM '$DIR/numbers.log'").unwrap();
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Null, Value::Integer(-1)),
+            vec!(Value::Integer(-7), Value::Null)
+        ));
+    }
+
+    #[test]
+    fn it_applies_limit_first_and_last() {
+        let logs = app_log();
+        let first = logs.query("SELECT status FROM '$DIR/app.log' LIMIT 2").unwrap();
+        assert_eq!(first.rows, integers(&[200, 404]));
+        let last = logs.query("SELECT status FROM '$DIR/app.log' LIMIT LAST 2").unwrap();
+        assert_eq!(last.rows, integers(&[503, 200]));
+    }
+
+    #[test]
+    fn it_sorts_by_order_by_keys_before_applying_the_limit() {
+        let logs = app_log();
+        assert_eq!(logs.query("SELECT status FROM '$DIR/app.log' ORDER BY latency DESC").unwrap().rows, integers(&[503, 200, 200, 404]));
+        assert_eq!(logs.query("SELECT latency FROM '$DIR/app.log' ORDER BY status, latency DESC LIMIT 2").unwrap().rows, integers(&[120, 80]));
+        assert_eq!(logs.query("SELECT latency FROM '$DIR/app.log' ORDER BY level LIMIT LAST 2").unwrap().rows, integers(&[80, 15]));
+        assert_eq!(logs.query("SELECT latency FROM '$DIR/app.log' WHERE status = 200 ORDER BY ts DESC").unwrap().rows, integers(&[80, 120]));
+    }
+
+    #[test]
+    fn it_keeps_only_the_top_rows_with_the_same_result_as_a_full_sort() {
+        let lines: Vec<String> = (0..300).map(|n| format!("n={} bucket={}", n, (n * 37) % 11)).collect();
+        let logs = Logs::new();
+        logs.write("app.log", &lines.iter().map(String::as_str).collect::<Vec<&str>>());
+
+        let all = logs.query("SELECT n FROM '$DIR/app.log' ORDER BY bucket DESC").unwrap().rows;
+        for count in &[0, 1, 7, 100, 400] {
+            let first = logs.query(&format!("SELECT n FROM '$DIR/app.log' ORDER BY bucket DESC LIMIT {}", count)).unwrap().rows;
+            assert_eq!(first, all.iter().take(*count).cloned().collect::<Vec<_>>());
+            let last = logs.query(&format!("SELECT n FROM '$DIR/app.log' ORDER BY bucket DESC LIMIT LAST {}", count)).unwrap().rows;
+            assert_eq!(last, all[all.len().saturating_sub(*count)..].to_vec());
+        }
+    }
+
+    #[test]
+    fn it_orders_values_of_different_types() {
+        let values = vec!(Value::Str("a".into()), Value::Float(2.5), Value::Null, Value::Integer(3), Value::Boolean(true), Value::Integer(-1));
+        let mut sorted = values.clone();
+        sorted.sort_by(sort_order);
+        assert_eq!(sorted, vec!(Value::Null, Value::Boolean(true), Value::Integer(-1), Value::Float(2.5), Value::Integer(3), Value::Str("a".into())));
+    }
+
+    #[test]
+    fn it_explains_the_plan_without_running_it() {
+        let logs = app_log();
+        let result = logs.query("EXPLAIN SELECT status FROM '$DIR/app.log' WHERE level = 'error' ORDER BY latency DESC LIMIT 1").unwrap();
+        assert_eq!(result.columns, vec!("plan"));
+
+        let lines: Vec<String> = result.rows.iter().map(|row| row[0].to_string()).collect();
+        assert_eq!(lines[..5].to_vec(), vec!(
+            "Limit 1".to_string(),
+            "  Project status".to_string(),
+            "    Sort latency DESC".to_string(),
+            "      Filter level = 'error'".to_string(),
+            format!("        Scan '{}'", logs.path("app.log"))
+        ));
+        assert!(lines[5].starts_with("Access: mapped into memory"));
+        assert!(lines[6].starts_with(&format!("Source: '{}' (uncompressed, ", logs.path("app.log"))) && lines[6].ends_with(", logfmt)"));
+        assert_eq!(lines[7..].to_vec(), vec!(
+            "Pushed down: records without 'error' are skipped before parsing",
+            "Top-k: sorting keeps only the first 1 row"
+        ));
+
+        let lines = logs.query("EXPLAIN SELECT * FROM '$DIR/app.log' LIMIT LAST 2 FOLLOW").unwrap().rows;
+        assert_eq!(lines[3][0], Value::Str("Access: read backwards from the end of the files, stopping once enough rows matched, then following new records".into()));
+    }
+
+    #[test]
+    fn it_reports_what_each_stage_did_with_explain_analyze() {
+        let logs = app_log();
+        logs.write("mixed.log", &["level=error status=500", "Connection reset by peer", "level=info status=200"]);
+        let analyze = |query: &str| -> Vec<String> {
+            logs.query(query).unwrap().rows.iter().map(|row| row[0].to_string()).collect()
+        };
+
+        let lines = analyze("EXPLAIN ANALYZE SELECT status FROM '$DIR/mixed.log' WHERE level = 'error'");
+        assert_eq!(lines[0], "Project status (1 row)");
+        assert!(lines[1].starts_with("  Filter level = 'error' (1 row, 2 skipped before parsing, "), "{}", lines[1]);
+        assert!(lines[2].contains(" (3 records, 67 B, 1 without fields, "), "{}", lines[2]);
+        assert!(lines.iter().any(|line| line.starts_with("Execution time: ")));
+
+        let lines = analyze("EXPLAIN ANALYZE SELECT status FROM '$DIR/*.log' WHERE level = 'error'");
+        assert_eq!(lines[0], "Project status (2 rows)");
+        assert!(lines[2].contains(" (7 records, 367 B, 1 without fields, "), "{}", lines[2]);
+        assert_eq!(lines[3], "Access: read from the start, merging the files by timestamp");
+        assert!(!lines.iter().any(|line| line.starts_with("Pushed down: ")));
+
+        let lines = analyze("EXPLAIN ANALYZE SELECT message FROM '$DIR/mixed.log' LIMIT LAST 2");
+        assert!(lines[2].contains(" (2 records, 45 B, 1 without fields, "), "{}", lines[2]);
+        assert!(lines[3].starts_with("Access: read backwards"), "{}", lines[3]);
+
+        let cache_directory = TempDir::new().unwrap();
+        let mut executor = Executor::new(FunctionRegistry::new());
+        executor.set_cache(Cache::new(cache_directory.path().to_path_buf()));
+        let lines: Vec<String> = executor.execute(&logs.query_ast("EXPLAIN ANALYZE SELECT status FROM '$DIR/mixed.log'")).unwrap()
+            .rows.iter().map(|row| row[0].to_string()).collect();
+        assert!(lines[1].contains(" (3 records, 67 B, 1 without fields, "), "{}", lines[1]);
+        assert_eq!(lines[2], "Access: read from the columns in the cache");
+
+        assert!(logs.query("EXPLAIN ANALYZE SELECT * FROM '$DIR/app.log' FOLLOW").is_err());
+    }
+
+    #[test]
+    fn it_selects_all_fields_with_wildcard() {
+        let logs = Logs::new();
+        logs.write("app.log", &["level=info", "level=warn msg=slow"]);
+        let result = logs.query("SELECT * FROM '$DIR/app.log'").unwrap();
+        assert_eq!(result.columns, vec!("level", "msg"));
+        assert_eq!(result.rows, vec!(vec!(Value::Str("info".into()), Value::Null), strings(&["warn", "slow"])));
+    }
+
+    #[test]
+    fn it_aggregates_over_all_matching_records() {
+        let result = app_log().query("SELECT count(*) AS requests, max(latency) AS slowest, avg(latency) FROM '$DIR/app.log' WHERE level != 'error'").unwrap();
+        assert_eq!(result.columns, vec!("requests", "slowest", "avg(latency)"));
+        assert_eq!(result.rows, vec!(vec!(Value::Integer(3), Value::Integer(120), Value::Float(215.0 / 3.0))));
+    }
+
+    #[test]
+    fn it_fails_when_fields_are_mixed_with_aggregates() {
+        assert!(app_log().query("SELECT level, count(*) FROM '$DIR/app.log'").is_err());
+        assert!(app_log().query("SELECT level, status, count(*) FROM '$DIR/app.log' GROUP BY level").is_err());
+        assert!(app_log().query("SELECT * FROM '$DIR/app.log' GROUP BY level").is_err());
+    }
+
+    #[test]
+    fn it_aggregates_each_group_in_the_order_groups_are_first_seen() {
+        let result = app_log().query("SELECT level, count(*) AS requests, max(latency) FROM '$DIR/app.log' GROUP BY level").unwrap();
+        assert_eq!(result.columns, vec!("level", "requests", "max(latency)"));
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Str("info".into()), Value::Integer(2), Value::Integer(120)),
+            vec!(Value::Str("warn".into()), Value::Integer(1), Value::Integer(15)),
+            vec!(Value::Str("error".into()), Value::Integer(1), Value::Integer(3000))
+        ));
+
+        let query = "SELECT CASE WHEN status >= 500 THEN 'server' WHEN status >= 400 THEN 'client' ELSE 'ok' END AS outcome, count(*) \
+            FROM '$DIR/app.log' GROUP BY outcome";
+        let result = app_log().query(query).unwrap();
+        assert_eq!(result.columns, vec!("outcome", "count(*)"));
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Str("ok".into()), Value::Integer(2)),
+            vec!(Value::Str("client".into()), Value::Integer(1)),
+            vec!(Value::Str("server".into()), Value::Integer(1))
+        ));
+
+        let result = app_log().query("SELECT status / 100 * 100 AS class, count(*) FROM '$DIR/app.log' WHERE level = 'debug' GROUP BY class").unwrap();
+        assert!(result.rows.is_empty());
+    }
+
+    #[test]
+    fn it_queries_multiple_files_merged_by_timestamp_with_file_and_line_columns() {
+        let logs = Logs::new();
+        logs.write("app-1.log", &["ts=2018-03-04T10:00:00Z n=1", "ts=2018-03-04T10:00:02Z n=3"])
+            .write("app-2.log", &["ts=2018-03-04T10:00:01Z n=2"])
+            .write("other.log", &["ts=2018-03-04T10:00:03Z n=4"]);
+
+        let result = logs.query("SELECT n, _file, _line FROM '$DIR/app-*.log'").unwrap();
+        assert_eq!(result.columns, vec!("n", "_file", "_line"));
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Integer(1), Value::Str(logs.path("app-1.log")), Value::Integer(1)),
+            vec!(Value::Integer(2), Value::Str(logs.path("app-2.log")), Value::Integer(1)),
+            vec!(Value::Integer(3), Value::Str(logs.path("app-1.log")), Value::Integer(2))
+        ));
+
+        let listed = logs.query("SELECT n FROM '$DIR/other.log', '$DIR/app-2.log'").unwrap();
+        assert_eq!(listed.rows, integers(&[2, 4]));
+
+        let directory = logs.query("SELECT count(*) FROM '$DIR'").unwrap();
+        assert_eq!(directory.rows, vec!(vec!(Value::Integer(4))));
+    }
+
+    #[test]
+    fn it_applies_limit_last_across_rotated_files() {
+        let logs = Logs::new();
+        logs.write("app.log", &["n=4"])
+            .write("app.log.1", &["n=2", "n=3"])
+            .write("app.log.2", &["n=1"]);
+
+        let result = logs.query("SELECT n FROM '$DIR/app.log*' LIMIT LAST 3").unwrap();
+        assert_eq!(result.rows, integers(&[2, 3, 4]));
+    }
+
+    #[test]
+    fn it_follows_records_appended_to_the_file() {
+        let logs = Logs::new();
+        logs.write("app.log", &["level=error n=1", "level=error n=2", "level=info n=3"]);
+        let path = logs.path("app.log");
+
+        // Records are appended once the existing one has been emitted, so following
+        // has started by then without waiting on another thread.
+        let query = logs.query_ast("SELECT n FROM '$DIR/app.log' WHERE level = 'error' LIMIT LAST 1 FOLLOW");
+        let mut rows = vec!();
+        Executor::new(FunctionRegistry::new()).follow(&query, |row| {
+            rows.push(row);
+            if rows.len() == 1 {
+                let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+                file.write_all(b"level=info n=4\nlevel=error n=5\n").unwrap();
+            }
+            rows.len() < 2
+        }).unwrap();
+
+        assert_eq!(rows, vec!(vec!(("n".to_string(), Value::Integer(2))), vec!(("n".to_string(), Value::Integer(5)))));
+        assert!(logs.query("SELECT n FROM '$DIR/app.log' FOLLOW").is_err());
+    }
+
+    #[test]
+    fn it_scans_chunks_in_parallel_with_the_same_result() {
+        let mut lines = vec!();
+        for n in 0..1000 {
+            lines.push(format!("n={} level={} latency={}", n, if n % 7 == 0 { "error" } else { "info" }, n % 13));
+            if n % 10 == 0 {
+                lines.push("  at main.rs:10".to_string());
+            }
+        }
+        let logs = Logs::new();
+        logs.write("app.log", &lines.iter().map(String::as_str).collect::<Vec<&str>>());
+        let file = MappedFile::open(Path::new(&logs.path("app.log"))).unwrap();
+        let executor = Executor::new(FunctionRegistry::new());
+
+        for query in &[
+            "SELECT n, _line FROM '$DIR/app.log' WHERE level = 'error'",
+            "SELECT * FROM '$DIR/app.log' WHERE latency > 10 LIMIT 5",
+            "SELECT n, _line FROM '$DIR/app.log' WHERE level = 'error' LIMIT LAST 5",
+            "SELECT count(*), sum(latency), avg(latency), min(n), max(n) FROM '$DIR/app.log' WHERE level = 'info'",
+            "SELECT level, latency / 5 AS bucket, count(*), sum(n) FROM '$DIR/app.log' GROUP BY level, bucket",
+            "SELECT n, latency FROM '$DIR/app.log' ORDER BY latency DESC, n LIMIT 10",
+            "SELECT n FROM '$DIR/app.log' WHERE level = 'info' ORDER BY latency LIMIT LAST 3"
+        ] {
+            let ast = logs.query_ast(query);
+            let plan = executor.plan(&ast).unwrap();
+            let expected = executor.select(&plan, file.records(), plan.limit.as_ref()).unwrap();
+            assert_eq!(executor.select_chunks(&plan, file.chunks(7, 1)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn it_gives_the_same_results_with_an_index() {
+        let lines: Vec<String> = (0..500).map(|n| format!("ts=2018-03-04T10:{:02}:{:02}Z n={} request_id=req-{}", n / 60, n % 60, n, n)).collect();
+        let logs = Logs::new();
+        logs.write("app.log", &lines.iter().map(String::as_str).collect::<Vec<&str>>());
+
+        let queries = [
+            "SELECT n, _line FROM '$DIR/app.log' WHERE request_id = 'req-42'",
+            "SELECT count(*) FROM '$DIR/app.log' WHERE request_id = 'req-none'",
+            "SELECT n FROM '$DIR/app.log' WHERE ts >= '2018-03-04T10:08:00Z' LIMIT 3",
+            "SELECT count(*) FROM '$DIR/app.log' WHERE ts < '2000-01-01'"
+        ];
+        let expected: Vec<ResultSet> = queries.iter().map(|query| logs.query(query).unwrap()).collect();
+        index::build(Path::new(&logs.path("app.log"))).unwrap();
+        for (query, expected) in queries.iter().zip(expected) {
+            assert_eq!(logs.query(query).unwrap(), expected, "{}", query);
+        }
+
+        let mut file = OpenOptions::new().append(true).open(logs.path("app.log")).unwrap();
+        file.write_all(b"ts=2018-03-04T11:00:00Z n=500 request_id=req-none\n").unwrap();
+        assert_eq!(logs.query(queries[1]).unwrap().rows, integers(&[1]));
+    }
+
+    #[test]
+    fn it_gives_the_same_results_from_the_cache() {
+        let lines: Vec<String> = (0..200).map(|n| format!("ts=2018-03-04T10:{:02}:{:02}Z n={} level={}", n / 60 * 2, n % 60, n, ["info", "warn", "error"][n % 3])).collect();
+        let logs = Logs::new();
+        logs.write("app.log", &lines.iter().map(String::as_str).collect::<Vec<&str>>())
+            .write("app.log.1", &["ts=2018-03-04T10:01:00Z n=1000 level=error", "  at main.rs:10", "ts=2018-03-04T10:03:00Z n=1001"]);
+
+        let queries = [
+            "SELECT n, _line FROM '$DIR/app.log' WHERE level = 'error' LIMIT 5",
+            "SELECT * FROM '$DIR/app.log*' WHERE n >= 199",
+            "SELECT n, _file FROM '$DIR/app.log*' WHERE level = 'error' LIMIT LAST 3",
+            "SELECT count(*), max(n) FROM '$DIR/app.log*' WHERE level != 'info'"
+        ];
+        let cache_directory = TempDir::new().unwrap();
+        let mut executor = Executor::new(FunctionRegistry::new());
+        executor.set_cache(Cache::new(cache_directory.path().to_path_buf()));
+
+        for query in &queries {
+            let ast = logs.query_ast(query);
+            let expected = Executor::new(FunctionRegistry::new()).execute(&ast).unwrap();
+            assert_eq!(executor.execute(&ast).unwrap(), expected, "{}", query);
+            assert_eq!(executor.execute(&ast).unwrap(), expected, "{}", query);
+        }
+        assert_eq!(fs::read_dir(cache_directory.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn it_finds_values_written_with_escapes_despite_prefiltering() {
+        let logs = Logs::new();
+        logs.write("app.log", &[
+            "n=1 msg=\"connection timeout\"",
+            "n=2 msg=\"connection t\\imeout\"",
+            "{\"n\": 3, \"msg\": \"connection \\u0074imeout\"}",
+            "n=4 msg=\"connection refused\""
+        ]);
+
+        let result = logs.query("SELECT n FROM '$DIR/app.log' WHERE msg LIKE '%timeout%'").unwrap();
+        assert_eq!(result.rows, integers(&[1, 2, 3]));
+        let result = logs.query("SELECT n FROM '$DIR/app.log' WHERE msg = 'connection timeout'").unwrap();
+        assert_eq!(result.rows, integers(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn it_finds_the_last_matching_records_of_large_files() {
+        let lines: Vec<String> = (1..20001).map(|n| format!("n={} level={}", n, if n % 1000 == 0 { "error" } else { "info" })).collect();
+        let logs = Logs::new();
+        logs.write("app.log", &lines.iter().map(String::as_str).collect::<Vec<&str>>());
+
+        let result = logs.query("SELECT n FROM '$DIR/app.log' WHERE level = 'error' LIMIT LAST 3").unwrap();
+        assert_eq!(result.rows, integers(&[18000, 19000, 20000]));
+    }
+
+    #[test]
+    fn it_keeps_multi_line_records_together_with_limit_last() {
+        let logs = Logs::new();
+        logs.write("app.log", &["n=1", "n=2 msg=boom", "  at main.rs:10", "n=3"]);
+
+        let result = logs.query("SELECT n, msg, _line FROM '$DIR/app.log' LIMIT LAST 2").unwrap();
+        assert_eq!(result.rows, vec!(
+            vec!(Value::Integer(2), Value::Str("boom".into()), Value::Integer(2)),
+            vec!(Value::Integer(3), Value::Null, Value::Integer(4))
+        ));
+
+        let result = logs.query("SELECT n, msg FROM '$DIR/app.log' LIMIT LAST 2").unwrap();
+        assert_eq!(result.rows, vec!(vec!(Value::Integer(2), Value::Str("boom".into())), vec!(Value::Integer(3), Value::Null)));
+    }
+}
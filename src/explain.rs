@@ -0,0 +1,217 @@
+//! Describes how a query is run for `EXPLAIN`, and what running it did for
+//! `EXPLAIN ANALYZE`.
+
+use std::fs;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+use parser::LimitDirection;
+use plan::{Access, LogicalPlan, PhysicalPlan};
+use record::Format;
+use source::{self, Compression};
+
+/// What the stages of a query did, collected while running it for
+/// `EXPLAIN ANALYZE`. Durations are summed over the threads scanning the
+/// files, so they can exceed the time the query took.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+pub struct Statistics {
+    /// Records read from the files, after any blocks skipped by an index.
+    pub records: usize,
+    /// Bytes of text of the records read, without line endings.
+    pub bytes: usize,
+    /// Records without any field, only available as `message`.
+    pub unparsed: usize,
+    /// Records skipped because their text lacks the literals the condition needs.
+    pub prefiltered: usize,
+    /// Records matching the condition.
+    pub matched: usize,
+    /// Rows computed by aggregating, one per group.
+    pub groups: usize,
+    pub scanning: Duration,
+    pub filtering: Duration,
+    pub sorting: Duration
+}
+
+impl Statistics {
+    pub fn add(&mut self, other: &Statistics) {
+        self.records += other.records;
+        self.bytes += other.bytes;
+        self.unparsed += other.unparsed;
+        self.prefiltered += other.prefiltered;
+        self.matched += other.matched;
+        self.groups += other.groups;
+        self.scanning += other.scanning;
+        self.filtering += other.filtering;
+        self.sorting += other.sorting;
+    }
+}
+
+/// The outcome of running a query for `EXPLAIN ANALYZE`.
+pub struct Analysis {
+    pub statistics: Statistics,
+    /// Rows returned.
+    pub rows: usize,
+    pub planning: Duration,
+    pub execution: Duration
+}
+
+/// The lines printed by `EXPLAIN`: the stages of `logical`, annotated with
+/// what they did if the query was run, followed by how `physical` reads the files.
+pub fn describe(logical: &LogicalPlan, physical: &PhysicalPlan, analysis: Option<&Analysis>) -> Result<Vec<String>, String> {
+    let mut lines: Vec<String> = logical.to_string().lines().map(String::from).collect();
+    if let Some(analysis) = analysis {
+        for (line, stage) in lines.iter_mut().zip(stages(logical)) {
+            line.push_str(&format!(" ({})", annotate(stage, physical, analysis)));
+        }
+    }
+
+    lines.push(format!("Access: {}", describe_access(physical)));
+    for path in source::resolve(&physical.sources)? {
+        lines.push(format!("Source: {}", describe_source(&path)?));
+    }
+    if let Some(ref prefilter) = physical.prefilter {
+        let literals: Vec<String> = prefilter.literals().iter().map(|literal| format!("'{}'", literal)).collect();
+        lines.push(format!("Pushed down: records without {} are skipped before parsing", literals.join(" and ")));
+    }
+    if let Some((number_of_rows, ref direction)) = physical.sort.as_ref().and_then(|sort| sort.top.as_ref()) {
+        let which = if *direction == LimitDirection::First { "first" } else { "last" };
+        lines.push(format!("Top-k: sorting keeps only the {} {}", which, count(*number_of_rows, "row")));
+    }
+
+    if let Some(analysis) = analysis {
+        lines.push(format!("Planning time: {}", format_duration(analysis.planning)));
+        lines.push(format!("Execution time: {}", format_duration(analysis.execution)));
+    }
+    Ok(lines)
+}
+
+/// The stages of `plan`, from the last one to the scan, as they are displayed.
+fn stages(plan: &LogicalPlan) -> Vec<&LogicalPlan> {
+    let mut stages = vec!();
+    let mut stage = Some(plan);
+    while let Some(plan) = stage {
+        stages.push(plan);
+        stage = plan.input();
+    }
+    stages
+}
+
+fn annotate(stage: &LogicalPlan, physical: &PhysicalPlan, analysis: &Analysis) -> String {
+    let statistics = &analysis.statistics;
+    match *stage {
+        LogicalPlan::Scan { .. } => {
+            format!("{}, {}, {} without fields, {}", count(statistics.records, "record"), format_bytes(statistics.bytes as u64),
+                statistics.unparsed, format_duration(statistics.scanning))
+        },
+        LogicalPlan::Filter { .. } => {
+            let mut parts = vec!(count(statistics.matched, "row"));
+            if physical.prefilter.is_some() {
+                parts.push(format!("{} skipped before parsing", statistics.prefiltered));
+            }
+            parts.push(format_duration(statistics.filtering));
+            parts.join(", ")
+        },
+        LogicalPlan::Aggregate { .. } => count(statistics.groups, "row"),
+        LogicalPlan::Sort { .. } => format!("{}, {}", count(sorted_rows(physical, statistics), "row"), format_duration(statistics.sorting)),
+        LogicalPlan::Project { .. } => count(sorted_rows(physical, statistics), "row"),
+        LogicalPlan::Limit { .. } => count(analysis.rows, "row")
+    }
+}
+
+/// The rows coming out of sorting, or the matching rows if there is no sort.
+fn sorted_rows(physical: &PhysicalPlan, statistics: &Statistics) -> usize {
+    match physical.sort.as_ref().and_then(|sort| sort.top.as_ref()) {
+        Some(&(number_of_rows, _)) => statistics.matched.min(number_of_rows),
+        None => statistics.matched
+    }
+}
+
+fn describe_access(physical: &PhysicalPlan) -> String {
+    let access = match physical.access {
+        Access::Backwards => "read backwards from the end of the files, stopping once enough rows matched".into(),
+        Access::Cached => "read from the columns in the cache".into(),
+        Access::Indexed { ref index, threads } => {
+            format!("mapped into memory, {} of {} blocks selected by the index, on {}",
+                index.matching_blocks(physical.condition()), index.block_count(), count(threads, "thread"))
+        },
+        Access::Mapped { threads } => format!("mapped into memory, on {}", count(threads, "thread")),
+        Access::Sequential => "read from the start, merging the files by timestamp".into()
+    };
+    if physical.follow {
+        format!("{}, then following new records", access)
+    } else {
+        access
+    }
+}
+
+/// The path, compression, size and record format of a file to read.
+fn describe_source(path: &Path) -> Result<String, String> {
+    if path == Path::new(source::STDIN) {
+        return Ok("STDIN".into());
+    }
+
+    let compression = match source::detect_compression(path)? {
+        Compression::None => "uncompressed",
+        Compression::Gzip => "gzip",
+        Compression::Zstd => "zstd",
+        Compression::Bzip2 => "bzip2"
+    };
+    let length = fs::metadata(path).map_err(|err| format!("Cannot read {:?}: {}", path, err))?.len();
+
+    // The format of the first record is taken for the format of the file.
+    let mut format = None;
+    for line in source::open_file(path)?.lines() {
+        let line = line.map_err(|err| format!("Cannot read {:?}: {}", path, err))?;
+        if !line.trim().is_empty() {
+            format = Some(Format::of(&line));
+            break;
+        }
+    }
+
+    let mut description = format!("'{}' ({}, {}", path.display(), compression, format_bytes(length));
+    match format {
+        Some(format) => description.push_str(&format!(", {})", format)),
+        None => description.push_str(", empty)")
+    }
+    Ok(description)
+}
+
+fn count(number: usize, noun: &str) -> String {
+    format!("{} {}{}", number, noun, if number == 1 { "" } else { "s" })
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_sizes_and_counts() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(count(1, "row"), "1 row");
+        assert_eq!(count(0, "row"), "0 rows");
+        assert_eq!(format_duration(Duration::from_micros(1500)), "1.500 ms");
+    }
+}
@@ -0,0 +1,241 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use record::{self, Record};
+use source::{self, Compression};
+
+/// Identifies the file behind a path, so rotation can be told apart from appends.
+#[cfg(unix)]
+fn identity(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn identity(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// A file being watched for appended lines, like `tail -F` does.
+struct FollowedFile {
+    path: PathBuf,
+    name: Arc<String>,
+    file: File,
+    identity: Option<(u64, u64)>,
+    position: u64,
+    /// Line number of the last complete line read, unknown when following started at the end.
+    line: Option<usize>,
+    /// Bytes of a line that has not been terminated yet.
+    partial: Vec<u8>,
+    /// The last record read, held back until a line shows it has no more continuation lines.
+    pending: Option<(Vec<u8>, usize)>
+}
+
+impl FollowedFile {
+    fn open(path: &Path) -> Result<FollowedFile, String> {
+        let file = File::open(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+        let metadata = file.metadata().map_err(|err| format!("Cannot read {:?}: {}", path, err))?;
+
+        Ok(FollowedFile {
+            path: path.to_path_buf(),
+            name: Arc::new(path.to_string_lossy().into_owned()),
+            identity: identity(&metadata),
+            position: metadata.len(),
+            file,
+            line: None,
+            partial: vec!(),
+            pending: None
+        })
+    }
+
+    /// Reads the lines appended since the last poll, then checks whether the
+    /// file was truncated or replaced by a new one.
+    fn poll(&mut self, records: &mut Vec<Record>) -> Result<(), String> {
+        let length = self.file.metadata().map_err(|err| format!("Cannot read {}: {}", self.name, err))?.len();
+        if length < self.position {
+            // Truncated in place, e.g. by `copytruncate` log rotation.
+            self.restart();
+        }
+
+        if !self.read_appended(records)? {
+            self.flush(records);
+
+            if let Ok(metadata) = fs::metadata(&self.path) {
+                if identity(&metadata) != self.identity {
+                    // Rotated: the old file has been read to its end, continue with the new one.
+                    if let Ok(file) = File::open(&self.path) {
+                        self.file = file;
+                        self.identity = identity(&metadata);
+                        self.restart();
+                        self.read_appended(records)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn restart(&mut self) {
+        self.position = 0;
+        self.line = Some(0);
+        self.partial.clear();
+    }
+
+    /// Returns whether any bytes were appended.
+    fn read_appended(&mut self, records: &mut Vec<Record>) -> Result<bool, String> {
+        let mut appended = vec!();
+        self.file.seek(SeekFrom::Start(self.position))
+            .and_then(|_| (&self.file).read_to_end(&mut appended))
+            .map_err(|err| format!("Cannot read {}: {}", self.name, err))?;
+        if appended.is_empty() {
+            return Ok(false);
+        }
+        self.position += appended.len() as u64;
+        self.partial.extend_from_slice(&appended);
+
+        let mut start = 0;
+        while let Some(newline) = self.partial[start..].iter().position(|&byte| byte == b'\n') {
+            let end = start + newline;
+            let mut line = self.partial[start..end].to_vec();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            start = end + 1;
+            self.line = self.line.map(|line| line + 1);
+            self.push_line(line, records);
+        }
+        self.partial.drain(..start);
+
+        Ok(true)
+    }
+
+    fn push_line(&mut self, line: Vec<u8>, records: &mut Vec<Record>) {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+
+        match &mut self.pending {
+            Some((text, _)) if record::is_continuation(&line) => {
+                text.push(b'\n');
+                text.extend_from_slice(&line);
+            },
+            _ => {
+                self.flush(records);
+                self.pending = Some((line, self.line.unwrap_or(0)));
+            }
+        }
+    }
+
+    fn flush(&mut self, records: &mut Vec<Record>) {
+        if let Some((text, line)) = self.pending.take() {
+            records.push(Record::parse(&String::from_utf8_lossy(&text), self.name.clone(), line));
+        }
+    }
+}
+
+/// Watches files for records appended to them.
+///
+/// Following starts at the current end of each file. Truncated files are
+/// read again from their start, and when a path is rotated to a new file the
+/// rest of the old file is read before switching over. Compressed files are
+/// rotated archives that no longer change, so they are not followed.
+pub struct Follower {
+    files: Vec<FollowedFile>
+}
+
+impl Follower {
+    pub fn open(paths: &[PathBuf]) -> Result<Follower, String> {
+        let mut files = vec!();
+        for path in paths {
+            if source::detect_compression(path)? == Compression::None {
+                files.push(FollowedFile::open(path)?);
+            }
+        }
+        Ok(Follower { files })
+    }
+
+    /// Returns the records appended to the files since the last call, file by file.
+    ///
+    /// A record is returned once the line after it has been written or once a
+    /// poll finds nothing new, so continuation lines written together with it
+    /// are not split off.
+    pub fn poll(&mut self) -> Result<Vec<Record>, String> {
+        let mut records = vec!();
+        for file in &mut self.files {
+            file.poll(&mut records)?;
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    use functions::Value;
+
+    fn append(path: &Path, content: &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn values(follower: &mut Follower) -> Vec<Value> {
+        follower.poll().unwrap().iter().map(|record| record.get("n")).collect()
+    }
+
+    #[test]
+    fn it_reads_records_appended_after_following_started() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        append(&path, "n=1\n");
+
+        let mut follower = Follower::open(std::slice::from_ref(&path)).unwrap();
+        assert_eq!(values(&mut follower), vec!());
+
+        append(&path, "n=2 msg=boom\n  at main.rs:10\nn=");
+        assert_eq!(values(&mut follower), vec!());
+        append(&path, "3\n");
+        assert_eq!(values(&mut follower), vec!(Value::Integer(2)));
+        assert_eq!(values(&mut follower), vec!(Value::Integer(3)));
+    }
+
+    #[test]
+    fn it_starts_over_when_the_file_is_truncated() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        append(&path, "n=1\nn=2\n");
+
+        let mut follower = Follower::open(std::slice::from_ref(&path)).unwrap();
+        File::create(&path).unwrap();
+        append(&path, "n=3\n");
+        values(&mut follower);
+        let records = follower.poll().unwrap();
+
+        assert_eq!(records.iter().map(|record| record.get("n")).collect::<Vec<Value>>(), vec!(Value::Integer(3)));
+        assert_eq!(records[0].line, 1);
+    }
+
+    #[test]
+    fn it_switches_to_the_new_file_after_rotation() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        append(&path, "n=1\n");
+
+        let mut follower = Follower::open(std::slice::from_ref(&path)).unwrap();
+        append(&path, "n=2\n");
+        fs::rename(&path, directory.path().join("app.log.1")).unwrap();
+        append(&path, "n=3\n");
+
+        let mut seen = vec!();
+        for _ in 0..3 {
+            seen.extend(values(&mut follower));
+        }
+        assert_eq!(seen, vec!(Value::Integer(2), Value::Integer(3)));
+    }
+}
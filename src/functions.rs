@@ -0,0 +1,1009 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::{Arc, RwLock};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use regex::Regex;
+use serde_json;
+
+use parser::{ArithmeticOperator, Expression, WhereComparator};
+
+const TIMESTAMP_FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+/// Patterns a regular expression function keeps compiled before starting over.
+const REGEX_CACHE_SIZE: usize = 64;
+const DATE_TRUNC_UNITS: [&str; 7] = ["second", "minute", "hour", "day", "week", "month", "year"];
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum DataType {
+    String,
+    Integer,
+    Float,
+    Number,
+    Timestamp,
+    Boolean,
+    Any
+}
+
+impl DataType {
+    /// Whether a value of type `actual` may be passed where `self` is expected.
+    /// Strings are accepted as timestamps and parsed when the function runs.
+    pub fn accepts(&self, actual: &DataType) -> bool {
+        match (self, actual) {
+            (&DataType::Any, _) | (_, &DataType::Any) => true,
+            (&DataType::Number, &DataType::Integer) | (&DataType::Number, &DataType::Float) => true,
+            (&DataType::Float, &DataType::Integer) | (&DataType::Float, &DataType::Number) => true,
+            (&DataType::Integer, &DataType::Number) => true,
+            (&DataType::Timestamp, &DataType::String) => true,
+            (expected, actual) => expected == actual
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        DataType::Number.accepts(self)
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum Value {
+    Null,
+    Str(String),
+    Integer(i64),
+    Float(f64),
+    Timestamp(DateTime<Utc>),
+    Boolean(bool)
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        *self == Value::Null
+    }
+
+    pub fn as_str(&self) -> Result<String, String> {
+        match *self {
+            Value::Str(ref s) => Ok(s.clone()),
+            Value::Null => Err("Expected String, got NULL".into()),
+            ref other => Ok(other.to_string())
+        }
+    }
+
+    pub fn as_integer(&self) -> Result<i64, String> {
+        match *self {
+            Value::Integer(i) => Ok(i),
+            Value::Float(f) => Ok(f as i64),
+            Value::Str(ref s) => s.trim().parse::<i64>().map_err(|_| format!("Expected Integer, got {:?}", s)),
+            ref other => Err(format!("Expected Integer, got {:?}", other))
+        }
+    }
+
+    pub fn as_float(&self) -> Result<f64, String> {
+        match *self {
+            Value::Integer(i) => Ok(i as f64),
+            Value::Float(f) => Ok(f),
+            Value::Str(ref s) => s.trim().parse::<f64>().map_err(|_| format!("Expected Float, got {:?}", s)),
+            ref other => Err(format!("Expected Float, got {:?}", other))
+        }
+    }
+
+    pub fn as_timestamp(&self) -> Result<DateTime<Utc>, String> {
+        match *self {
+            Value::Timestamp(timestamp) => Ok(timestamp),
+            Value::Integer(seconds) => Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| format!("Timestamp out of range: {}", seconds)),
+            Value::Str(ref s) => parse_timestamp(s, None),
+            ref other => Err(format!("Expected Timestamp, got {:?}", other))
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    /// Numbers compare numerically regardless of representation, NULL sorts
+    /// before everything else and values of unrelated types are unordered.
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, Value::Null) => Some(Ordering::Equal),
+            (Value::Null, _) => Some(Ordering::Less),
+            (_, Value::Null) => Some(Ordering::Greater),
+            (Value::Integer(left), Value::Integer(right)) => left.partial_cmp(right),
+            (Value::Integer(_), Value::Float(_)) | (Value::Float(_), Value::Integer(_)) | (Value::Float(_), Value::Float(_)) => {
+                self.as_float().ok()?.partial_cmp(&other.as_float().ok()?)
+            },
+            (Value::Str(left), Value::Str(right)) => left.partial_cmp(right),
+            (Value::Timestamp(left), Value::Timestamp(right)) => left.partial_cmp(right),
+            (Value::Boolean(left), Value::Boolean(right)) => left.partial_cmp(right),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => write!(f, "NULL"),
+            Value::Str(ref s) => write!(f, "{}", s),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Timestamp(timestamp) => write!(f, "{}", timestamp.to_rfc3339()),
+            Value::Boolean(b) => write!(f, "{}", b)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Signature {
+    pub arguments: Vec<DataType>,
+    pub optional_arguments: usize,
+    pub variadic: bool,
+    pub return_type: DataType
+}
+
+impl Signature {
+    pub fn new(arguments: Vec<DataType>, return_type: DataType) -> Signature {
+        Signature {
+            arguments,
+            optional_arguments: 0,
+            variadic: false,
+            return_type
+        }
+    }
+
+    /// Marks the last `count` arguments as optional.
+    ///
+    /// # Panics
+    ///
+    /// If `count` exceeds the number of arguments.
+    pub fn with_optional_arguments(mut self, count: usize) -> Signature {
+        assert!(count <= self.arguments.len(), "{} optional arguments given for a signature of {} arguments", count, self.arguments.len());
+        self.optional_arguments = count;
+        self
+    }
+
+    /// Allows the last argument type to be repeated any number of times.
+    pub fn variadic(mut self) -> Signature {
+        self.variadic = true;
+        self
+    }
+
+    /// The number of arguments that must be passed. `optional_arguments` is a
+    /// public field, so it is not trusted to be at most the number of arguments.
+    fn required_arguments(&self) -> usize {
+        self.arguments.len().saturating_sub(self.optional_arguments)
+    }
+
+    fn check(&self, name: &str, argument_types: &[DataType]) -> Result<DataType, String> {
+        let required = self.required_arguments();
+        if argument_types.len() < required || (!self.variadic && argument_types.len() > self.arguments.len()) {
+            return Err(format!("Function {} expects {} arguments, got {}", name, self.describe_arity(), argument_types.len()));
+        }
+
+        for (index, actual) in argument_types.iter().enumerate() {
+            let expected = self.arguments.get(index).or_else(|| self.arguments.last()).unwrap_or(&DataType::Any);
+            if !expected.accepts(actual) {
+                return Err(format!("Function {} expects argument {} to be {:?}, got {:?}", name, index + 1, expected, actual));
+            }
+        }
+
+        Ok(self.return_type.clone())
+    }
+
+    fn describe_arity(&self) -> String {
+        let required = self.required_arguments();
+        if self.variadic {
+            format!("at least {}", required)
+        } else if self.optional_arguments > 0 {
+            format!("{} to {}", required, self.arguments.len())
+        } else {
+            required.to_string()
+        }
+    }
+}
+
+pub type ScalarFunction = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+/// Running state of an aggregate function over a group of rows.
+///
+/// Partial results computed over separate parts of the input are combined by
+/// feeding the `state` of one accumulator into `merge` of another.
+pub trait Accumulator: Send {
+    fn update(&mut self, arguments: &[Value]) -> Result<(), String>;
+    fn state(&self) -> Vec<Value>;
+    fn merge(&mut self, state: &[Value]) -> Result<(), String>;
+    fn finish(&self) -> Result<Value, String>;
+}
+
+pub type AggregateFunction = Arc<dyn Fn() -> Box<dyn Accumulator> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    scalars: HashMap<String, (Signature, ScalarFunction)>,
+    aggregates: HashMap<String, (Signature, AggregateFunction)>
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> FunctionRegistry {
+        let mut registry = FunctionRegistry::empty();
+        register_builtins(&mut registry);
+        register_builtin_aggregates(&mut registry);
+        registry
+    }
+}
+
+impl FunctionRegistry {
+    /// A registry with the built-in function library.
+    pub fn new() -> FunctionRegistry {
+        FunctionRegistry::default()
+    }
+
+    /// A registry without any functions.
+    pub fn empty() -> FunctionRegistry {
+        FunctionRegistry {
+            scalars: HashMap::new(),
+            aggregates: HashMap::new()
+        }
+    }
+
+    pub fn register_scalar<F>(&mut self, name: &str, signature: Signature, function: F)
+        where F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static {
+        self.scalars.insert(name.to_lowercase(), (signature, Arc::new(function)));
+    }
+
+    /// Registers an aggregate function; `accumulator` is called once per group
+    /// to create the state the group's rows are folded into.
+    pub fn register_aggregate<F>(&mut self, name: &str, signature: Signature, accumulator: F)
+        where F: Fn() -> Box<dyn Accumulator> + Send + Sync + 'static {
+        self.aggregates.insert(name.to_lowercase(), (signature, Arc::new(accumulator)));
+    }
+
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        let name = name.to_lowercase();
+        self.scalars.get(&name).map(|(signature, _)| signature)
+            .or_else(|| self.aggregates.get(&name).map(|(signature, _)| signature))
+    }
+
+    pub fn is_aggregate(&self, name: &str) -> bool {
+        self.aggregates.contains_key(&name.to_lowercase())
+    }
+
+    /// Whether `expression` calls an aggregate function anywhere.
+    pub fn contains_aggregate(&self, expression: &Expression) -> bool {
+        match *expression {
+            Expression::FunctionCall { ref name, ref arguments } => {
+                self.is_aggregate(name) || arguments.iter().any(|argument| self.contains_aggregate(argument))
+            },
+            Expression::Negate(ref operand) => self.contains_aggregate(operand),
+            Expression::BinaryOperation { ref left, ref right, .. } | Expression::Comparison { ref left, ref right, .. } => {
+                self.contains_aggregate(left) || self.contains_aggregate(right)
+            },
+            Expression::Case { ref branches, ref else_result } => {
+                branches.iter().any(|(condition, result)| self.contains_aggregate(condition) || self.contains_aggregate(result)) ||
+                    else_result.iter().any(|result| self.contains_aggregate(result))
+            },
+            _ => false
+        }
+    }
+
+    pub fn create_accumulator(&self, name: &str) -> Result<Box<dyn Accumulator>, String> {
+        match self.aggregates.get(&name.to_lowercase()) {
+            Some((_, accumulator)) => Ok(accumulator()),
+            None => Err(format!("Unknown aggregate function {}", name))
+        }
+    }
+
+    pub fn call(&self, name: &str, arguments: &[Value]) -> Result<Value, String> {
+        match self.scalars.get(&name.to_lowercase()) {
+            Some((_, function)) => function(arguments),
+            None => Err(format!("Unknown function {}", name))
+        }
+    }
+
+    /// Infers the type of `expression`, validating every function call against
+    /// its signature. Fields are untyped until a scan reads them, so they check as `Any`.
+    pub fn check_expression(&self, expression: &Expression) -> Result<DataType, String> {
+        self.check(expression, false)
+    }
+
+    /// Validates `left mode right` as used by `WHERE` conditions and `CASE` branches.
+    pub fn check_comparison(&self, left: &Expression, mode: &WhereComparator, right: &Expression) -> Result<DataType, String> {
+        let left_type = self.check_expression(left)?;
+        let right_type = self.check_expression(right)?;
+        comparison_result_type(mode, left_type, right_type)
+    }
+
+    fn check(&self, expression: &Expression, inside_aggregate: bool) -> Result<DataType, String> {
+        match *expression {
+            Expression::Wildcard | Expression::Field(_) => Ok(DataType::Any),
+            Expression::Str(_) => Ok(DataType::String),
+            Expression::Number(_) => Ok(DataType::Integer),
+            Expression::Float(_) => Ok(DataType::Float),
+            Expression::Negate(ref operand) => {
+                let operand_type = self.check(operand, inside_aggregate)?;
+                if operand_type.is_numeric() {
+                    Ok(operand_type)
+                } else {
+                    Err(format!("Cannot negate {:?}", operand_type))
+                }
+            },
+            Expression::BinaryOperation { ref left, ref operator, ref right } => {
+                let left_type = self.check(left, inside_aggregate)?;
+                let right_type = self.check(right, inside_aggregate)?;
+                arithmetic_result_type(operator, left_type, right_type)
+            },
+            Expression::Comparison { ref left, ref mode, ref right } => {
+                let left_type = self.check(left, inside_aggregate)?;
+                let right_type = self.check(right, inside_aggregate)?;
+                comparison_result_type(mode, left_type, right_type)
+            },
+            Expression::Case { ref branches, ref else_result } => {
+                for (condition, _) in branches {
+                    let condition_type = self.check(condition, inside_aggregate)?;
+                    if !DataType::Boolean.accepts(&condition_type) {
+                        return Err(format!("CASE condition must be Boolean, got {:?}", condition_type));
+                    }
+                }
+
+                let mut result_type = None;
+                for result in branches.iter().map(|(_, result)| result).chain(else_result.iter().map(|result| &**result)) {
+                    let branch_type = self.check(result, inside_aggregate)?;
+                    result_type = Some(match result_type {
+                        None => branch_type,
+                        Some(previous) => unify_types(previous, branch_type).ok_or("CASE branches have incompatible result types")?
+                    });
+                }
+                Ok(result_type.unwrap_or(DataType::Any))
+            },
+            Expression::FunctionCall { ref name, ref arguments } => {
+                let signature = self.signature(name).ok_or_else(|| format!("Unknown function {}", name))?;
+                let is_aggregate = self.is_aggregate(name);
+                if is_aggregate && inside_aggregate {
+                    return Err(format!("Aggregate function {} cannot be nested inside another aggregate", name));
+                }
+
+                let mut argument_types = vec!();
+                for argument in arguments {
+                    if *argument == Expression::Wildcard && !is_aggregate {
+                        return Err(format!("Function {} does not accept *", name));
+                    }
+                    argument_types.push(self.check(argument, inside_aggregate || is_aggregate)?);
+                }
+                let return_type = signature.check(name, &argument_types)?;
+                check_literal_arguments(&name.to_lowercase(), arguments)?;
+                Ok(return_type)
+            }
+        }
+    }
+}
+
+fn arithmetic_result_type(operator: &ArithmeticOperator, left: DataType, right: DataType) -> Result<DataType, String> {
+    if !left.is_numeric() || !right.is_numeric() {
+        return Err(format!("Cannot apply {:?} to {:?} and {:?}", operator, left, right));
+    }
+
+    match (left, right) {
+        _ if *operator == ArithmeticOperator::Divide => Ok(DataType::Float),
+        (DataType::Integer, DataType::Integer) => Ok(DataType::Integer),
+        (DataType::Float, _) | (_, DataType::Float) => Ok(DataType::Float),
+        _ => Ok(DataType::Number)
+    }
+}
+
+fn comparison_result_type(mode: &WhereComparator, left: DataType, right: DataType) -> Result<DataType, String> {
+    let comparable = match *mode {
+        WhereComparator::Like => DataType::String.accepts(&left) && DataType::String.accepts(&right),
+        _ => unify_types(left.clone(), right.clone()).is_some() || (left == DataType::Timestamp && right == DataType::String)
+    };
+
+    if comparable {
+        Ok(DataType::Boolean)
+    } else {
+        Err(format!("Cannot compare {:?} with {:?} using {:?}", left, right, mode))
+    }
+}
+
+/// The type both `left` and `right` can be represented as, if any.
+fn unify_types(left: DataType, right: DataType) -> Option<DataType> {
+    if left.accepts(&right) {
+        Some(left)
+    } else if right.accepts(&left) {
+        Some(right)
+    } else {
+        None
+    }
+}
+
+/// Arguments which configure a function rather than feed it data (regular
+/// expressions, truncation units, formats) are validated once when they are literals.
+fn check_literal_arguments(name: &str, arguments: &[Expression]) -> Result<(), String> {
+    match (name, arguments.first(), arguments.get(1)) {
+        ("regexp_extract", _, Some(Expression::Str(pattern))) | ("regexp_replace", _, Some(Expression::Str(pattern))) => {
+            Regex::new(pattern).map(|_| ()).map_err(|err| format!("Invalid regular expression in {}: {}", name, err))
+        },
+        ("strftime", _, Some(Expression::Str(format))) => {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                Err(format!("Invalid format for strftime: {:?}", format))
+            } else {
+                Ok(())
+            }
+        },
+        ("date_trunc", Some(Expression::Str(unit)), _) => {
+            if DATE_TRUNC_UNITS.contains(&unit.to_lowercase().as_str()) {
+                Ok(())
+            } else {
+                Err(format!("Invalid unit for date_trunc: {:?}, expected one of {:?}", unit, DATE_TRUNC_UNITS))
+            }
+        },
+        _ => Ok(())
+    }
+}
+
+fn parse_timestamp(input: &str, format: Option<&str>) -> Result<DateTime<Utc>, String> {
+    let input = input.trim();
+
+    if let Some(format) = format {
+        return NaiveDateTime::parse_from_str(input, format)
+            .or_else(|_| NaiveDate::parse_from_str(input, format).map(|date| date.and_hms_opt(0, 0, 0).unwrap()))
+            .map(|timestamp| Utc.from_utc_datetime(&timestamp))
+            .map_err(|err| format!("Cannot parse {:?} as timestamp with format {:?}: {}", input, format, err));
+    }
+
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(input) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    for format in &TIMESTAMP_FORMATS {
+        if let Ok(timestamp) = NaiveDateTime::parse_from_str(input, format) {
+            return Ok(Utc.from_utc_datetime(&timestamp));
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+    }
+    if let Ok(seconds) = input.parse::<i64>() {
+        return Value::Integer(seconds).as_timestamp();
+    }
+
+    Err(format!("Cannot parse {:?} as timestamp", input))
+}
+
+/// Rounds `number` to `digits` decimal places, or to tens, hundreds and so
+/// on for negative `digits`.
+fn round_to(number: f64, digits: i64) -> f64 {
+    // Past the range of f64 every number is either already that precise or rounds to 0.
+    if digits > 308 {
+        return number;
+    }
+    if digits < -308 {
+        return 0.0;
+    }
+    let factor = 10f64.powi(digits as i32);
+    let scaled = number * factor;
+    // From 2^52 on, f64 holds whole numbers only, so there is nothing left to round.
+    if !scaled.is_finite() || scaled.abs() >= 2f64.powi(52) {
+        return number;
+    }
+    scaled.round() / factor
+}
+
+/// Formats `timestamp` like `strftime`, failing on invalid formats which
+/// chrono would otherwise panic on.
+fn format_timestamp(timestamp: DateTime<Utc>, format: &str) -> Result<String, String> {
+    let mut formatted = String::new();
+    write!(formatted, "{}", timestamp.format(format)).map_err(|_| format!("Invalid format for strftime: {:?}", format))?;
+    Ok(formatted)
+}
+
+fn truncate_timestamp(unit: &str, timestamp: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let date = timestamp.date_naive();
+    let truncated = match unit.to_lowercase().as_str() {
+        "second" => date.and_hms_opt(timestamp.hour(), timestamp.minute(), timestamp.second()),
+        "minute" => date.and_hms_opt(timestamp.hour(), timestamp.minute(), 0),
+        "hour" => date.and_hms_opt(timestamp.hour(), 0, 0),
+        "day" => date.and_hms_opt(0, 0, 0),
+        "week" => (date - Duration::days(i64::from(date.weekday().num_days_from_monday()))).and_hms_opt(0, 0, 0),
+        "month" => NaiveDate::from_ymd_opt(date.year(), date.month(), 1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        "year" => NaiveDate::from_ymd_opt(date.year(), 1, 1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        _ => return Err(format!("Invalid unit for date_trunc: {:?}, expected one of {:?}", unit, DATE_TRUNC_UNITS))
+    };
+
+    Ok(Utc.from_utc_datetime(&truncated.expect("truncated timestamp is valid")))
+}
+
+pub(crate) fn json_to_value(json: &serde_json::Value) -> Value {
+    match *json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::String(ref s) => Value::Str(s.clone()),
+        serde_json::Value::Bool(b) => Value::Boolean(b),
+        serde_json::Value::Number(ref n) => {
+            n.as_i64().map(Value::Integer).unwrap_or_else(|| Value::Float(n.as_f64().unwrap_or(0.0)))
+        },
+        ref other => Value::Str(other.to_string())
+    }
+}
+
+fn json_extract(document: &str, path: &str) -> Value {
+    let mut current = match serde_json::from_str::<serde_json::Value>(document) {
+        Ok(json) => json,
+        Err(_) => return Value::Null
+    };
+
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+    for segment in path.split('.').filter(|segment| !segment.is_empty()) {
+        let next = match current {
+            serde_json::Value::Object(ref mut object) => object.remove(segment),
+            serde_json::Value::Array(ref mut array) => {
+                segment.parse::<usize>().ok().filter(|&index| index < array.len()).map(|index| array.swap_remove(index))
+            },
+            _ => None
+        };
+        match next {
+            Some(json) => current = json,
+            None => return Value::Null
+        }
+    }
+
+    json_to_value(&current)
+}
+
+/// Regular expressions compiled by pattern, so that a function called for
+/// every record compiles its pattern once rather than once per record.
+#[derive(Default)]
+struct RegexCache {
+    compiled: RwLock<HashMap<String, Regex>>
+}
+
+impl RegexCache {
+    fn compile(&self, pattern: &str) -> Result<Regex, String> {
+        if let Some(regex) = self.compiled.read().unwrap_or_else(|err| err.into_inner()).get(pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = Regex::new(pattern).map_err(|err| err.to_string())?;
+        let mut compiled = self.compiled.write().unwrap_or_else(|err| err.into_inner());
+        // Patterns computed per record could otherwise grow the cache without bound.
+        if compiled.len() >= REGEX_CACHE_SIZE {
+            compiled.clear();
+        }
+        compiled.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+}
+
+/// Wraps a function so that it returns NULL whenever any of its arguments is NULL.
+fn strict<F>(function: F) -> impl Fn(&[Value]) -> Result<Value, String> + Send + Sync
+    where F: Fn(&[Value]) -> Result<Value, String> + Send + Sync {
+    move |arguments: &[Value]| {
+        if arguments.iter().any(Value::is_null) {
+            Ok(Value::Null)
+        } else {
+            function(arguments)
+        }
+    }
+}
+
+fn register_builtins(registry: &mut FunctionRegistry) {
+    use self::DataType::*;
+
+    registry.register_scalar("lower", Signature::new(vec!(String), String), strict(|args| {
+        Ok(Value::Str(args[0].as_str()?.to_lowercase()))
+    }));
+    registry.register_scalar("upper", Signature::new(vec!(String), String), strict(|args| {
+        Ok(Value::Str(args[0].as_str()?.to_uppercase()))
+    }));
+    registry.register_scalar("trim", Signature::new(vec!(String), String), strict(|args| {
+        Ok(Value::Str(args[0].as_str()?.trim().to_string()))
+    }));
+    registry.register_scalar("length", Signature::new(vec!(String), Integer), strict(|args| {
+        Ok(Value::Integer(args[0].as_str()?.chars().count() as i64))
+    }));
+    registry.register_scalar("substr", Signature::new(vec!(String, Integer, Integer), String).with_optional_arguments(1), strict(|args| {
+        let input = args[0].as_str()?;
+        let start = (args[1].as_integer()?.max(1) - 1) as usize;
+        let characters = input.chars().skip(start);
+        let result = match args.get(2) {
+            Some(length) => characters.take(length.as_integer()?.max(0) as usize).collect(),
+            None => characters.collect()
+        };
+        Ok(Value::Str(result))
+    }));
+    registry.register_scalar("split_part", Signature::new(vec!(String, String, Integer), String), strict(|args| {
+        let input = args[0].as_str()?;
+        let delimiter = args[1].as_str()?;
+        let index = args[2].as_integer()?;
+        if index < 1 {
+            return Err(format!("split_part index must be positive, got {}", index));
+        }
+        Ok(Value::Str(input.split(delimiter.as_str()).nth(index as usize - 1).unwrap_or("").to_string()))
+    }));
+    let patterns = RegexCache::default();
+    registry.register_scalar("regexp_extract", Signature::new(vec!(String, String, Integer), String).with_optional_arguments(1), strict(move |args| {
+        let input = args[0].as_str()?;
+        let pattern = patterns.compile(&args[1].as_str()?)?;
+        let group = match args.get(2) {
+            Some(group) => group.as_integer()? as usize,
+            None => 0
+        };
+        Ok(pattern.captures(&input)
+            .and_then(|captures| captures.get(group))
+            .map(|matched| Value::Str(matched.as_str().to_string()))
+            .unwrap_or(Value::Null))
+    }));
+    let patterns = RegexCache::default();
+    registry.register_scalar("regexp_replace", Signature::new(vec!(String, String, String), String), strict(move |args| {
+        let input = args[0].as_str()?;
+        let pattern = patterns.compile(&args[1].as_str()?)?;
+        Ok(Value::Str(pattern.replace_all(&input, args[2].as_str()?.as_str()).into_owned()))
+    }));
+    registry.register_scalar("coalesce", Signature::new(vec!(Any), Any).variadic(), |args| {
+        Ok(args.iter().find(|value| !value.is_null()).cloned().unwrap_or(Value::Null))
+    });
+    registry.register_scalar("abs", Signature::new(vec!(Number), Number), strict(|args| {
+        match args[0] {
+            Value::Integer(i) => Ok(i.checked_abs().map(Value::Integer).unwrap_or(Value::Null)),
+            ref other => Ok(Value::Float(other.as_float()?.abs()))
+        }
+    }));
+    registry.register_scalar("round", Signature::new(vec!(Number, Integer), Number).with_optional_arguments(1), strict(|args| {
+        let digits = match args.get(1) {
+            Some(digits) => digits.as_integer()?,
+            None => 0
+        };
+        match args[0] {
+            Value::Integer(i) if digits >= 0 => Ok(Value::Integer(i)),
+            ref other => Ok(Value::Float(round_to(other.as_float()?, digits)))
+        }
+    }));
+    registry.register_scalar("date_trunc", Signature::new(vec!(String, Timestamp), Timestamp), strict(|args| {
+        Ok(Value::Timestamp(truncate_timestamp(&args[0].as_str()?, args[1].as_timestamp()?)?))
+    }));
+    registry.register_scalar("strftime", Signature::new(vec!(Timestamp, String), String), strict(|args| {
+        Ok(Value::Str(format_timestamp(args[0].as_timestamp()?, &args[1].as_str()?)?))
+    }));
+    registry.register_scalar("to_timestamp", Signature::new(vec!(Any, String), Timestamp).with_optional_arguments(1), strict(|args| {
+        match (&args[0], args.get(1)) {
+            (Value::Str(s), Some(format)) => Ok(Value::Timestamp(parse_timestamp(s, Some(&format.as_str()?))?)),
+            (value, _) => Ok(Value::Timestamp(value.as_timestamp()?))
+        }
+    }));
+    registry.register_scalar("json_extract", Signature::new(vec!(String, String), Any), strict(|args| {
+        Ok(json_extract(&args[0].as_str()?, &args[1].as_str()?))
+    }));
+}
+
+#[derive(Default)]
+struct Count {
+    count: i64
+}
+
+impl Accumulator for Count {
+    fn update(&mut self, arguments: &[Value]) -> Result<(), String> {
+        if !arguments[0].is_null() {
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Vec<Value> {
+        vec!(Value::Integer(self.count))
+    }
+
+    fn merge(&mut self, state: &[Value]) -> Result<(), String> {
+        self.count += state[0].as_integer()?;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<Value, String> {
+        Ok(Value::Integer(self.count))
+    }
+}
+
+#[derive(Default)]
+struct Sum {
+    sum: Option<Value>,
+    count: i64
+}
+
+impl Sum {
+    fn add(&mut self, value: &Value) -> Result<(), String> {
+        if value.is_null() {
+            return Ok(());
+        }
+        self.sum = Some(match (self.sum.take(), value) {
+            (None, &Value::Integer(i)) => Value::Integer(i),
+            // Sums beyond the range of integers carry on as floats rather than overflowing.
+            (Some(Value::Integer(sum)), &Value::Integer(i)) => sum.checked_add(i).map_or(Value::Float(sum as f64 + i as f64), Value::Integer),
+            (sum, value) => Value::Float(sum.map_or(Ok(0.0), |sum| sum.as_float())? + value.as_float()?)
+        });
+        Ok(())
+    }
+}
+
+impl Accumulator for Sum {
+    fn update(&mut self, arguments: &[Value]) -> Result<(), String> {
+        if !arguments[0].is_null() {
+            self.count += 1;
+        }
+        self.add(&arguments[0])
+    }
+
+    fn state(&self) -> Vec<Value> {
+        vec!(self.sum.clone().unwrap_or(Value::Null), Value::Integer(self.count))
+    }
+
+    fn merge(&mut self, state: &[Value]) -> Result<(), String> {
+        self.count += state[1].as_integer()?;
+        self.add(&state[0])
+    }
+
+    fn finish(&self) -> Result<Value, String> {
+        Ok(self.sum.clone().unwrap_or(Value::Null))
+    }
+}
+
+struct Average(Sum);
+
+impl Accumulator for Average {
+    fn update(&mut self, arguments: &[Value]) -> Result<(), String> {
+        self.0.update(arguments)
+    }
+
+    fn state(&self) -> Vec<Value> {
+        self.0.state()
+    }
+
+    fn merge(&mut self, state: &[Value]) -> Result<(), String> {
+        self.0.merge(state)
+    }
+
+    fn finish(&self) -> Result<Value, String> {
+        match self.0.sum {
+            Some(ref sum) => Ok(Value::Float(sum.as_float()? / self.0.count as f64)),
+            None => Ok(Value::Null)
+        }
+    }
+}
+
+/// Keeps the smallest (or, with `Ordering::Greater`, the largest) value seen.
+struct Extremum {
+    keep: Ordering,
+    value: Value
+}
+
+impl Accumulator for Extremum {
+    fn update(&mut self, arguments: &[Value]) -> Result<(), String> {
+        let candidate = &arguments[0];
+        if candidate.is_null() {
+            return Ok(());
+        }
+        if self.value.is_null() || candidate.partial_cmp(&self.value) == Some(self.keep) {
+            self.value = candidate.clone();
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Vec<Value> {
+        vec!(self.value.clone())
+    }
+
+    fn merge(&mut self, state: &[Value]) -> Result<(), String> {
+        self.update(state)
+    }
+
+    fn finish(&self) -> Result<Value, String> {
+        Ok(self.value.clone())
+    }
+}
+
+fn register_builtin_aggregates(registry: &mut FunctionRegistry) {
+    use self::DataType::*;
+
+    registry.register_aggregate("count", Signature::new(vec!(Any), Integer), || Box::new(Count::default()));
+    registry.register_aggregate("sum", Signature::new(vec!(Number), Number), || Box::new(Sum::default()));
+    registry.register_aggregate("avg", Signature::new(vec!(Number), Float), || Box::new(Average(Sum::default())));
+    registry.register_aggregate("min", Signature::new(vec!(Any), Any), || Box::new(Extremum { keep: Ordering::Less, value: Value::Null }));
+    registry.register_aggregate("max", Signature::new(vec!(Any), Any), || Box::new(Extremum { keep: Ordering::Greater, value: Value::Null }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, arguments: Vec<Value>) -> Value {
+        FunctionRegistry::new().call(name, &arguments).unwrap()
+    }
+
+    fn string(s: &str) -> Value {
+        Value::Str(s.into())
+    }
+
+    #[test]
+    fn it_evaluates_string_functions() {
+        assert_eq!(call("lower", vec!(string("ERROR"))), string("error"));
+        assert_eq!(call("UPPER", vec!(string("warn"))), string("WARN"));
+        assert_eq!(call("trim", vec!(string("  x "))), string("x"));
+        assert_eq!(call("length", vec!(string("héllo"))), Value::Integer(5));
+        assert_eq!(call("substr", vec!(string("connection"), Value::Integer(2), Value::Integer(3))), string("onn"));
+        assert_eq!(call("substr", vec!(string("connection"), Value::Integer(5))), string("ection"));
+        assert_eq!(call("split_part", vec!(string("a/b/c"), string("/"), Value::Integer(2))), string("b"));
+        assert_eq!(call("split_part", vec!(string("a/b/c"), string("/"), Value::Integer(7))), string(""));
+    }
+
+    #[test]
+    fn it_evaluates_regular_expression_functions() {
+        assert_eq!(call("regexp_extract", vec!(string("took 125ms"), string("(\\d+)ms"), Value::Integer(1))), string("125"));
+        assert_eq!(call("regexp_extract", vec!(string("no match"), string("\\d+"))), Value::Null);
+        assert_eq!(call("regexp_replace", vec!(string("user=42 id=7"), string("\\d+"), string("#"))), string("user=# id=#"));
+    }
+
+    #[test]
+    fn it_compiles_each_regular_expression_once() {
+        let patterns = RegexCache::default();
+        assert_eq!(patterns.compile("\\d+").unwrap().as_str(), "\\d+");
+        patterns.compile("\\d+").unwrap();
+        assert_eq!(patterns.compiled.read().unwrap().len(), 1);
+        assert!(patterns.compile("(unclosed").is_err());
+
+        for index in 0..REGEX_CACHE_SIZE + 1 {
+            patterns.compile(&format!("a{}", index)).unwrap();
+        }
+        assert!(patterns.compiled.read().unwrap().len() <= REGEX_CACHE_SIZE);
+    }
+
+    #[test]
+    fn it_evaluates_numeric_functions() {
+        assert_eq!(call("abs", vec!(Value::Integer(-3))), Value::Integer(3));
+        assert_eq!(call("abs", vec!(Value::Float(-2.5))), Value::Float(2.5));
+        assert_eq!(call("abs", vec!(Value::Integer(i64::MIN))), Value::Null);
+        assert_eq!(call("round", vec!(Value::Float(2.345), Value::Integer(2))), Value::Float(2.35));
+        assert_eq!(call("round", vec!(Value::Float(2.5))), Value::Float(3.0));
+        assert_eq!(call("round", vec!(Value::Float(1234.5), Value::Integer(-2))), Value::Float(1200.0));
+        assert_eq!(call("round", vec!(Value::Float(1.25), Value::Integer(400))), Value::Float(1.25));
+        assert_eq!(call("round", vec!(Value::Float(1.25), Value::Integer(4294967297))), Value::Float(1.25));
+        assert_eq!(call("round", vec!(Value::Float(1.25), Value::Integer(-400))), Value::Float(0.0));
+        assert_eq!(call("round", vec!(Value::Float(1e300), Value::Integer(20))), Value::Float(1e300));
+        assert_eq!(call("coalesce", vec!(Value::Null, Value::Integer(1), Value::Integer(2))), Value::Integer(1));
+    }
+
+    #[test]
+    fn it_evaluates_timestamp_functions() {
+        let timestamp = call("to_timestamp", vec!(string("2018-03-04T10:17:42Z")));
+        assert_eq!(call("strftime", vec!(timestamp.clone(), string("%Y-%m-%d %H:%M"))), string("2018-03-04 10:17"));
+        let truncated = call("date_trunc", vec!(string("hour"), timestamp));
+        assert_eq!(call("strftime", vec!(truncated, string("%H:%M:%S"))), string("10:00:00"));
+        let custom = call("to_timestamp", vec!(string("04/03/2018"), string("%d/%m/%Y")));
+        assert_eq!(call("strftime", vec!(custom, string("%F"))), string("2018-03-04"));
+        assert_eq!(call("to_timestamp", vec!(Value::Integer(0))).to_string(), "1970-01-01T00:00:00+00:00");
+        assert!(FunctionRegistry::new().call("strftime", &[Value::Integer(0), string("%Q")]).is_err());
+    }
+
+    #[test]
+    fn it_extracts_values_from_json() {
+        let document = string("{\"request\": {\"status\": 503, \"path\": \"/api\"}, \"tags\": [\"a\", \"b\"]}");
+        assert_eq!(call("json_extract", vec!(document.clone(), string("$.request.status"))), Value::Integer(503));
+        assert_eq!(call("json_extract", vec!(document.clone(), string("request.path"))), string("/api"));
+        assert_eq!(call("json_extract", vec!(document.clone(), string("$.tags.1"))), string("b"));
+        assert_eq!(call("json_extract", vec!(document, string("$.missing"))), Value::Null);
+    }
+
+    #[test]
+    fn it_propagates_null_arguments() {
+        assert_eq!(call("upper", vec!(Value::Null)), Value::Null);
+    }
+
+    #[test]
+    #[should_panic(expected = "3 optional arguments given for a signature of 2 arguments")]
+    fn it_rejects_more_optional_arguments_than_arguments() {
+        Signature::new(vec!(DataType::String, DataType::Integer), DataType::String).with_optional_arguments(3);
+    }
+
+    #[test]
+    fn it_checks_arity_of_signatures_with_too_many_optional_arguments() {
+        let mut signature = Signature::new(vec!(DataType::String), DataType::String);
+        signature.optional_arguments = 2;
+        assert_eq!(signature.check("f", &[]), Ok(DataType::String));
+        assert_eq!(signature.describe_arity(), "0 to 1");
+    }
+
+    #[test]
+    fn it_checks_arity_and_argument_types() {
+        let registry = FunctionRegistry::new();
+        let call = |name: &str, arguments: Vec<Expression>| Expression::FunctionCall { name: name.into(), arguments };
+
+        assert_eq!(registry.check_expression(&call("length", vec!(Expression::Field("message".into())))), Ok(DataType::Integer));
+        assert!(registry.check_expression(&call("length", vec!())).is_err());
+        assert!(registry.check_expression(&call("upper", vec!(Expression::Number(1)))).is_err());
+        assert!(registry.check_expression(&call("substr", vec!(Expression::Str("a".into()), Expression::Str("b".into())))).is_err());
+        assert!(registry.check_expression(&call("coalesce", vec!(Expression::Number(1), Expression::Str("a".into()), Expression::Float(1.0)))).is_ok());
+        assert!(registry.check_expression(&call("nope", vec!())).is_err());
+    }
+
+    #[test]
+    fn it_validates_literal_patterns_and_units_before_evaluation() {
+        let registry = FunctionRegistry::new();
+        let regexp = Expression::FunctionCall { name: "regexp_extract".into(), arguments: vec!(Expression::Field("message".into()), Expression::Str("(unclosed".into())) };
+        let date_trunc = Expression::FunctionCall { name: "date_trunc".into(), arguments: vec!(Expression::Str("fortnight".into()), Expression::Field("date".into())) };
+        let strftime = |format: &str| Expression::FunctionCall { name: "strftime".into(), arguments: vec!(Expression::Field("date".into()), Expression::Str(format.into())) };
+
+        assert!(registry.check_expression(&regexp).is_err());
+        assert!(registry.check_expression(&date_trunc).is_err());
+        assert!(registry.check_expression(&strftime("%Q")).is_err());
+        assert!(registry.check_expression(&strftime("%Y-%m-%d %H:%M")).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_arithmetic_on_strings() {
+        let registry = FunctionRegistry::new();
+        let expression = Expression::BinaryOperation {
+            left: Box::new(Expression::Field("latency".into())),
+            operator: ArithmeticOperator::Divide,
+            right: Box::new(Expression::Str("1000".into()))
+        };
+        assert!(registry.check_expression(&expression).is_err());
+    }
+
+    fn aggregate(name: &str, rows: Vec<Value>) -> Value {
+        let registry = FunctionRegistry::new();
+        let mut first_half = registry.create_accumulator(name).unwrap();
+        let mut second_half = registry.create_accumulator(name).unwrap();
+        let middle = rows.len() / 2;
+        for row in &rows[..middle] {
+            first_half.update(std::slice::from_ref(row)).unwrap();
+        }
+        for row in &rows[middle..] {
+            second_half.update(std::slice::from_ref(row)).unwrap();
+        }
+        first_half.merge(&second_half.state()).unwrap();
+        first_half.finish().unwrap()
+    }
+
+    #[test]
+    fn it_evaluates_aggregates_across_merged_partial_states() {
+        let rows = vec!(Value::Integer(4), Value::Null, Value::Integer(1), Value::Float(2.5), Value::Integer(7));
+        assert_eq!(aggregate("count", rows.clone()), Value::Integer(4));
+        assert_eq!(aggregate("sum", rows.clone()), Value::Float(14.5));
+        assert_eq!(aggregate("avg", rows.clone()), Value::Float(3.625));
+        assert_eq!(aggregate("min", rows.clone()), Value::Integer(1));
+        assert_eq!(aggregate("max", rows), Value::Integer(7));
+        assert_eq!(aggregate("sum", vec!(Value::Integer(2), Value::Integer(3))), Value::Integer(5));
+        assert_eq!(aggregate("sum", vec!(Value::Integer(i64::MAX), Value::Integer(5))), Value::Float(i64::MAX as f64 + 5.0));
+        assert_eq!(aggregate("sum", vec!(Value::Integer(i64::MAX), Value::Integer(1), Value::Integer(1))), Value::Float(i64::MAX as f64 + 2.0));
+    }
+
+    #[test]
+    fn it_rejects_nested_aggregates_and_wildcards_in_scalar_functions() {
+        let registry = FunctionRegistry::new();
+        let call = |name: &str, arguments: Vec<Expression>| Expression::FunctionCall { name: name.into(), arguments };
+
+        assert!(registry.check_expression(&call("count", vec!(Expression::Wildcard))).is_ok());
+        assert!(registry.check_expression(&call("upper", vec!(Expression::Wildcard))).is_err());
+        assert!(registry.check_expression(&call("max", vec!(call("count", vec!(Expression::Wildcard))))).is_err());
+    }
+
+    #[test]
+    fn it_infers_the_result_type_of_case_expressions() {
+        let registry = FunctionRegistry::new();
+        let condition = Expression::Comparison {
+            left: Box::new(Expression::Field("status".into())),
+            mode: WhereComparator::GreaterThanOrEquals,
+            right: Box::new(Expression::Number(500))
+        };
+        let case = |result: Expression, else_result: Expression| Expression::Case { branches: vec!((condition.clone(), result)), else_result: Some(Box::new(else_result)) };
+
+        assert_eq!(registry.check_expression(&case(Expression::Str("server".into()), Expression::Str("ok".into()))), Ok(DataType::String));
+        assert_eq!(registry.check_expression(&case(Expression::Number(1), Expression::Float(0.5))), Ok(DataType::Float));
+        assert!(registry.check_expression(&case(Expression::Number(1), Expression::Str("ok".into()))).is_err());
+    }
+
+    #[test]
+    fn it_rejects_comparisons_between_incompatible_types() {
+        let registry = FunctionRegistry::new();
+        let length = Expression::FunctionCall { name: "length".into(), arguments: vec!(Expression::Field("message".into())) };
+
+        assert!(registry.check_comparison(&length, &WhereComparator::GreaterThan, &Expression::Number(10)).is_ok());
+        assert!(registry.check_comparison(&length, &WhereComparator::StrictEquals, &Expression::Str("10".into())).is_err());
+        assert!(registry.check_comparison(&length, &WhereComparator::Like, &Expression::Str("1%".into())).is_err());
+    }
+}
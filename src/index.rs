@@ -0,0 +1,475 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::iter::Flatten;
+use std::path::{Path, PathBuf};
+use std::vec;
+
+use encoding::{self, Decoder, Fingerprint};
+use functions::Value;
+use mapped::{MappedFile, MappedRecords};
+use optimizer;
+use parser::{Expression, WhereComparator};
+use record::{Fields, TIMESTAMP_FIELDS};
+
+/// Extension appended to the name of a log for its index, `app.log.lqi`.
+pub const EXTENSION: &str = "lqi";
+
+const MAGIC: &[u8; 4] = b"LQIX";
+const VERSION: u32 = 1;
+
+/// Blocks end at the first record boundary after this many bytes.
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+/// The path of the index of the log at `path`.
+pub fn path_for(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Whether `path` is an index rather than a log.
+pub fn is_index(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == EXTENSION)
+}
+
+/// Builds the index of the uncompressed log at `path` and writes it next to it.
+pub fn build(path: &Path) -> Result<Index, String> {
+    let index = Index::build(path, BLOCK_SIZE)?;
+    index.write(&path_for(path))?;
+    Ok(index)
+}
+
+/// A set of field values that answers "certainly not contained" or "maybe
+/// contained", taking about ten bits per value for 1% false positives.
+#[derive(Debug)]
+#[derive(PartialEq)]
+struct Bloom {
+    words: Vec<u64>
+}
+
+impl Bloom {
+    fn with_keys(keys: &HashSet<u64>) -> Bloom {
+        let bits = (keys.len() * BLOOM_BITS_PER_KEY).max(64);
+        let mut bloom = Bloom { words: vec!(0; bits.div_ceil(64)) };
+        for &key in keys {
+            for bit in bloom.bits(key) {
+                bloom.words[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    fn may_contain(&self, key: u64) -> bool {
+        self.bits(key).all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits of `key`, derived from two halves of its hash.
+    fn bits(&self, key: u64) -> impl Iterator<Item = usize> {
+        let bits = self.words.len() as u64 * 64;
+        let (first, second) = (key & 0xffff_ffff, (key >> 32) | 1);
+        (0..BLOOM_HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits) as usize)
+    }
+}
+
+/// The lowest and highest string value of a timestamp field within a block.
+#[derive(Debug)]
+#[derive(PartialEq)]
+struct Range {
+    field: String,
+    min: String,
+    max: String
+}
+
+/// Consecutive records of a log, from the byte offset `start` up to `end`.
+#[derive(Debug)]
+#[derive(PartialEq)]
+struct Block {
+    start: usize,
+    end: usize,
+    /// Number of lines before `start`.
+    line: usize,
+    records: usize,
+    ranges: Vec<Range>,
+    /// The string values of all fields, as `field\0value`.
+    values: Bloom
+}
+
+impl Block {
+    /// Whether a record of the block can match `field mode value`. Only
+    /// comparisons of a field with a string are decided, and only strings
+    /// that are never converted to numbers or booleans, so no values other
+    /// than strings can match them.
+    fn may_match(&self, field: &Expression, mode: &WhereComparator, value: &Expression) -> bool {
+        let (name, mode, literal) = match (field, value) {
+            (Expression::Field(name), Expression::Str(literal)) => (name, mode.clone(), literal),
+            (Expression::Str(literal), Expression::Field(name)) => (name, flip(mode), literal),
+            _ => return true
+        };
+        if !optimizer::is_stored_field(name) || !compares_as_string(literal) {
+            return true;
+        }
+
+        if mode == WhereComparator::StrictEquals && !self.values.may_contain(value_key(name, literal)) {
+            return false;
+        }
+        if !TIMESTAMP_FIELDS.contains(&name.as_str()) {
+            return true;
+        }
+
+        let literal = literal.as_str();
+        match (self.ranges.iter().find(|range| range.field == *name), mode) {
+            (Some(range), WhereComparator::StrictEquals) => range.min.as_str() <= literal && literal <= range.max.as_str(),
+            (Some(range), WhereComparator::LessThan) => range.min.as_str() < literal,
+            (Some(range), WhereComparator::LessThanOrEquals) => range.min.as_str() <= literal,
+            (Some(range), WhereComparator::GreaterThan) => range.max.as_str() > literal,
+            (Some(range), WhereComparator::GreaterThanOrEquals) => range.max.as_str() >= literal,
+            (None, WhereComparator::StrictEquals) | (None, WhereComparator::LessThan) | (None, WhereComparator::LessThanOrEquals) |
+            (None, WhereComparator::GreaterThan) | (None, WhereComparator::GreaterThanOrEquals) => false,
+            _ => true
+        }
+    }
+}
+
+/// The records of the blocks of a log that may match a query.
+pub type IndexedRecords<'a> = Flatten<vec::IntoIter<MappedRecords<'a>>>;
+
+/// An index of a log stored next to it, built with `log-ql --index`.
+///
+/// The log is split into blocks of about a megabyte at record boundaries.
+/// For each block the index keeps where it starts, the range of the values
+/// of its timestamp fields and a bloom filter of all its string values, so
+/// a query looking for `request_id = 'req-42'` or `ts >= '2018-03-04'` only
+/// scans the blocks that may contain matching records.
+///
+/// The index records the size, modification time and a hash of the start and
+/// end of the log, and is ignored once the log no longer matches them.
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Index {
+    fingerprint: Fingerprint,
+    blocks: Vec<Block>
+}
+
+impl Index {
+    fn build(path: &Path, block_size: usize) -> Result<Index, String> {
+        let fingerprint = Fingerprint::of(path)?;
+        let file = MappedFile::open(path)?;
+        if file.len() as u64 != fingerprint.length {
+            return Err(format!("{:?} changed while indexing it", path));
+        }
+
+        let mut blocks = vec!();
+        let mut records = file.records();
+        let (mut start, mut line) = (0, 0);
+        let mut block = BlockBuilder::default();
+        while let Some(record) = records.next() {
+            block.add(&record?);
+            if records.position() - start >= block_size {
+                blocks.push(block.finish(start, records.position(), line));
+                block = BlockBuilder::default();
+                start = records.position();
+                line = records.line();
+            }
+        }
+        if start < file.len() {
+            blocks.push(block.finish(start, file.len(), line));
+        }
+
+        Ok(Index { fingerprint, blocks })
+    }
+
+    /// Reads the index of the log at `path`. Returns `None` if there is none
+    /// or if the log changed since it was built.
+    pub fn load(path: &Path) -> Result<Option<Index>, String> {
+        let index_path = path_for(path);
+        let data = match fs::read(&index_path) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Cannot read {:?}: {}", index_path, err))
+        };
+
+        let index = Index::decode(&mut Decoder::new(&data))
+            .map_err(|err| format!("Invalid index {:?}, rebuild it with `log-ql --index`: {}", index_path, err))?;
+        if index.fingerprint != Fingerprint::of(path)? {
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The number of blocks that may match `condition`.
+    pub fn matching_blocks(&self, condition: Option<(&Expression, &WhereComparator, &Expression)>) -> usize {
+        self.candidates(condition).count()
+    }
+
+    fn candidates<'s>(&'s self, condition: Option<(&'s Expression, &'s WhereComparator, &'s Expression)>) -> impl Iterator<Item = &'s Block> + 's {
+        self.blocks.iter().filter(move |block| condition.is_none_or(|(field, mode, value)| block.may_match(field, mode, value)))
+    }
+
+    /// Splits the records of the blocks of `file` that may match `condition`
+    /// into at most `count` parts of consecutive blocks to be scanned
+    /// independently. There is always at least one, possibly empty, part.
+    pub fn chunks<'a>(&self, file: &'a MappedFile, condition: Option<(&Expression, &WhereComparator, &Expression)>, count: usize) -> Vec<IndexedRecords<'a>> {
+        let blocks: Vec<&Block> = self.candidates(condition).collect();
+
+        let total: usize = blocks.iter().map(|block| block.end - block.start).sum();
+        let part_size = total.div_ceil(count.max(1)).max(1);
+        let mut parts: Vec<Vec<(usize, usize, usize)>> = vec!(vec!());
+        let mut size = 0;
+        for block in blocks {
+            if size >= part_size {
+                parts.push(vec!());
+                size = 0;
+            }
+            let part = parts.last_mut().unwrap();
+            match part.last_mut() {
+                Some((_, end, _)) if *end == block.start => *end = block.end,
+                _ => part.push((block.start, block.end, block.line))
+            }
+            size += block.end - block.start;
+        }
+
+        parts.into_iter()
+            .map(|part| part.into_iter().map(|(start, end, line)| file.records_between(start, end, line)).collect::<Vec<_>>().into_iter().flatten())
+            .collect()
+    }
+
+    /// Reads an index written by `Index::write`.
+    fn decode(decoder: &mut Decoder) -> Result<Index, String> {
+        decoder.header(MAGIC, VERSION)?;
+        let fingerprint = Fingerprint::read(decoder)?;
+
+        let mut blocks = vec!();
+        for _ in 0..decoder.u64()? {
+            let (start, end, line, records) = (decoder.usize()?, decoder.usize()?, decoder.usize()?, decoder.usize()?);
+            let mut ranges = vec!();
+            for _ in 0..decoder.u64()? {
+                ranges.push(Range { field: decoder.string()?, min: decoder.string()?, max: decoder.string()? });
+            }
+            let mut words = vec!();
+            for _ in 0..decoder.u64()? {
+                words.push(decoder.u64()?);
+            }
+            if words.is_empty() || start > end {
+                return Err("corrupt block".into());
+            }
+            blocks.push(Block { start, end, line, records, ranges, values: Bloom { words } });
+        }
+
+        if blocks.last().is_some_and(|block| block.end as u64 != fingerprint.length) {
+            return Err("blocks do not cover the log".into());
+        }
+        Ok(Index { fingerprint, blocks })
+    }
+
+    fn write(&self, path: &Path) -> Result<(), String> {
+        let mut data = vec!();
+        encoding::put_header(&mut data, MAGIC, VERSION);
+        self.fingerprint.write(&mut data);
+
+        encoding::put_u64(&mut data, self.blocks.len() as u64);
+        for block in &self.blocks {
+            for number in [block.start, block.end, block.line, block.records, block.ranges.len()] {
+                encoding::put_u64(&mut data, number as u64);
+            }
+            for range in &block.ranges {
+                for text in [&range.field, &range.min, &range.max] {
+                    encoding::put_bytes(&mut data, text.as_bytes());
+                }
+            }
+            encoding::put_u64(&mut data, block.values.words.len() as u64);
+            for &word in &block.values.words {
+                encoding::put_u64(&mut data, word);
+            }
+        }
+
+        fs::write(path, data).map_err(|err| format!("Cannot write {:?}: {}", path, err))
+    }
+}
+
+/// Collects what the index keeps about a block while its records are read.
+#[derive(Default)]
+struct BlockBuilder {
+    records: usize,
+    ranges: Vec<Range>,
+    values: HashSet<u64>
+}
+
+impl BlockBuilder {
+    fn add(&mut self, record: &dyn Fields) {
+        self.records += 1;
+        for (field, value) in record.pairs() {
+            let value = match value {
+                Value::Str(value) => value,
+                _ => continue
+            };
+            self.values.insert(value_key(&field, &value));
+
+            if !TIMESTAMP_FIELDS.contains(&field.as_str()) {
+                continue;
+            }
+            match self.ranges.iter_mut().find(|range| range.field == field) {
+                Some(range) if value < range.min => range.min = value,
+                Some(range) if value > range.max => range.max = value,
+                Some(_) => {},
+                None => self.ranges.push(Range { field, min: value.clone(), max: value })
+            }
+        }
+    }
+
+    fn finish(self, start: usize, end: usize, line: usize) -> Block {
+        Block {
+            start,
+            end,
+            line,
+            records: self.records,
+            ranges: self.ranges,
+            values: Bloom::with_keys(&self.values)
+        }
+    }
+}
+
+fn value_key(field: &str, value: &str) -> u64 {
+    encoding::fnv1a(&[field.as_bytes(), b"\0", value.as_bytes()].concat())
+}
+
+/// Whether only strings compare with `literal`, which holds unless it reads
+/// as a number or a boolean, the types strings are converted to in comparisons.
+fn compares_as_string(literal: &str) -> bool {
+    literal.trim().parse::<f64>().is_err() && !literal.eq_ignore_ascii_case("true") && !literal.eq_ignore_ascii_case("false")
+}
+
+/// The comparator for the operands swapped, `'a' < b` being `b > 'a'`.
+fn flip(mode: &WhereComparator) -> WhereComparator {
+    match *mode {
+        WhereComparator::LessThan => WhereComparator::GreaterThan,
+        WhereComparator::LessThanOrEquals => WhereComparator::GreaterThanOrEquals,
+        WhereComparator::GreaterThan => WhereComparator::LessThan,
+        WhereComparator::GreaterThanOrEquals => WhereComparator::LessThanOrEquals,
+        ref mode => mode.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    use record::Fields;
+    use source;
+
+    fn write_log(directory: &TempDir, lines: usize) -> PathBuf {
+        let path = directory.path().join("app.log");
+        let mut file = File::create(&path).unwrap();
+        for n in 0..lines {
+            writeln!(file, "ts=2018-03-04T10:{:02}:{:02}Z level={} request_id=req-{} n={}", n / 60, n % 60, if n % 10 == 0 { "error" } else { "info" }, n, n).unwrap();
+            if n % 7 == 0 {
+                writeln!(file, "  at main.rs:{}", n).unwrap();
+            }
+        }
+        path
+    }
+
+    fn condition(query: &str) -> (Expression, WhereComparator, Expression) {
+        use parser::{GrammarItem, Parser};
+        let ast = Parser::new(format!("SELECT n FROM 'app.log' WHERE {}", query)).parse().unwrap();
+        match ast.right.unwrap().left.unwrap().entry {
+            GrammarItem::Condition { field, mode, value } => (field, mode, value),
+            other => panic!("Not a condition: {:?}", other)
+        }
+    }
+
+    fn scanned(index: &Index, file: &MappedFile, query: &str) -> Vec<Value> {
+        let (field, mode, value) = condition(query);
+        index.chunks(file, Some((&field, &mode, &value)), 3).into_iter()
+            .flat_map(|chunk| chunk.map(|record| record.unwrap().get("n")).collect::<Vec<Value>>())
+            .collect()
+    }
+
+    #[test]
+    fn it_writes_and_loads_indexes_until_the_log_changes() {
+        let directory = TempDir::new().unwrap();
+        let path = write_log(&directory, 300);
+        assert_eq!(Index::load(&path).unwrap(), None);
+
+        let index = build(&path).unwrap();
+        assert!(path_for(&path).ends_with("app.log.lqi"));
+        assert_eq!(Index::load(&path).unwrap(), Some(index));
+
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"n=300\n").unwrap();
+        assert_eq!(Index::load(&path).unwrap(), None);
+
+        fs::write(path_for(&path), b"LQIX\x01").unwrap();
+        assert!(Index::load(&path).unwrap_err().contains("Invalid index"));
+    }
+
+    #[test]
+    fn it_covers_all_records_with_their_line_numbers() {
+        let directory = TempDir::new().unwrap();
+        let path = write_log(&directory, 300);
+        let index = Index::build(&path, 512).unwrap();
+        let file = MappedFile::open(&path).unwrap();
+        assert!(index.block_count() > 10);
+        assert_eq!(index.blocks.iter().map(|block| block.records).sum::<usize>(), 300);
+
+        let all: Vec<(Value, Value)> = file.records().map(|record| record.unwrap()).map(|record| (record.get("n"), record.get("_line"))).collect();
+        for count in 1..5 {
+            let chunks = index.chunks(&file, None, count);
+            assert!(chunks.len() <= count);
+            let indexed: Vec<(Value, Value)> = chunks.into_iter()
+                .flat_map(|chunk| chunk.map(|record| record.unwrap()).map(|record| (record.get("n"), record.get("_line"))).collect::<Vec<_>>())
+                .collect();
+            assert_eq!(indexed, all);
+        }
+    }
+
+    #[test]
+    fn it_skips_blocks_that_cannot_match() {
+        let directory = TempDir::new().unwrap();
+        let path = write_log(&directory, 300);
+        let index = Index::build(&path, 512).unwrap();
+        let file = MappedFile::open(&path).unwrap();
+        let total = file.records().count();
+
+        let found = scanned(&index, &file, "request_id = 'req-123'");
+        assert!(found.contains(&Value::Integer(123)) && found.len() < total / 4);
+        assert!(scanned(&index, &file, "'req-123' = request_id").contains(&Value::Integer(123)));
+        assert!(scanned(&index, &file, "request_id = 'req-none'").len() < total / 4);
+
+        let after = scanned(&index, &file, "ts >= '2018-03-04T10:04:00Z'");
+        assert!(after.contains(&Value::Integer(240)) && !after.contains(&Value::Integer(100)));
+        let before = scanned(&index, &file, "'2018-03-04T10:00:30Z' > ts");
+        assert!(before.contains(&Value::Integer(29)) && !before.contains(&Value::Integer(100)));
+        assert!(scanned(&index, &file, "ts < '2000-01-01'").is_empty());
+        assert!(scanned(&index, &file, "time < '2100-01-01'").is_empty());
+
+        assert_eq!(scanned(&index, &file, "n = '123'").len(), total);
+        assert_eq!(scanned(&index, &file, "level != 'error'").len(), total);
+        assert_eq!(scanned(&index, &file, "request_id LIKE 'req-1%'").len(), total);
+        assert_eq!(scanned(&index, &file, "_file = 'other.log'").len(), total);
+    }
+
+    #[test]
+    fn it_indexes_empty_logs_and_skips_indexes_of_directories() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("app.log");
+        File::create(&path).unwrap();
+        let index = build(&path).unwrap();
+        assert_eq!(index.block_count(), 0);
+
+        let file = MappedFile::open(&path).unwrap();
+        assert_eq!(index.chunks(&file, None, 4).len(), 1);
+        assert_eq!(source::resolve(&[directory.path().to_string_lossy().into_owned()]).unwrap(), vec!(path));
+    }
+}
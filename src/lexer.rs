@@ -1,37 +1,165 @@
+//! Splits the text of a query into tokens, each with the span of text it
+//! was read from, for the parser and for tools mapping tokens back to the query.
+
+use std::fmt;
 use std::iter::Peekable;
 
+use error::{ParseError, Span};
+
+/// The words with a meaning in queries, recognized in any case. They are
+/// names only when quoted.
+pub const KEYWORDS: [&str; 21] = [
+    "EXPLAIN", "ANALYZE", "SELECT", "FROM", "WHERE", "GROUP", "ORDER", "BY", "ASC", "DESC", "LIMIT",
+    "LIKE", "AS", "CASE", "WHEN", "THEN", "ELSE", "END", "FOLLOW", "TAIL", "STDIN"
+];
+
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[derive(Clone)]
 pub enum LexItem {
+    /// A keyword of `KEYWORDS`, in upper case whatever its case in the query.
+    Keyword(&'static str),
     Identifier(String),
+    /// A name in double quotes, never taken for a keyword, e.g. for fields
+    /// whose names contain spaces or dashes.
+    QuotedIdentifier(String),
     Str(String),
     Equals,
-    Number(usize),
+    NotEquals,
+    LessThan,
+    LessThanOrEquals,
+    GreaterThan,
+    GreaterThanOrEquals,
+    Number(i64),
+    Float(f64),
     Comma,
-    EOF
+    /// Ends a statement, see `Parser::parse_script`.
+    Semicolon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LeftParen,
+    RightParen,
+    Eof
+}
+
+impl LexItem {
+    /// The name `LexItem::Eof` had before it followed Rust's naming conventions.
+    #[deprecated(note = "renamed to `LexItem::Eof`")]
+    pub const EOF: LexItem = LexItem::Eof;
+}
+
+/// A token and where it is in the query.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Token {
+    pub kind: LexItem,
+    pub span: Span
+}
+
+/// Renders a token as written in a query, to name it in error messages.
+impl fmt::Display for LexItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexItem::Keyword(keyword) => write!(f, "`{}`", keyword),
+            LexItem::Identifier(ref identifier) => write!(f, "`{}`", identifier),
+            LexItem::QuotedIdentifier(ref identifier) => write!(f, "`{}`", quote(identifier, '"')),
+            LexItem::Str(ref s) => write!(f, "{}", quote(s, '\'')),
+            LexItem::Equals => write!(f, "`=`"),
+            LexItem::NotEquals => write!(f, "`!=`"),
+            LexItem::LessThan => write!(f, "`<`"),
+            LexItem::LessThanOrEquals => write!(f, "`<=`"),
+            LexItem::GreaterThan => write!(f, "`>`"),
+            LexItem::GreaterThanOrEquals => write!(f, "`>=`"),
+            LexItem::Number(number) => write!(f, "`{}`", number),
+            LexItem::Float(number) => write!(f, "`{:?}`", number),
+            LexItem::Comma => write!(f, "`,`"),
+            LexItem::Semicolon => write!(f, "`;`"),
+            LexItem::Plus => write!(f, "`+`"),
+            LexItem::Minus => write!(f, "`-`"),
+            LexItem::Star => write!(f, "`*`"),
+            LexItem::Slash => write!(f, "`/`"),
+            LexItem::LeftParen => write!(f, "`(`"),
+            LexItem::RightParen => write!(f, "`)`"),
+            LexItem::Eof => write!(f, "the end of the query")
+        }
+    }
+}
+
+/// Puts `text` between `quote`s, doubling the quotes it contains, so it is
+/// read back as the same string or identifier.
+pub fn quote(text: &str, quote: char) -> String {
+    let doubled: String = [quote, quote].iter().collect();
+    format!("{}{}{}", quote, text.replace(quote, &doubled), quote)
+}
+
+/// Whether `text` is read back as the identifier `text`, rather than having
+/// to be quoted.
+pub fn is_identifier(text: &str) -> bool {
+    match tokenize(text) {
+        Ok(ref tokens) => matches!(tokens.as_slice(), [Token { kind: LexItem::Identifier(ref identifier), .. }, _] if identifier == text),
+        Err(_) => false
+    }
+}
+
+/// The keyword `word` is, written in any case.
+pub fn keyword(word: &str) -> Option<&'static str> {
+    KEYWORDS.iter().cloned().find(|keyword| keyword.eq_ignore_ascii_case(word))
 }
 
-fn consume_string<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> String {
+fn is_identifier_start(ch: char) -> bool {
+    ch.is_alphabetic() || ch == '_'
+}
+
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// Reads the text quoted with `quote` whose opening quote was consumed, up
+/// to and including the closing quote. Quotes are embedded by doubling
+/// them or escaping them with a backslash. Returns `None` if the input ends
+/// before the closing quote.
+fn consume_string<T: Iterator<Item = (usize, char)>>(quote: char, iter: &mut Peekable<T>) -> Option<String> {
     let mut resulting_str = String::from("");
 
-    while let Some(ch) = iter.peek().map(|c| *c) {
-        if ch == '\'' {
-            break;
-        } else {
-            resulting_str.push(ch.clone());
+    while let Some((_, ch)) = iter.next() {
+        if ch == quote {
+            if iter.peek().map(|&(_, ch)| ch) != Some(quote) {
+                return Some(resulting_str);
+            }
+            resulting_str.push(quote);
             iter.next();
+        } else if ch == '\\' {
+            match iter.next()?.1 {
+                'n' => resulting_str.push('\n'),
+                'r' => resulting_str.push('\r'),
+                't' => resulting_str.push('\t'),
+                escaped @ ('\\' | '\'' | '"') => resulting_str.push(escaped),
+                // Kept as is, so regular expressions like '\d+' need no doubled backslashes.
+                other => {
+                    resulting_str.push('\\');
+                    resulting_str.push(other);
+                }
+            }
+        } else {
+            resulting_str.push(ch);
         }
     }
 
-    resulting_str
+    None
 }
 
-fn consume_identifier<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> String {
+/// Reads a name made of letters, digits and underscores, whose parts can be
+/// separated by dots, like `kubernetes.pod_name`.
+fn consume_identifier<T: Iterator<Item = (usize, char)> + Clone>(iter: &mut Peekable<T>) -> String {
     let mut resulting_str = String::from("");
 
-    while let Some(ch) = iter.peek().map(|c| *c) {
-        if ch.is_alphabetic() {
-            resulting_str.push(ch.clone());
+    while let Some(&(_, ch)) = iter.peek() {
+        let is_separator = ch == '.' && iter.clone().nth(1).is_some_and(|(_, next)| is_identifier_char(next));
+        if is_identifier_char(ch) || is_separator {
+            resulting_str.push(ch);
             iter.next();
         } else {
             break;
@@ -41,54 +169,307 @@ fn consume_identifier<T: Iterator<Item = char>>(iter: &mut Peekable<T>) -> Strin
     resulting_str
 }
 
-fn consume_number<T: Iterator<Item = char>>(c: char, iter: &mut Peekable<T>) -> usize {
-    let mut number = c.to_string().parse::<i64>().expect("Expected digit");
-    while let Some(Ok(digit)) = iter.peek().map(|c| c.to_string().parse::<i64>()) {
-        number = number * 10 + digit;
+/// The units a number can be suffixed with, and the fraction turning it into
+/// bytes for sizes or into milliseconds for durations.
+const UNITS: [(&str, i64, i64); 17] = [
+    ("B", 1, 1), ("KB", 1_000, 1), ("MB", 1_000_000, 1), ("GB", 1_000_000_000, 1), ("TB", 1_000_000_000_000, 1),
+    ("KiB", 1 << 10, 1), ("MiB", 1 << 20, 1), ("GiB", 1 << 30, 1), ("TiB", 1 << 40, 1),
+    ("ns", 1, 1_000_000), ("us", 1, 1_000), ("µs", 1, 1_000), ("ms", 1, 1),
+    ("s", 1_000, 1), ("m", 60_000, 1), ("h", 3_600_000, 1), ("d", 86_400_000, 1)
+];
+
+enum NumberError {
+    OutOfRange,
+    Malformed,
+    UnknownUnit(String)
+}
+
+fn consume_while<T, P>(iter: &mut Peekable<T>, digits: &mut String, predicate: P) where T: Iterator<Item = (usize, char)>, P: Fn(char) -> bool {
+    while let Some(&(_, ch)) = iter.peek() {
+        if predicate(ch) {
+            digits.push(ch);
+            iter.next();
+        } else {
+            break;
+        }
+    }
+}
+
+/// Whether the `e` about to be read starts the exponent of a number, like
+/// in `1e3` or `2.5E-3`, rather than a unit.
+fn at_exponent<T: Iterator<Item = (usize, char)> + Clone>(iter: &Peekable<T>) -> bool {
+    let mut ahead = iter.clone().map(|(_, ch)| ch).skip(1);
+    match ahead.next() {
+        Some('+') | Some('-') => ahead.next().is_some_and(|ch| ch.is_ascii_digit()),
+        Some(ch) => ch.is_ascii_digit(),
+        None => false
+    }
+}
+
+/// Reads an integer, possibly negative and hexadecimal, or a float, possibly
+/// in scientific notation, followed by an optional unit of `UNITS`.
+fn consume_number<T: Iterator<Item = (usize, char)> + Clone>(iter: &mut Peekable<T>) -> Result<LexItem, NumberError> {
+    let mut number = String::from("");
+    if iter.peek().map(|&(_, ch)| ch) == Some('-') {
+        number.push('-');
+        iter.next();
+    }
+    consume_while(iter, &mut number, |ch| ch.is_ascii_digit());
+
+    if number.trim_start_matches('-') == "0" && matches!(iter.peek(), Some(&(_, 'x')) | Some(&(_, 'X'))) {
+        iter.next();
+        let mut digits = String::from("");
+        consume_while(iter, &mut digits, |ch| ch.is_alphanumeric());
+        if digits.is_empty() || !digits.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return Err(NumberError::Malformed);
+        }
+        let sign = if number.starts_with('-') { "-" } else { "" };
+        return i64::from_str_radix(&format!("{}{}", sign, digits), 16).map(LexItem::Number).map_err(|_| NumberError::OutOfRange);
+    }
+
+    let mut is_float = false;
+    if iter.peek().map(|&(_, ch)| ch) == Some('.') {
+        number.push('.');
         iter.next();
+        consume_while(iter, &mut number, |ch| ch.is_ascii_digit());
+        is_float = true;
+    }
+    if matches!(iter.peek(), Some(&(_, 'e')) | Some(&(_, 'E'))) && at_exponent(iter) {
+        number.push('e');
+        iter.next();
+        consume_while(iter, &mut number, |ch| ch == '+' || ch == '-');
+        consume_while(iter, &mut number, |ch| ch.is_ascii_digit());
+        is_float = true;
+    }
+
+    let mut unit = String::from("");
+    consume_while(iter, &mut unit, |ch| ch.is_alphanumeric() || ch == '_');
+    let unit = if unit.is_empty() {
+        None
+    } else {
+        Some(UNITS.iter().find(|&&(name, _, _)| name == unit).ok_or(NumberError::UnknownUnit(unit))?)
+    };
+
+    if is_float {
+        let mut value = number.parse::<f64>().map_err(|_| NumberError::Malformed)?;
+        if let Some(&(_, multiplier, divisor)) = unit {
+            value = value * multiplier as f64 / divisor as f64;
+            // Sizes and durations like `1.5KB` are whole numbers most of the time.
+            if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+                return Ok(LexItem::Number(value as i64));
+            }
+        }
+        if value.is_finite() { Ok(LexItem::Float(value)) } else { Err(NumberError::OutOfRange) }
+    } else {
+        let value = number.parse::<i64>().map_err(|_| NumberError::OutOfRange)?;
+        match unit {
+            Some(&(_, multiplier, divisor)) => {
+                let value = value.checked_mul(multiplier).ok_or(NumberError::OutOfRange)?;
+                if value % divisor == 0 {
+                    Ok(LexItem::Number(value / divisor))
+                } else {
+                    Ok(LexItem::Float(value as f64 / divisor as f64))
+                }
+            },
+            None => Ok(LexItem::Number(value))
+        }
+    }
+}
+
+/// Skips a `--` comment up to the end of its line.
+fn skip_line_comment<T: Iterator<Item = (usize, char)>>(iter: &mut Peekable<T>) {
+    while let Some(&(_, ch)) = iter.peek() {
+        if ch == '\n' {
+            break;
+        }
+        iter.next();
+    }
+}
+
+/// Skips a `/* */` comment, returning whether it is closed.
+fn skip_block_comment<T: Iterator<Item = (usize, char)>>(iter: &mut Peekable<T>) -> bool {
+    iter.next();
+    iter.next();
+    let mut previous = None;
+    for (_, ch) in iter {
+        if previous == Some('*') && ch == '/' {
+            return true;
+        }
+        previous = Some(ch);
+    }
+    false
+}
+
+/// Whether a `-` after `tokens` is a binary minus rather than the sign of a number.
+/// `END` closes a `CASE` expression, so it ends an operand like `)` does.
+fn follows_operand(tokens: &[Token]) -> bool {
+    match tokens.last() {
+        Some(token) => matches!(token.kind, LexItem::Number(_) | LexItem::Float(_) | LexItem::Str(_) |
+            LexItem::Identifier(_) | LexItem::QuotedIdentifier(_) | LexItem::RightParen | LexItem::Keyword("END")),
+        None => false
+    }
+}
+
+/// Turns byte offsets into spans, counting lines while going through the
+/// input. Offsets have to be passed in increasing order.
+struct Lines<'a> {
+    input: &'a str,
+    offset: usize,
+    line: usize,
+    line_start: usize
+}
+
+impl<'a> Lines<'a> {
+    fn new(input: &'a str) -> Lines<'a> {
+        Lines { input, offset: 0, line: 1, line_start: 0 }
+    }
+
+    fn span(&mut self, start: usize, end: usize) -> Span {
+        for (index, ch) in self.input[self.offset..start].char_indices() {
+            if ch == '\n' {
+                self.line += 1;
+                self.line_start = self.offset + index + 1;
+            }
+        }
+        self.offset = start;
+        Span { start, end, line: self.line, column: self.input[self.line_start..start].chars().count() + 1 }
     }
-    number as usize
 }
 
-pub fn tokenize(input: &String) -> Result<Vec<LexItem>, String> {
+/// The tokens of `input`, ending with `LexItem::Eof`.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
     let mut result = Vec::new();
+    let mut lines = Lines::new(input);
 
-    let mut it = input.chars().peekable();
+    let mut it = input.char_indices().peekable();
 
-    while let Some(&ch) = it.peek() {
-        match ch {
-            '0'...'9' => {
+    while let Some(&(start, ch)) = it.peek() {
+        let next = it.clone().nth(1).map(|(_, ch)| ch);
+        let is_negative_number = ch == '-' && next.is_some_and(|ch| ch.is_ascii_digit()) && !follows_operand(&result);
+        let kind = match ch {
+            _ if ch.is_whitespace() => {
                 it.next();
-                let n = consume_number(ch, &mut it);
-                result.push(LexItem::Number(n));
+                continue;
             },
-            '\'' => {
-                it.next();
-                let string = consume_string(&mut it);
-                result.push(LexItem::Str(string));
+            '-' if next == Some('-') => {
+                skip_line_comment(&mut it);
+                continue;
+            },
+            '/' if next == Some('*') => {
+                if !skip_block_comment(&mut it) {
+                    return Err(ParseError::UnterminatedComment { span: lines.span(start, input.len()) });
+                }
+                continue;
+            },
+            _ if ch.is_ascii_digit() || is_negative_number => {
+                match consume_number(&mut it) {
+                    Ok(number) => number,
+                    Err(err) => {
+                        let end = it.peek().map(|&(offset, _)| offset).unwrap_or(input.len());
+                        let text = input[start..end].to_string();
+                        let span = lines.span(start, end);
+                        return Err(match err {
+                            NumberError::OutOfRange => ParseError::InvalidNumber { text, span },
+                            NumberError::Malformed => ParseError::MalformedNumber { text, span },
+                            NumberError::UnknownUnit(unit) => ParseError::UnknownUnit { unit, span }
+                        });
+                    }
+                }
+            },
+            '\'' | '"' => {
                 it.next();
+                match consume_string(ch, &mut it) {
+                    Some(string) if ch == '"' => LexItem::QuotedIdentifier(string),
+                    Some(string) => LexItem::Str(string),
+                    None => return Err(ParseError::UnterminatedString { span: lines.span(start, input.len()) })
+                }
             },
             '=' => {
-                result.push(LexItem::Equals);
                 it.next();
+                LexItem::Equals
+            },
+            '!' => {
+                it.next();
+                if it.peek().map(|&(_, ch)| ch) == Some('=') {
+                    it.next();
+                    LexItem::NotEquals
+                } else {
+                    return Err(ParseError::UnexpectedChar { found: ch, span: lines.span(start, start + 1) });
+                }
+            },
+            '<' => {
+                it.next();
+                match it.peek().map(|&(_, ch)| ch) {
+                    Some('=') => {
+                        it.next();
+                        LexItem::LessThanOrEquals
+                    },
+                    Some('>') => {
+                        it.next();
+                        LexItem::NotEquals
+                    },
+                    _ => LexItem::LessThan
+                }
+            },
+            '>' => {
+                it.next();
+                if it.peek().map(|&(_, ch)| ch) == Some('=') {
+                    it.next();
+                    LexItem::GreaterThanOrEquals
+                } else {
+                    LexItem::GreaterThan
+                }
             },
             ',' => {
-                result.push(LexItem::Comma);
                 it.next();
+                LexItem::Comma
+            },
+            ';' => {
+                it.next();
+                LexItem::Semicolon
+            },
+            '+' => {
+                it.next();
+                LexItem::Plus
+            },
+            '-' => {
+                it.next();
+                LexItem::Minus
+            },
+            '*' => {
+                it.next();
+                LexItem::Star
+            },
+            '/' => {
+                it.next();
+                LexItem::Slash
+            },
+            '(' => {
+                it.next();
+                LexItem::LeftParen
+            },
+            ')' => {
+                it.next();
+                LexItem::RightParen
             },
-            ' ' => { it.next(); },
             _ => {
-                if ch.is_alphabetic() {
-                    let string = consume_identifier(&mut it);
-                    result.push(LexItem::Identifier(string));
+                if is_identifier_start(ch) {
+                    let identifier = consume_identifier(&mut it);
+                    match keyword(&identifier) {
+                        Some(keyword) => LexItem::Keyword(keyword),
+                        None => LexItem::Identifier(identifier)
+                    }
                 } else {
-                    return Err(format!("Unexpected char {}", ch));
+                    return Err(ParseError::UnexpectedChar { found: ch, span: lines.span(start, start + ch.len_utf8()) });
                 }
             }
-        }
+        };
+
+        let end = it.peek().map(|&(offset, _)| offset).unwrap_or(input.len());
+        result.push(Token { kind, span: lines.span(start, end) });
     }
 
-    result.push(LexItem::EOF);
+    result.push(Token { kind: LexItem::Eof, span: lines.span(input.len(), input.len()) });
 
     Ok(result)
 }
@@ -97,30 +478,56 @@ pub fn tokenize(input: &String) -> Result<Vec<LexItem>, String> {
 mod tests {
     use super::*;
 
+    fn kinds(input: &str) -> Result<Vec<LexItem>, ParseError> {
+        Ok(tokenize(input)?.into_iter().map(|token| token.kind).collect())
+    }
+
     #[test]
     fn parse_string_consumes_until_quote() {
         let test_str = "'app.log'";
-        let mut iter = test_str.chars().peekable();
+        let mut iter = test_str.char_indices().peekable();
         iter.next();
-        let actual_str = consume_string(&mut iter);
-        assert_eq!(actual_str, "app.log");
+        let actual_str = consume_string('\'', &mut iter);
+        assert_eq!(actual_str, Some("app.log".into()));
+    }
+
+    #[test]
+    fn it_tokenizes_escaped_strings_and_quoted_identifiers() {
+        let results = kinds(r#"'it''s' 'a\'b\\c\n\d' "user id" "say ""hi""""#).unwrap();
+        assert_eq!(results, vec!(
+            LexItem::Str("it's".into()),
+            LexItem::Str("a'b\\c\n\\d".into()),
+            LexItem::QuotedIdentifier("user id".into()),
+            LexItem::QuotedIdentifier("say \"hi\"".into()),
+            LexItem::Eof
+        ));
+        assert_eq!(LexItem::Str("it's".into()).to_string(), "'it''s'");
+
+        assert_eq!(tokenize("x = 'app.log"), Err(ParseError::UnterminatedString { span: Span { start: 4, end: 12, line: 1, column: 5 } }));
+        assert_eq!(tokenize("SELECT \"a"), Err(ParseError::UnterminatedString { span: Span { start: 7, end: 9, line: 1, column: 8 } }));
+        assert!(tokenize("x = 'a\\").is_err());
+        assert!(is_identifier("request_id") && !is_identifier("request-id") && !is_identifier(""));
     }
 
     #[test]
     fn it_returns_eol_when_end_of_input_is_reached() {
-        let results = tokenize(&"SELECT type FROM 'app.log' WHERE type = 'error'".into()).unwrap();
-        assert_eq!(results[8], super::LexItem::EOF);
+        let results = kinds("SELECT type FROM 'app.log' WHERE type = 'error'").unwrap();
+        assert_eq!(results[8], super::LexItem::Eof);
+        let query = String::from("SELECT type FROM 'app.log'");
+        #[allow(deprecated)]
+        let eof = super::LexItem::EOF;
+        assert_eq!(tokenize(&query).unwrap().last().map(|token| &token.kind), Some(&eof));
     }
 
     #[test]
     fn it_tokenizes_simple_select_where() {
-        let results = tokenize(&"SELECT type FROM 'app.log' WHERE type = 'error'".into()).unwrap();
-        assert_eq!(results[0], super::LexItem::Identifier("SELECT".into()));
+        let results = kinds("SELECT type FROM 'app.log' WHERE type = 'error'").unwrap();
+        assert_eq!(results[0], super::LexItem::Keyword("SELECT"));
         assert_eq!(results[1], super::LexItem::Identifier("type".into()));
-        assert_eq!(results[2], super::LexItem::Identifier("FROM".into()));
+        assert_eq!(results[2], super::LexItem::Keyword("FROM"));
         assert_eq!(results[3], super::LexItem::Str("app.log".into()));
 
-        assert_eq!(results[4], super::LexItem::Identifier("WHERE".into()));
+        assert_eq!(results[4], super::LexItem::Keyword("WHERE"));
         assert_eq!(results[5], super::LexItem::Identifier("type".into()));
         assert_eq!(results[6], super::LexItem::Equals);
         assert_eq!(results[7], super::LexItem::Str("error".into()));
@@ -128,28 +535,153 @@ mod tests {
 
     #[test]
     fn it_tokenizes_simple_select_with_limit() {
-        let results = tokenize(&"SELECT type FROM 'app.log' LIMIT 10".into()).unwrap();
-        assert_eq!(results[0], super::LexItem::Identifier("SELECT".into()));
+        let results = kinds("SELECT type FROM 'app.log' LIMIT 10").unwrap();
+        assert_eq!(results[0], super::LexItem::Keyword("SELECT"));
         assert_eq!(results[1], super::LexItem::Identifier("type".into()));
-        assert_eq!(results[2], super::LexItem::Identifier("FROM".into()));
+        assert_eq!(results[2], super::LexItem::Keyword("FROM"));
         assert_eq!(results[3], super::LexItem::Str("app.log".into()));
 
-        assert_eq!(results[4], super::LexItem::Identifier("LIMIT".into()));
+        assert_eq!(results[4], super::LexItem::Keyword("LIMIT"));
         assert_eq!(results[5], super::LexItem::Number(10));
     }
 
     #[test]
     fn it_tokenizes_select_with_multiple_select_fields() {
-        let results = tokenize(&"SELECT type, date, severity FROM 'app.log' LIMIT 10".into()).unwrap();
-        assert_eq!(results[0], super::LexItem::Identifier("SELECT".into()));
+        let results = kinds("SELECT type, date, severity FROM 'app.log' LIMIT 10").unwrap();
+        assert_eq!(results[0], super::LexItem::Keyword("SELECT"));
         assert_eq!(results[1], super::LexItem::Identifier("type".into()));
         assert_eq!(results[2], super::LexItem::Comma);
         assert_eq!(results[3], super::LexItem::Identifier("date".into()));
         assert_eq!(results[4], super::LexItem::Comma);
         assert_eq!(results[5], super::LexItem::Identifier("severity".into()));
-        assert_eq!(results[6], super::LexItem::Identifier("FROM".into()));
+        assert_eq!(results[6], super::LexItem::Keyword("FROM"));
         assert_eq!(results[7], super::LexItem::Str("app.log".into()));
-        assert_eq!(results[8], super::LexItem::Identifier("LIMIT".into()));
+        assert_eq!(results[8], super::LexItem::Keyword("LIMIT"));
         assert_eq!(results[9], super::LexItem::Number(10));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_tokenizes_arithmetic_and_function_calls() {
+        let results = kinds("SELECT upper(severity), latency / 1000.5 FROM 'app.log'").unwrap();
+        assert_eq!(results[1], super::LexItem::Identifier("upper".into()));
+        assert_eq!(results[2], super::LexItem::LeftParen);
+        assert_eq!(results[3], super::LexItem::Identifier("severity".into()));
+        assert_eq!(results[4], super::LexItem::RightParen);
+        assert_eq!(results[5], super::LexItem::Comma);
+        assert_eq!(results[6], super::LexItem::Identifier("latency".into()));
+        assert_eq!(results[7], super::LexItem::Slash);
+        assert_eq!(results[8], super::LexItem::Float(1000.5));
+    }
+
+    #[test]
+    fn it_tokenizes_operators() {
+        let results = kinds("* + - /").unwrap();
+        assert_eq!(results, vec!(LexItem::Star, LexItem::Plus, LexItem::Minus, LexItem::Slash, LexItem::Eof));
+        assert_eq!(kinds("a;").unwrap(), vec!(LexItem::Identifier("a".into()), LexItem::Semicolon, LexItem::Eof));
+    }
+
+    #[test]
+    fn it_tokenizes_identifiers_with_underscores() {
+        let results = kinds("SELECT regexp_extract(message, 'x') FROM 'app.log'").unwrap();
+        assert_eq!(results[1], super::LexItem::Identifier("regexp_extract".into()));
+    }
+
+    #[test]
+    fn it_tokenizes_keywords_in_any_case_and_identifiers_with_digits_and_dots() {
+        let results = kinds("select http_status, trace_id2, kubernetes.pod.name, _x FROM 'a' Where a like 'b' limit last").unwrap();
+        assert_eq!(results, vec!(
+            LexItem::Keyword("SELECT"),
+            LexItem::Identifier("http_status".into()), LexItem::Comma,
+            LexItem::Identifier("trace_id2".into()), LexItem::Comma,
+            LexItem::Identifier("kubernetes.pod.name".into()), LexItem::Comma,
+            LexItem::Identifier("_x".into()),
+            LexItem::Keyword("FROM"), LexItem::Str("a".into()),
+            LexItem::Keyword("WHERE"), LexItem::Identifier("a".into()), LexItem::Keyword("LIKE"), LexItem::Str("b".into()),
+            LexItem::Keyword("LIMIT"), LexItem::Identifier("last".into()),
+            LexItem::Eof
+        ));
+        assert_eq!(kinds("a. 2x").unwrap_err(), ParseError::UnexpectedChar { found: '.', span: Span { start: 1, end: 2, line: 1, column: 2 } });
+        assert_eq!(kinds("\"from\"").unwrap(), vec!(LexItem::QuotedIdentifier("from".into()), LexItem::Eof));
+        assert!(is_identifier("kubernetes.pod") && is_identifier("trace_id2"));
+        assert!(!is_identifier("From") && !is_identifier("2x") && !is_identifier("a."));
+    }
+
+    #[test]
+    fn it_skips_whitespace_and_comments() {
+        let query = "SELECT\ta, -- the first field\n\u{a0}b /* and\n the second */ FROM\u{3000}'x'\r\n-- trailing";
+        let results = tokenize(query).unwrap();
+        let found: Vec<LexItem> = results.iter().map(|token| token.kind.clone()).collect();
+        assert_eq!(found, vec!(
+            LexItem::Keyword("SELECT"), LexItem::Identifier("a".into()), LexItem::Comma, LexItem::Identifier("b".into()),
+            LexItem::Keyword("FROM"), LexItem::Str("x".into()), LexItem::Eof
+        ));
+        assert_eq!((results[3].span.line, results[3].span.column), (2, 2));
+        assert_eq!((results[4].span.line, results[4].span.column), (3, 16));
+
+        assert_eq!(kinds("1 - -1 --1\n/*/ */ 2 /**/").unwrap(), vec!(LexItem::Number(1), LexItem::Minus, LexItem::Number(-1), LexItem::Number(2), LexItem::Eof));
+        assert_eq!(tokenize("a /* b"), Err(ParseError::UnterminatedComment { span: Span { start: 2, end: 6, line: 1, column: 3 } }));
+    }
+
+    #[test]
+    fn it_tokenizes_comparison_operators() {
+        let results = kinds("= != <> < <= > >=").unwrap();
+        assert_eq!(results, vec!(
+            LexItem::Equals,
+            LexItem::NotEquals,
+            LexItem::NotEquals,
+            LexItem::LessThan,
+            LexItem::LessThanOrEquals,
+            LexItem::GreaterThan,
+            LexItem::GreaterThanOrEquals,
+            LexItem::Eof
+        ));
+    }
+
+    #[test]
+    fn it_fails_on_single_exclamation_mark() {
+        assert!(tokenize("status ! 500").is_err());
+    }
+
+    #[test]
+    fn it_tokenizes_numbers_with_signs_exponents_and_units() {
+        let results = kinds("-12 a-1 (2)-1 0x1F, -0x10 2.5e3 1E-2 10KB 1.5KiB 250ms 1500us 2h 9223372036854775807, -9223372036854775808").unwrap();
+        assert_eq!(results, vec!(
+            LexItem::Number(-12),
+            LexItem::Identifier("a".into()), LexItem::Minus, LexItem::Number(1),
+            LexItem::LeftParen, LexItem::Number(2), LexItem::RightParen, LexItem::Minus, LexItem::Number(1),
+            LexItem::Number(31), LexItem::Comma,
+            LexItem::Number(-16),
+            LexItem::Float(2500.0),
+            LexItem::Float(0.01),
+            LexItem::Number(10_000),
+            LexItem::Number(1536),
+            LexItem::Number(250),
+            LexItem::Float(1.5),
+            LexItem::Number(7_200_000),
+            LexItem::Number(i64::MAX), LexItem::Comma,
+            LexItem::Number(i64::MIN),
+            LexItem::Eof
+        ));
+
+        let results = kinds("CASE WHEN x > 1 THEN 2 ELSE 3 END -1").unwrap();
+        assert_eq!(results[results.len() - 4..].to_vec(), vec!(LexItem::Keyword("END"), LexItem::Minus, LexItem::Number(1), LexItem::Eof));
+
+        assert_eq!(tokenize("x = 9223372036854775808"), Err(ParseError::InvalidNumber { text: "9223372036854775808".into(), span: Span { start: 4, end: 23, line: 1, column: 5 } }));
+        assert!(matches!(tokenize("x = 1e999"), Err(ParseError::InvalidNumber { .. })));
+        assert!(matches!(tokenize("x = 9223372036854775807KB"), Err(ParseError::InvalidNumber { .. })));
+        assert!(matches!(tokenize("x = 0x"), Err(ParseError::MalformedNumber { .. })));
+        assert!(matches!(tokenize("x = 0x1G"), Err(ParseError::MalformedNumber { .. })));
+        assert_eq!(tokenize("x = 10 + 5kb"), Err(ParseError::UnknownUnit { unit: "kb".into(), span: Span { start: 9, end: 12, line: 1, column: 10 } }));
+    }
+
+    #[test]
+    fn it_reports_where_tokens_and_errors_are() {
+        let results = tokenize("SELECT 'a\nb' FROM x").unwrap();
+        let spans: Vec<(usize, usize, usize, usize)> = results.iter().map(|token| (token.span.start, token.span.end, token.span.line, token.span.column)).collect();
+        assert_eq!(spans, vec!((0, 6, 1, 1), (7, 12, 1, 8), (13, 17, 2, 4), (18, 19, 2, 9), (19, 19, 2, 10)));
+
+        assert_eq!(tokenize("a = ä ?"), Err(ParseError::UnexpectedChar { found: '?', span: Span { start: 7, end: 8, line: 1, column: 7 } }));
+        assert_eq!(tokenize("a = 'b"), Err(ParseError::UnterminatedString { span: Span { start: 4, end: 6, line: 1, column: 5 } }));
+        assert_eq!(tokenize("LIMIT 99999999999999999999"), Err(ParseError::InvalidNumber { text: "99999999999999999999".into(), span: Span { start: 6, end: 26, line: 1, column: 7 } }));
+    }
+}
@@ -1,2 +1,31 @@
-mod lexer;
+extern crate bzip2;
+extern crate chrono;
+extern crate flate2;
+extern crate glob;
+extern crate memchr;
+extern crate memmap2;
+extern crate regex;
+extern crate serde_json;
+extern crate zstd;
+
+#[cfg(test)]
+extern crate tempfile;
+
+pub mod ast;
+pub mod cache;
+pub mod context;
+mod encoding;
+pub mod error;
+pub mod executor;
+pub mod explain;
+pub mod follow;
+pub mod functions;
+pub mod index;
+pub mod lexer;
+pub mod mapped;
+pub mod optimizer;
 pub mod parser;
+pub mod plan;
+pub mod record;
+pub mod reverse;
+pub mod source;
@@ -0,0 +1,148 @@
+extern crate log_ql;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use log_ql::cache::Cache;
+use log_ql::context::QueryContext;
+use log_ql::functions::Value;
+use log_ql::index;
+use log_ql::parser::ASTNode;
+use log_ql::source::{self, Compression};
+
+const USAGE: &str = "Usage: log-ql [--follow] [--cache] QUERY[; QUERY...]
+       log-ql --index FILE...
+
+Options:
+    -f, --follow    Keep watching the files of the last query and print records as they are appended
+    --cache         Keep the files converted into columns in ~/.cache/log-ql to speed up repeated queries
+    --index         Build an index next to each file to speed up repeated queries";
+
+fn main() {
+    let mut follow = false;
+    let mut cache = false;
+    let mut query = None;
+
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    if arguments.first().is_some_and(|argument| argument == "--index") {
+        if arguments.len() == 1 {
+            exit_with_usage();
+        }
+        if let Err(err) = build_indexes(&arguments[1..]) {
+            eprintln!("log-ql: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    for argument in arguments {
+        match argument.as_str() {
+            "-f" | "--follow" => follow = true,
+            "--cache" => cache = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if query.is_none() => query = Some(argument),
+            _ => exit_with_usage()
+        }
+    }
+
+    let query = query.unwrap_or_else(|| exit_with_usage());
+    if let Err(err) = run(&query, follow, cache) {
+        eprintln!("log-ql: {}", err);
+        process::exit(1);
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn run(query: &str, follow: bool, cache: bool) -> Result<(), String> {
+    let mut context = QueryContext::new();
+    if cache {
+        context.set_cache(Cache::new(cache_directory()));
+    }
+    let statements = context.parser(query.into()).parse_script().map_err(|err| err.render(query))?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    // The results of the statements of a script are separated by an empty line.
+    for (position, ast) in statements.iter().enumerate() {
+        let is_last = position + 1 == statements.len();
+        if position > 0 && writeln!(out).is_err() {
+            break;
+        }
+        if !run_statement(&context, ast, follow && is_last, &mut out)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a statement, printing its rows to `out`. Returns whether `out` still
+/// takes output.
+fn run_statement<W: Write>(context: &QueryContext, ast: &ASTNode, follow: bool, out: &mut W) -> Result<bool, String> {
+    if ast.is_explain() {
+        for row in context.executor().execute(ast)?.rows {
+            let line: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+            if writeln!(out, "{}", line.join("\t")).is_err() {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
+    if follow || ast.is_follow() {
+        // Stop quietly once the reader goes away, e.g. `| head`.
+        context.executor().follow(ast, |row| {
+            let pairs: Vec<String> = row.iter().map(|(name, value)| format!("{}={}", name, format_value(value))).collect();
+            writeln!(out, "{}", pairs.join(" ")).is_ok()
+        })?;
+        return Ok(false);
+    }
+
+    let result = context.executor().execute(ast)?;
+    let mut lines = vec!(result.columns.join("\t"));
+    for row in &result.rows {
+        lines.push(row.iter().map(format_value).collect::<Vec<String>>().join("\t"));
+    }
+    for line in lines {
+        if writeln!(out, "{}", line).is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// `$XDG_CACHE_HOME/log-ql`, or `~/.cache/log-ql` if it is not set.
+fn cache_directory() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("log-ql")
+}
+
+fn build_indexes(files: &[String]) -> Result<(), String> {
+    for file in files {
+        let path = Path::new(file);
+        if source::detect_compression(path)? != Compression::None {
+            return Err(format!("Cannot index compressed file {:?}", path));
+        }
+        let index = index::build(path)?;
+        println!("Indexed {}: {} blocks in {}", file, index.block_count(), index::path_for(path).display());
+    }
+    Ok(())
+}
+
+/// Quotes strings containing whitespace so records printed while following read as logfmt.
+fn format_value(value: &Value) -> String {
+    match *value {
+        Value::Str(ref s) if s.is_empty() || s.contains(char::is_whitespace) => format!("{:?}", s),
+        _ => value.to_string()
+    }
+}
@@ -0,0 +1,257 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+
+use memchr::memchr;
+use memmap2::Mmap;
+
+use record::{self, LineRecord};
+
+/// An uncompressed log file mapped into memory, so its records can be
+/// scanned without copying each line.
+///
+/// The mapping reflects the file as it changes: records appended meanwhile are
+/// not seen, but truncating the file while it is scanned makes the process
+/// fail with SIGBUS when reading past the new end. Followed files, which are
+/// expected to be truncated by log rotation, are therefore never mapped.
+pub struct MappedFile {
+    path: PathBuf,
+    name: Arc<String>,
+    /// `None` for empty files, which cannot be mapped.
+    map: Option<Mmap>
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> Result<MappedFile, String> {
+        let file = File::open(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+        let length = file.metadata().map_err(|err| format!("Cannot read {:?}: {}", path, err))?.len();
+
+        let map = if length == 0 {
+            None
+        } else {
+            // Safety: the mapping is only read, see the type's documentation for
+            // what happens if the file is truncated meanwhile.
+            Some(unsafe { Mmap::map(&file) }.map_err(|err| format!("Cannot map {:?}: {}", path, err))?)
+        };
+
+        Ok(MappedFile {
+            path: path.to_path_buf(),
+            name: Arc::new(path.to_string_lossy().into_owned()),
+            map
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.data().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data().is_empty()
+    }
+
+    fn data(&self) -> &[u8] {
+        self.map.as_ref().map(|map| &map[..]).unwrap_or(&[])
+    }
+
+    /// The records between the byte offsets `start` and `end`, which have to
+    /// be record boundaries, with `line` lines before `start`.
+    pub fn records_between<'a>(&'a self, start: usize, end: usize, line: usize) -> MappedRecords<'a> {
+        MappedRecords {
+            file: &self.name,
+            data: &self.data()[..end],
+            position: start,
+            line
+        }
+    }
+
+    pub fn records<'a>(&'a self) -> MappedRecords<'a> {
+        MappedRecords {
+            file: &self.name,
+            data: self.data(),
+            position: 0,
+            line: 0
+        }
+    }
+
+    /// Splits the file into at most `count` parts of at least `min_size` bytes
+    /// to be scanned independently. Parts start at a record, never at one of
+    /// its continuation lines, and number their lines as in the whole file.
+    pub fn chunks<'a>(&'a self, count: usize, min_size: usize) -> Vec<MappedRecords<'a>> {
+        let data = self.data();
+        let count = count.min(data.len() / min_size.max(1)).max(1);
+
+        let mut chunks = vec!();
+        let (mut start, mut line) = (0, 0);
+        for index in 1..=count {
+            let end = if index == count { data.len() } else { next_record_start(data, data.len() / count * index).max(start) };
+            if end == start && index != count {
+                continue;
+            }
+            chunks.push(MappedRecords {
+                file: &self.name,
+                data: &data[..end],
+                position: start,
+                line
+            });
+            line += memchr::memchr_iter(b'\n', &data[start..end]).count();
+            start = end;
+        }
+        chunks
+    }
+}
+
+/// The offset of the first line at or after `offset` that starts a record.
+fn next_record_start(data: &[u8], offset: usize) -> usize {
+    let mut position = match offset {
+        0 => 0,
+        _ => memchr(b'\n', &data[offset - 1..]).map(|newline| offset + newline).unwrap_or(data.len())
+    };
+
+    while position < data.len() {
+        let rest = &data[position..];
+        let line = &rest[..memchr(b'\n', rest).unwrap_or(rest.len())];
+        if !line.iter().all(u8::is_ascii_whitespace) && !record::is_continuation(line) {
+            break;
+        }
+        position += line.len() + 1;
+    }
+    position.min(data.len())
+}
+
+/// The records of a `MappedFile`, borrowing their text from the mapping.
+/// Continuation lines are joined to their record just like `FileRecords` does.
+pub struct MappedRecords<'a> {
+    file: &'a Arc<String>,
+    data: &'a [u8],
+    position: usize,
+    line: usize
+}
+
+impl<'a> MappedRecords<'a> {
+    /// The byte offset of the next record.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The number of lines before the next record.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the next line without its line ending, or `None` at the end of the data.
+    fn next_line(&mut self) -> Option<&'a [u8]> {
+        if self.position >= self.data.len() {
+            return None;
+        }
+
+        let rest = &self.data[self.position..];
+        let length = memchr(b'\n', rest).unwrap_or(rest.len());
+        self.position += (length + 1).min(rest.len());
+        self.line += 1;
+
+        let line = &rest[..length];
+        Some(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+}
+
+impl<'a> Iterator for MappedRecords<'a> {
+    type Item = Result<LineRecord<'a>, String>;
+
+    fn next(&mut self) -> Option<Result<LineRecord<'a>, String>> {
+        let (start, line) = loop {
+            let start = self.position;
+            let text = self.next_line()?;
+            if !text.iter().all(u8::is_ascii_whitespace) {
+                break (start, self.line);
+            }
+        };
+
+        let mut end = self.position;
+        loop {
+            let (position, line_count) = (self.position, self.line);
+            match self.next_line() {
+                Some(text) if text.iter().all(u8::is_ascii_whitespace) => {},
+                Some(text) if record::is_continuation(text) => end = self.position,
+                _ => {
+                    self.position = position;
+                    self.line = line_count;
+                    break;
+                }
+            }
+        }
+
+        let bytes = &self.data[start..end];
+        let text = match str::from_utf8(bytes) {
+            Ok(text) => Cow::Borrowed(text),
+            Err(_) => String::from_utf8_lossy(bytes)
+        };
+        Some(Ok(LineRecord::new(text, self.file, line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    use functions::Value;
+    use record::Fields;
+
+    fn mapped(content: &[u8]) -> (NamedTempFile, MappedFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        let mapped = MappedFile::open(file.path()).unwrap();
+        (file, mapped)
+    }
+
+    #[test]
+    fn it_reads_records_with_their_line_numbers() {
+        let (_file, mapped) = mapped(b"n=1\r\n\nn=2 msg=boom\n  at main.rs:10\n\n\tat lib.rs:2\nn=3");
+        let records: Vec<(Value, Value, Value)> = mapped.records()
+            .map(|record| record.unwrap())
+            .map(|record| (record.get("n"), record.get("msg"), record.get("_line")))
+            .collect();
+
+        assert_eq!(records, vec!(
+            (Value::Integer(1), Value::Null, Value::Integer(1)),
+            (Value::Integer(2), Value::Str("boom".into()), Value::Integer(3)),
+            (Value::Integer(3), Value::Null, Value::Integer(7))
+        ));
+    }
+
+    #[test]
+    fn it_splits_files_into_chunks_at_record_boundaries() {
+        let content = b"n=1\nn=2 msg=boom\n  at main.rs:10\n\tat lib.rs:2\nn=3\n\nn=4\nn=5\n";
+        let (_file, mapped) = mapped(content);
+        let all: Vec<(Value, Value)> = mapped.records().map(|record| record.unwrap()).map(|record| (record.get("n"), record.get("_line"))).collect();
+
+        for count in 1..12 {
+            let chunks = mapped.chunks(count, 1);
+            assert!(!chunks.is_empty() && chunks.len() <= count);
+            let joined: Vec<(Value, Value)> = chunks.into_iter()
+                .flat_map(|chunk| chunk.map(|record| record.unwrap()).map(|record| (record.get("n"), record.get("_line"))).collect::<Vec<_>>())
+                .collect();
+            assert_eq!(joined, all, "{} chunks", count);
+        }
+
+        assert_eq!(mapped.chunks(8, content.len()).len(), 1);
+    }
+
+    #[test]
+    fn it_reads_empty_files_and_invalid_utf8() {
+        let (_file, mapped_empty) = mapped(b"");
+        assert_eq!(mapped_empty.records().count(), 0);
+        assert_eq!(mapped_empty.chunks(4, 1).len(), 1);
+
+        let (_file, mapped_invalid) = mapped(b"msg=caf\xe9\n");
+        let record = mapped_invalid.records().next().unwrap().unwrap();
+        assert_eq!(record.get("msg"), Value::Str("caf\u{fffd}".into()));
+    }
+}
@@ -0,0 +1,117 @@
+use memchr::memchr;
+use memchr::memmem::Finder;
+
+use parser::{Expression, WhereComparator};
+use record::{FILE_FIELD, LINE_FIELD};
+
+/// Literal text that the line of a record has to contain for the record to
+/// match a `WHERE` clause, derived from the clause before any line is parsed.
+///
+/// `level = 'error'` needs `error` somewhere in the line and
+/// `message LIKE '%connection%timeout%'` needs both `connection` and
+/// `timeout`, so lines without them can be skipped without splitting them
+/// into fields.
+///
+/// Field values match the line's text except where escape sequences were
+/// decoded, so lines containing a backslash are never skipped. Literals that
+/// also match differently formatted values, like numbers (`503` equals
+/// `503.0`), booleans (`'TRUE'` equals `true`) and nested JSON, which is
+/// formatted anew, are not used.
+pub struct Prefilter {
+    /// Longest, and so most selective, literal first.
+    finders: Vec<Finder<'static>>
+}
+
+impl Prefilter {
+    /// Derives the literals required by `field mode value`, or `None` if
+    /// there are none.
+    pub fn from_condition(field: &Expression, mode: &WhereComparator, value: &Expression) -> Option<Prefilter> {
+        let mut literals = required_literals(field, mode, value);
+        literals.retain(|literal| !literal.is_empty() && is_verbatim(literal));
+        if literals.is_empty() {
+            return None;
+        }
+
+        literals.sort_by_key(|literal| std::cmp::Reverse(literal.len()));
+        Some(Prefilter {
+            finders: literals.iter().map(|literal| Finder::new(literal.as_bytes()).into_owned()).collect()
+        })
+    }
+
+    /// The literals records need to contain, longest first.
+    pub fn literals(&self) -> Vec<String> {
+        self.finders.iter().map(|finder| String::from_utf8_lossy(finder.needle()).into_owned()).collect()
+    }
+
+    /// Whether a record with the line(s) `text` can match the condition.
+    pub fn may_match(&self, text: &[u8]) -> bool {
+        self.finders.iter().all(|finder| finder.find(text).is_some()) || memchr(b'\\', text).is_some()
+    }
+}
+
+fn required_literals(field: &Expression, mode: &WhereComparator, value: &Expression) -> Vec<String> {
+    match (field, mode, value) {
+        (Expression::Field(name), WhereComparator::StrictEquals, Expression::Str(literal)) |
+        (Expression::Str(literal), WhereComparator::StrictEquals, Expression::Field(name)) if is_stored_field(name) => {
+            vec!(literal.clone())
+        },
+        (Expression::Field(name), WhereComparator::Like, Expression::Str(pattern)) if is_stored_field(name) => {
+            pattern.split(['%', '_']).map(String::from).collect()
+        },
+        _ => vec!()
+    }
+}
+
+/// Virtual fields are not part of the line.
+pub(crate) fn is_stored_field(name: &str) -> bool {
+    name != FILE_FIELD && name != LINE_FIELD
+}
+
+/// Whether a field value containing `literal` always has it verbatim in its line.
+fn is_verbatim(literal: &str) -> bool {
+    let could_be_number = literal.chars().all(|ch| ch.is_ascii_digit() || ['.', '-', '+', 'e', 'E'].contains(&ch)) ||
+        literal.trim().parse::<f64>().is_ok();
+    let could_be_boolean = literal.eq_ignore_ascii_case("true") || literal.eq_ignore_ascii_case("false");
+
+    !could_be_number && !could_be_boolean && !literal.contains(['"', '\\', '{', '}', '[', ']', ':', ',']) && !literal.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefilter(field: Expression, mode: WhereComparator, value: Expression) -> Option<Prefilter> {
+        Prefilter::from_condition(&field, &mode, &value)
+    }
+
+    fn literals(prefilter: Option<Prefilter>) -> Vec<String> {
+        prefilter.map(|prefilter| prefilter.literals()).unwrap_or_default()
+    }
+
+    #[test]
+    fn it_derives_literals_from_equality_and_like() {
+        assert_eq!(literals(prefilter(Expression::Field("level".into()), WhereComparator::StrictEquals, Expression::Str("error".into()))), vec!("error"));
+        assert_eq!(literals(prefilter(Expression::Str("error".into()), WhereComparator::StrictEquals, Expression::Field("level".into()))), vec!("error"));
+        assert_eq!(literals(prefilter(Expression::Field("msg".into()), WhereComparator::Like, Expression::Str("%conn%timeout_at%".into()))), vec!("timeout", "conn", "at"));
+    }
+
+    #[test]
+    fn it_skips_conditions_without_verbatim_literals() {
+        assert!(prefilter(Expression::Field("status".into()), WhereComparator::StrictEquals, Expression::Str("503".into())).is_none());
+        assert!(prefilter(Expression::Field("status".into()), WhereComparator::Like, Expression::Str("5%".into())).is_none());
+        assert!(prefilter(Expression::Field("ok".into()), WhereComparator::StrictEquals, Expression::Str("TRUE".into())).is_none());
+        assert!(prefilter(Expression::Field("level".into()), WhereComparator::NotEquals, Expression::Str("error".into())).is_none());
+        assert!(prefilter(Expression::Field("_file".into()), WhereComparator::StrictEquals, Expression::Str("app.log".into())).is_none());
+        assert!(prefilter(Expression::FunctionCall { name: "lower".into(), arguments: vec!(Expression::Field("level".into())) }, WhereComparator::StrictEquals, Expression::Str("error".into())).is_none());
+        assert!(prefilter(Expression::Field("msg".into()), WhereComparator::Like, Expression::Str("%".into())).is_none());
+        assert!(prefilter(Expression::Field("request".into()), WhereComparator::Like, Expression::Str("%path:/%".into())).is_none());
+    }
+
+    #[test]
+    fn it_only_rejects_lines_missing_a_literal_without_escapes() {
+        let prefilter = prefilter(Expression::Field("msg".into()), WhereComparator::Like, Expression::Str("%conn%timeout%".into())).unwrap();
+        assert!(prefilter.may_match(b"msg=\"connection timeout\""));
+        assert!(!prefilter.may_match(b"msg=\"connection refused\""));
+        assert!(prefilter.may_match(b"{\"msg\": \"connection \\u0074imeout\"}"));
+    }
+}
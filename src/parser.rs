@@ -1,15 +1,158 @@
-use lexer;
+use std::fmt;
+
+pub use error::{ParseError, Span};
+use functions::FunctionRegistry;
+use lexer::{self, Token};
 use lexer::LexItem;
+use source;
+
 
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Clone)]
 pub enum GrammarItem {
+    /// Describes the plan of the query below it instead of returning its
+    /// rows; with `analyze` the query is run to report what it did.
+    Explain { analyze: bool },
     Query,
-    LogFile { fields: Vec<String>, filename: String },
-    Condition { field: String, mode: WhereComparator, value: String },
+    LogFile { fields: Vec<SelectField>, filenames: Vec<String> },
+    Condition { field: Expression, mode: WhereComparator, value: Expression },
     Limit { number_of_rows: usize, direction: LimitDirection },
-    LogResult
+    /// How the matching records are produced: aggregated per distinct value of
+    /// `group_by`, in the order of `order_by`, or as they are read if both are
+    /// empty; `follow` keeps watching the files for new records.
+    LogResult { follow: bool, group_by: Vec<Expression>, order_by: Vec<SortKey> }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct SelectField {
+    pub expression: Expression,
+    pub alias: Option<String>
+}
+
+impl SelectField {
+    pub fn new(expression: Expression, alias: Option<String>) -> SelectField {
+        SelectField {
+            expression,
+            alias
+        }
+    }
+}
+
+/// An expression of `ORDER BY` with its direction.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct SortKey {
+    pub expression: Expression,
+    pub descending: bool
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.expression, if self.descending { " DESC" } else { "" })
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum Expression {
+    Wildcard,
+    Field(String),
+    Str(String),
+    Number(i64),
+    Float(f64),
+    Negate(Box<Expression>),
+    BinaryOperation { left: Box<Expression>, operator: ArithmeticOperator, right: Box<Expression> },
+    Comparison { left: Box<Expression>, mode: WhereComparator, right: Box<Expression> },
+    FunctionCall { name: String, arguments: Vec<Expression> },
+    /// `CASE WHEN condition THEN result ... ELSE result END`, each branch pairing a `Comparison` with its result.
+    Case { branches: Vec<(Expression, Expression)>, else_result: Option<Box<Expression>> }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide
+}
+
+impl ArithmeticOperator {
+    fn precedence(&self) -> u8 {
+        match *self {
+            ArithmeticOperator::Add | ArithmeticOperator::Subtract => 1,
+            ArithmeticOperator::Multiply | ArithmeticOperator::Divide => 2
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArithmeticOperator::Add => write!(f, "+"),
+            ArithmeticOperator::Subtract => write!(f, "-"),
+            ArithmeticOperator::Multiply => write!(f, "*"),
+            ArithmeticOperator::Divide => write!(f, "/")
+        }
+    }
+}
+
+impl Expression {
+    /// Formats `self` as the operand of `parent`, adding parentheses where
+    /// they are needed to keep the original grouping.
+    fn fmt_operand(&self, parent: &ArithmeticOperator, is_right: bool, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::BinaryOperation { ref operator, .. } if operator.precedence() < parent.precedence() || (is_right && operator.precedence() == parent.precedence()) => {
+                write!(f, "({})", self)
+            },
+            _ => write!(f, "{}", self)
+        }
+    }
+}
+
+/// Renders an expression back into query syntax, e.g. to name result columns.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::Wildcard => write!(f, "*"),
+            Expression::Field(ref name) => write!(f, "{}", quote_identifier(name)),
+            Expression::Str(ref s) => write!(f, "{}", lexer::quote(s, '\'')),
+            Expression::Number(number) => write!(f, "{}", number),
+            Expression::Float(number) => write!(f, "{:?}", number),
+            Expression::Negate(ref operand) => {
+                match **operand {
+                    Expression::BinaryOperation { .. } => write!(f, "-({})", operand),
+                    _ => write!(f, "-{}", operand)
+                }
+            },
+            Expression::BinaryOperation { ref left, ref operator, ref right } => {
+                left.fmt_operand(operator, false, f)?;
+                write!(f, " {} ", operator)?;
+                right.fmt_operand(operator, true, f)
+            },
+            Expression::Comparison { ref left, ref mode, ref right } => write!(f, "{} {} {}", left, mode, right),
+            Expression::FunctionCall { ref name, ref arguments } => {
+                let arguments: Vec<String> = arguments.iter().map(|argument| argument.to_string()).collect();
+                write!(f, "{}({})", name, arguments.join(", "))
+            },
+            Expression::Case { ref branches, ref else_result } => {
+                write!(f, "CASE")?;
+                for (condition, result) in branches {
+                    write!(f, " WHEN {} THEN {}", condition, result)?;
+                }
+                if let Some(ref else_result) = *else_result {
+                    write!(f, " ELSE {}", else_result)?;
+                }
+                write!(f, " END")
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -25,53 +168,97 @@ pub enum LimitDirection {
 #[derive(Clone)]
 pub enum WhereComparator {
     StrictEquals,
+    NotEquals,
+    LessThan,
+    LessThanOrEquals,
+    GreaterThan,
+    GreaterThanOrEquals,
     Like
 }
 
+impl fmt::Display for WhereComparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WhereComparator::StrictEquals => write!(f, "="),
+            WhereComparator::NotEquals => write!(f, "!="),
+            WhereComparator::LessThan => write!(f, "<"),
+            WhereComparator::LessThanOrEquals => write!(f, "<="),
+            WhereComparator::GreaterThan => write!(f, ">"),
+            WhereComparator::GreaterThanOrEquals => write!(f, ">="),
+            WhereComparator::Like => write!(f, "LIKE")
+        }
+    }
+}
+
+/// A parsed statement as a tree of grammar items. `ast::Statement` and
+/// `ast::Query` give the same tree with a named field for each clause.
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct ASTNode {
     pub left: Option<Box<ASTNode>>,
     pub right: Option<Box<ASTNode>>,
     pub entry: GrammarItem,
+    /// The text the node was parsed from, including the keyword starting its
+    /// clause. Nodes not built by the parser have an empty span at the start.
+    pub span: Span
 }
 
 impl ASTNode {
     pub fn new(entry: GrammarItem, left: Option<Box<ASTNode>>, right: Option<Box<ASTNode>>) -> ASTNode {
         ASTNode {
-            entry: entry,
-            left: left,
-            right: right
+            entry,
+            left,
+            right,
+            span: Span::default()
+        }
+    }
+
+    /// Whether `self` is an `EXPLAIN` of a query, which is its left child.
+    pub fn is_explain(&self) -> bool {
+        matches!(self.entry, GrammarItem::Explain { .. })
+    }
+
+    /// Whether `self` is a query that keeps following its files (`FOLLOW` or `TAIL`).
+    pub fn is_follow(&self) -> bool {
+        match self.right {
+            Some(ref log_result) => matches!(log_result.entry, GrammarItem::LogResult { follow: true, .. }),
+            None => false
         }
     }
 }
 
 pub struct Parser {
     query: String,
+    functions: FunctionRegistry,
     token_index: usize,
-    token_stream: Vec<LexItem>
+    tokens: Vec<Token>
 }
 
 impl Parser {
     pub fn new(query: String) -> Parser {
+        Parser::with_functions(query, FunctionRegistry::new())
+    }
+
+    pub fn with_functions(query: String, functions: FunctionRegistry) -> Parser {
         Parser {
-            query: query,
-            token_stream: vec!(),
+            query,
+            functions,
+            tokens: vec!(),
             token_index: 0
         }
     }
 
     fn current_token(&self) -> Option<&lexer::LexItem> {
-        if self.token_index < self.token_stream.len() {
-            Some(&self.token_stream[self.token_index])
+        if self.token_index < self.tokens.len() {
+            Some(&self.tokens[self.token_index].kind)
         } else {
             None
         }
     }
 
     fn next_token(&self) -> Option<&lexer::LexItem> {
-        if self.token_index + 1 < self.token_stream.len() {
-            Some(&self.token_stream[self.token_index + 1])
+        if self.token_index + 1 < self.tokens.len() {
+            Some(&self.tokens[self.token_index + 1].kind)
         } else {
             None
         }
@@ -81,220 +268,655 @@ impl Parser {
         self.token_index += 1;
     }
 
-    fn expect_eof(&self) -> Result<(), String> {
-        if let Some(current_token) = self.current_token() {
-            match *current_token {
-                lexer::LexItem::EOF => {
-                    Ok(())
-                },
-                _ => {
-                    Err(format!("Expected EOF, got {:?}", self.current_token()))
-                }
-            }
-        } else {
-            Err(format!("Expected EOF, got {:?}", self.current_token()))
+    fn current_span(&self) -> Span {
+        self.tokens.get(self.token_index).or(self.tokens.last()).map(|token| token.span).unwrap_or_default()
+    }
+
+    /// The span from the token at `first` to the last token consumed.
+    fn span_from(&self, first: usize) -> Span {
+        let start = self.tokens[first].span;
+        let end = self.tokens[first..self.token_index].last().map_or(start.start, |token| token.span.end);
+        Span { end, ..start }
+    }
+
+    /// An error for the current token, which is not `expected`, suggesting the
+    /// keyword it is probably a misspelling of among the ones `expected` names.
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let named: Vec<&str> = lexer::KEYWORDS.iter().cloned().filter(|keyword| expected.contains(&format!("`{}`", keyword))).collect();
+        self.unexpected_with_hint(expected, self.misspelling_hint(&named))
+    }
+
+    fn unexpected_with_hint(&self, expected: &str, hint: Option<String>) -> ParseError {
+        let span = self.current_span();
+        match self.current_token() {
+            Some(&LexItem::Eof) | None => ParseError::UnexpectedEnd { expected: expected.into(), span },
+            Some(token) => ParseError::UnexpectedToken { expected: expected.into(), found: token.to_string(), span, hint }
         }
     }
 
-    fn expect_equals(&self) -> Result<(), String> {
-        if let Some(current_token) = self.current_token() {
-            match *current_token {
-                lexer::LexItem::Equals => {
-                    Ok(())
-                },
-                _ => {
-                    Err(format!("Expected assign, got {:?}", self.current_token()))
-                }
-            }
+    /// Suggests the keyword the current token is probably a misspelling of,
+    /// among `keywords`, the ones that can come at this point.
+    fn misspelling_hint(&self, keywords: &[&str]) -> Option<String> {
+        let word = match self.current_token() {
+            Some(LexItem::Identifier(ref word)) => word.to_uppercase(),
+            _ => return None
+        };
+
+        keywords.iter()
+            .map(|keyword| (edit_distance(&word, keyword), keyword))
+            .filter(|&(distance, keyword)| distance <= keyword.len() / 3)
+            .min_by_key(|&(distance, _)| distance)
+            .map(|(_, keyword)| format!("did you mean `{}`?", keyword))
+    }
+
+    /// Suggests where the clause starting at the current token goes, if it
+    /// is one that can only come before the `clauses` already parsed.
+    fn clause_hint(&self, clauses: &[&str]) -> Option<String> {
+        let clause = match self.current_token() {
+            Some(&LexItem::Keyword(keyword)) => match keyword {
+                "WHERE" => "WHERE",
+                "GROUP" => "GROUP BY",
+                "ORDER" => "ORDER BY",
+                "LIMIT" => "LIMIT",
+                "FOLLOW" | "TAIL" => "FOLLOW",
+                _ => return None
+            },
+            _ => return None
+        };
+        if clauses.contains(&clause) {
+            Some(format!("a query can only have one `{}` clause", clause))
         } else {
-            Err("Expected assign, got EOF".into())
+            clauses.last().map(|last| format!("`{}` must come after `{}`", last, clause))
         }
     }
 
-    fn expect_identifier(&self, identifier_value: Option<&str>) -> Result<String, String> {
+    fn invalid(&self, message: String, span: Span) -> ParseError {
+        ParseError::InvalidQuery { message, span }
+    }
+
+    fn expect_eof(&self) -> Result<(), ParseError> {
         match self.current_token() {
-            Some(&lexer::LexItem::Identifier(ref identifier)) => {
-                if identifier_value.is_some() {
-                    if identifier_value.unwrap() == identifier {
-                        Ok(identifier.clone())
-                    } else {
-                        Err(format!("Expected Identifier {:?}, got {:?}", identifier_value.unwrap(), self.current_token()))
-                    }
-                } else {
-                    Ok(identifier.clone())
-                }
-            },
-            _ => { Err(format!("Expected Identifier, got {:?}", self.current_token())) }
+            Some(&lexer::LexItem::Eof) => Ok(()),
+            _ => Err(self.unexpected("the end of the query"))
         }
     }
 
-    fn expect_number(&self, expected_number: Option<usize>) -> Result<usize, String> {
+    /// Expects the end of the input or the `;` ending a statement, without consuming it.
+    fn expect_end_of_statement(&self) -> Result<(), ParseError> {
         match self.current_token() {
-            Some(&lexer::LexItem::Number(ref num)) => {
-                if expected_number.is_some() {
-                    if &expected_number.unwrap() == num {
-                        Ok(num.clone())
-                    } else {
-                        Err(format!("Expected Number {:?}, got {:?}", expected_number.unwrap(), self.current_token()))
-                    }
-                } else {
-                    Ok(num.clone())
-                }
-            },
-            _ => { Err(format!("Expected Number, got {:?}", self.current_token())) }
+            Some(&LexItem::Semicolon) => Ok(()),
+            _ => self.expect_eof()
         }
     }
 
-    fn parse_log_file_where_value(&self) -> Result<String, String> {
-        if let Some(&lexer::LexItem::Str(ref s)) = self.current_token() {
-            Ok(s.clone())
-        } else {
-            Err(format!("Expected String, got {:?}", self.current_token()))
+    fn skip_semicolons(&mut self) {
+        while let Some(&LexItem::Semicolon) = self.current_token() {
+            self.consume_token();
         }
     }
 
-    fn parse_log_file(&mut self) -> Result<ASTNode, String> {
-        let log_file_name;
-        let log_file_fields = try!(self.expect_select_field_list());
-        self.consume_token();
+    fn expect_token(&self, expected: &LexItem) -> Result<(), ParseError> {
+        match self.current_token() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(self.unexpected(&expected.to_string()))
+        }
+    }
 
-        try!(self.expect_identifier(Some("FROM")));
-        self.consume_token();
-        if let Some(&lexer::LexItem::Str(ref s)) = self.current_token() {
-            log_file_name = s.clone();
-        } else {
-            return Err(format!("Expected String, got {:?}", self.current_token()));
+    fn expect_keyword(&self, keyword: &str) -> Result<(), ParseError> {
+        match self.current_token() {
+            Some(&LexItem::Keyword(found)) if found == keyword => Ok(()),
+            _ => Err(self.unexpected(&format!("`{}`", keyword)))
         }
+    }
 
+    fn expect_field_name(&self) -> Result<String, ParseError> {
+        match self.current_token() {
+            Some(LexItem::Identifier(name)) | Some(LexItem::QuotedIdentifier(name)) => Ok(name.clone()),
+            Some(&LexItem::Keyword(_)) => Err(self.keyword_as_name("a field name")),
+            _ => Err(self.unexpected("a field name"))
+        }
+    }
+
+    /// An error for a keyword found where `expected` names a field, suggesting
+    /// to quote it unless it starts a clause, which is more likely misplaced.
+    fn keyword_as_name(&self, expected: &str) -> ParseError {
+        let hint = match self.current_token() {
+            Some(&LexItem::Keyword(keyword)) if !["EXPLAIN", "SELECT", "FROM", "WHERE", "GROUP", "ORDER", "LIMIT", "FOLLOW", "TAIL"].contains(&keyword) => {
+                let span = self.current_span();
+                Some(format!("quote keywords used as names, like {}", lexer::quote(&self.query[span.start..span.end], '"')))
+            },
+            _ => None
+        };
+        self.unexpected_with_hint(expected, hint)
+    }
+
+    fn expect_number(&self, expected_number: Option<usize>) -> Result<usize, ParseError> {
+        match self.current_token() {
+            Some(&lexer::LexItem::Number(num)) if num >= 0 => {
+                match expected_number {
+                    Some(expected) if expected != num as usize => Err(self.unexpected(&format!("`{}`", expected))),
+                    _ => Ok(num as usize)
+                }
+            },
+            _ => Err(self.unexpected("a non-negative number"))
+        }
+    }
+
+    fn parse_log_file(&mut self) -> Result<ASTNode, ParseError> {
+        let log_file_fields = self.parse_select_field_list()?;
+
+        self.expect_keyword("FROM")?;
         self.consume_token();
 
-        Ok(ASTNode::new(GrammarItem::LogFile { filename: log_file_name.into(), fields: log_file_fields }, None, None))
+        let mut log_file_names = vec!();
+        loop {
+            match self.current_token() {
+                Some(LexItem::Str(s)) => log_file_names.push(s.clone()),
+                Some(&LexItem::Keyword("STDIN")) => log_file_names.push(source::STDIN.into()),
+                Some(LexItem::QuotedIdentifier(_)) => {
+                    return Err(self.unexpected_with_hint("a file name or STDIN", Some("file names are quoted with single quotes, like 'app.log'".into())));
+                },
+                _ => return Err(self.unexpected("a file name or STDIN"))
+            }
+            self.consume_token();
+
+            if self.expect_token(&LexItem::Comma).is_ok() {
+                self.consume_token();
+            } else {
+                break;
+            }
+        }
+
+        Ok(ASTNode::new(GrammarItem::LogFile { filenames: log_file_names, fields: log_file_fields }, None, None))
     }
 
-    fn parse_condition(&mut self) -> Result<ASTNode, String> {
-        try!(self.expect_identifier(Some("WHERE")));
+    fn parse_condition(&mut self) -> Result<ASTNode, ParseError> {
+        self.expect_keyword("WHERE")?;
         self.consume_token();
-        let log_file_field = try!(self.expect_identifier(None));
+        let (log_file_field, where_comparator, log_where_clause_value) = self.parse_comparison()?;
+
+        Ok(ASTNode::new(GrammarItem::Condition { field: log_file_field, mode: where_comparator, value: log_where_clause_value }, None, None))
+    }
+
+    fn parse_comparison(&mut self) -> Result<(Expression, WhereComparator, Expression), ParseError> {
+        let left = self.parse_expression()?;
+
+        let where_comparator = match self.current_token() {
+            Some(&LexItem::Equals) => WhereComparator::StrictEquals,
+            Some(&LexItem::NotEquals) => WhereComparator::NotEquals,
+            Some(&LexItem::LessThan) => WhereComparator::LessThan,
+            Some(&LexItem::LessThanOrEquals) => WhereComparator::LessThanOrEquals,
+            Some(&LexItem::GreaterThan) => WhereComparator::GreaterThan,
+            Some(&LexItem::GreaterThanOrEquals) => WhereComparator::GreaterThanOrEquals,
+            Some(&LexItem::Keyword("LIKE")) => WhereComparator::Like,
+            _ => return Err(self.unexpected("a comparison operator or `LIKE`"))
+        };
         self.consume_token();
 
-        let where_comparator;
+        let right = self.parse_expression()?;
 
-        if let Ok(_) = self.expect_equals() {
-            where_comparator = WhereComparator::StrictEquals;
+        Ok((left, where_comparator, right))
+    }
+
+    fn parse_case(&mut self) -> Result<Expression, ParseError> {
+        self.expect_keyword("CASE")?;
+        self.consume_token();
+
+        let mut branches = vec!();
+        while self.expect_keyword("WHEN").is_ok() {
             self.consume_token();
-        } else if let Ok(_) = self.expect_identifier(Some("LIKE")) {
-            where_comparator = WhereComparator::Like;
+            let (left, mode, right) = self.parse_comparison()?;
+            self.expect_keyword("THEN")?;
             self.consume_token();
+            let result = self.parse_expression()?;
+            branches.push((Expression::Comparison { left: Box::new(left), mode, right: Box::new(right) }, result));
+        }
+
+        if branches.is_empty() {
+            return Err(self.unexpected("`WHEN`"));
+        }
+
+        let else_result = if self.expect_keyword("ELSE").is_ok() {
+            self.consume_token();
+            Some(Box::new(self.parse_expression()?))
         } else {
-            return Err(format!("Expected '=' or LIKE, got {:?}", self.current_token()))
+            None
+        };
+
+        self.expect_keyword("END")?;
+
+        Ok(Expression::Case { branches, else_result })
+    }
+
+    fn parse_group_by(&mut self) -> Result<Vec<Expression>, ParseError> {
+        self.expect_keyword("GROUP")?;
+        self.consume_token();
+        self.expect_keyword("BY")?;
+        self.consume_token();
+
+        let mut expressions = vec!(self.parse_expression()?);
+        while self.expect_token(&LexItem::Comma).is_ok() {
+            self.consume_token();
+            expressions.push(self.parse_expression()?);
         }
 
-        let log_where_clause_value = try!(self.parse_log_file_where_value());
+        Ok(expressions)
+    }
+
+    fn parse_order_by(&mut self) -> Result<Vec<SortKey>, ParseError> {
+        self.expect_keyword("ORDER")?;
+        self.consume_token();
+        self.expect_keyword("BY")?;
         self.consume_token();
 
-        Ok(ASTNode::new(GrammarItem::Condition { field: log_file_field, mode: where_comparator, value: log_where_clause_value }, None, None))
+        let mut keys = vec!();
+        loop {
+            let expression = self.parse_expression()?;
+            let descending = self.expect_keyword("DESC").is_ok();
+            if descending || self.expect_keyword("ASC").is_ok() {
+                self.consume_token();
+            }
+            keys.push(SortKey { expression, descending });
+
+            if self.expect_token(&LexItem::Comma).is_ok() {
+                self.consume_token();
+            } else {
+                break;
+            }
+        }
+
+        Ok(keys)
     }
 
-    fn parse_limit(&mut self) -> Result<ASTNode, String> {
-        try!(self.expect_identifier(Some("LIMIT")));
+    fn parse_limit(&mut self) -> Result<ASTNode, ParseError> {
+        self.expect_keyword("LIMIT")?;
         self.consume_token();
         let direction;
-        if let Ok(_) = self.expect_identifier(Some("LAST")) {
+        if matches!(self.current_token(), Some(LexItem::Identifier(ref word)) if word.eq_ignore_ascii_case("LAST")) {
             direction = LimitDirection::Last;
             self.consume_token();
         } else {
             direction = LimitDirection::First;
         }
 
-        let number_of_rows = try!(self.expect_number(None));
+        let number_of_rows = self.expect_number(None)?;
 
-        Ok(ASTNode::new(GrammarItem::Limit { number_of_rows: number_of_rows, direction: direction }, None, None))
+        Ok(ASTNode::new(GrammarItem::Limit { number_of_rows, direction }, None, None))
     }
 
-    fn expect_select_field_list(&mut self) -> Result<Vec<String>, String> {
+    fn parse_select_field_list(&mut self) -> Result<Vec<SelectField>, ParseError> {
         let mut select_fields = vec!();
 
-        while self.token_index < self.token_stream.len() {
-            match self.current_token() {
-                Some(&LexItem::Identifier(ref identifier)) => {
-                    if identifier == "FROM" {
-                        return Err("Expected Select Identifier, got keyword FROM".into());
-                    }
-                    select_fields.push(identifier.clone());
-                    if let Some(&LexItem::Identifier(ref possible_from)) = self.next_token() {
-                        if possible_from == "FROM" {
-                            break;
-                        }
-                    }
-                },
-                Some(&LexItem::Comma) => {
-                    if let Some(&LexItem::Identifier(ref possible_from)) = self.next_token() {
-                        if possible_from == "FROM" {
-                            return Err("Expected Identifier, got keyword FROM".into());
-                        }
-                    } else {
-                        return Err(format!("Expected Identifier, got {:?}", self.next_token()));
-                    }
-                },
-                _ => return Err(format!("Expected Identifier, got {:?}", self.current_token()))
+        loop {
+            select_fields.push(self.parse_select_field()?);
+
+            if self.expect_token(&LexItem::Comma).is_ok() {
+                self.consume_token();
+            } else {
+                break;
             }
+        }
 
+        Ok(select_fields)
+    }
+
+    fn parse_select_field(&mut self) -> Result<SelectField, ParseError> {
+        if self.expect_token(&LexItem::Star).is_ok() {
             self.consume_token();
+            return Ok(SelectField::new(Expression::Wildcard, None));
         }
 
-        Ok(select_fields)
+        let expression = self.parse_expression()?;
+
+        let alias = if self.expect_keyword("AS").is_ok() {
+            self.consume_token();
+            let alias = self.expect_field_name()?;
+            self.consume_token();
+            Some(alias)
+        } else {
+            None
+        };
+
+        Ok(SelectField::new(expression, alias))
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, ParseError> {
+        let mut expression = self.parse_term()?;
+
+        loop {
+            let operator = match self.current_token() {
+                Some(&LexItem::Plus) => ArithmeticOperator::Add,
+                Some(&LexItem::Minus) => ArithmeticOperator::Subtract,
+                _ => break
+            };
+            self.consume_token();
+            let right = self.parse_term()?;
+            expression = Expression::BinaryOperation { left: Box::new(expression), operator, right: Box::new(right) };
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_term(&mut self) -> Result<Expression, ParseError> {
+        let mut expression = self.parse_factor()?;
+
+        loop {
+            let operator = match self.current_token() {
+                Some(&LexItem::Star) => ArithmeticOperator::Multiply,
+                Some(&LexItem::Slash) => ArithmeticOperator::Divide,
+                _ => break
+            };
+            self.consume_token();
+            let right = self.parse_factor()?;
+            expression = Expression::BinaryOperation { left: Box::new(expression), operator, right: Box::new(right) };
+        }
+
+        Ok(expression)
+    }
+
+    fn parse_factor(&mut self) -> Result<Expression, ParseError> {
+        let expression = match self.current_token() {
+            Some(&LexItem::Minus) => {
+                self.consume_token();
+                return Ok(Expression::Negate(Box::new(self.parse_factor()?)));
+            },
+            Some(&LexItem::LeftParen) => {
+                self.consume_token();
+                let expression = self.parse_expression()?;
+                self.expect_token(&LexItem::RightParen)?;
+                expression
+            },
+            Some(&LexItem::Number(number)) => Expression::Number(number),
+            Some(&LexItem::Float(number)) => Expression::Float(number),
+            Some(LexItem::Str(s)) => Expression::Str(s.clone()),
+            Some(&LexItem::Keyword("CASE")) => self.parse_case()?,
+            Some(&LexItem::Identifier(_)) | Some(&LexItem::QuotedIdentifier(_)) => {
+                let name = self.expect_field_name()?;
+                if let Some(&LexItem::LeftParen) = self.next_token() {
+                    self.consume_token();
+                    self.consume_token();
+                    let arguments = self.parse_function_arguments()?;
+                    Expression::FunctionCall { name, arguments }
+                } else {
+                    Expression::Field(name)
+                }
+            },
+            Some(&LexItem::Keyword(_)) => return Err(self.keyword_as_name("an expression")),
+            _ => return Err(self.unexpected("an expression"))
+        };
+        self.consume_token();
+
+        Ok(expression)
+    }
+
+    fn parse_function_arguments(&mut self) -> Result<Vec<Expression>, ParseError> {
+        let mut arguments = vec!();
+
+        if self.expect_token(&LexItem::RightParen).is_ok() {
+            return Ok(arguments);
+        }
+
+        if self.expect_token(&LexItem::Star).is_ok() {
+            self.consume_token();
+            self.expect_token(&LexItem::RightParen)?;
+            arguments.push(Expression::Wildcard);
+            return Ok(arguments);
+        }
+
+        loop {
+            arguments.push(self.parse_expression()?);
+
+            if self.expect_token(&LexItem::Comma).is_ok() {
+                self.consume_token();
+            } else {
+                break;
+            }
+        }
+
+        self.expect_token(&LexItem::RightParen)?;
+
+        Ok(arguments)
+    }
+
+    fn check_types(&self, node: &ASTNode) -> Result<(), ParseError> {
+        match node.entry {
+            GrammarItem::LogFile { ref fields, .. } => {
+                for field in fields {
+                    self.functions.check_expression(&field.expression).map_err(|message| self.invalid(message, node.span))?;
+                }
+            },
+            GrammarItem::Condition { ref field, ref mode, ref value } => {
+                if self.functions.contains_aggregate(field) || self.functions.contains_aggregate(value) {
+                    return Err(self.invalid("Aggregate functions are not allowed in WHERE".into(), node.span));
+                }
+                self.functions.check_comparison(field, mode, value).map_err(|message| self.invalid(message, node.span))?;
+            },
+            GrammarItem::LogResult { ref group_by, ref order_by, .. } => {
+                for expression in group_by {
+                    if self.functions.contains_aggregate(expression) {
+                        return Err(self.invalid("Aggregate functions are not allowed in GROUP BY".into(), node.span));
+                    }
+                    self.functions.check_expression(expression).map_err(|message| self.invalid(message, node.span))?;
+                }
+                for key in order_by {
+                    if self.functions.contains_aggregate(&key.expression) {
+                        return Err(self.invalid("Aggregate functions are not allowed in ORDER BY".into(), node.span));
+                    }
+                    self.functions.check_expression(&key.expression).map_err(|message| self.invalid(message, node.span))?;
+                }
+            },
+            _ => {}
+        }
+
+        for child in node.left.iter().chain(node.right.iter()) {
+            self.check_types(child)?;
+        }
+
+        Ok(())
     }
 
-    pub fn parse(&mut self) -> Result<ASTNode, String> {
-        self.token_stream = try!(lexer::tokenize(&self.query));
+    /// Parses a single statement, which may end with `;`.
+    pub fn parse(&mut self) -> Result<ASTNode, ParseError> {
+        self.tokens = lexer::tokenize(&self.query)?;
         self.token_index = 0;
 
-        try!(self.expect_identifier(Some("SELECT")));
+        let statement = self.parse_statement()?;
+        self.skip_semicolons();
+        self.expect_eof()?;
+        Ok(statement)
+    }
+
+    /// Parses a script of statements separated by `;`, to be run one after
+    /// the other. Only the last statement can follow its files, as following
+    /// never ends.
+    pub fn parse_script(&mut self) -> Result<Vec<ASTNode>, ParseError> {
+        self.tokens = lexer::tokenize(&self.query)?;
+        self.token_index = 0;
+
+        let mut statements: Vec<ASTNode> = vec!();
+        loop {
+            self.skip_semicolons();
+            if let Some(&LexItem::Eof) = self.current_token() {
+                break;
+            }
+            if let Some(previous) = statements.last().filter(|previous| previous.is_follow()) {
+                return Err(self.invalid("FOLLOW can only be used in the last statement".into(), previous.span));
+            }
+            statements.push(self.parse_statement()?);
+        }
+
+        if statements.is_empty() {
+            return Err(self.unexpected("`SELECT`"));
+        }
+        Ok(statements)
+    }
+
+    fn parse_statement(&mut self) -> Result<ASTNode, ParseError> {
+        let statement_start = self.token_index;
+        if self.expect_keyword("EXPLAIN").is_ok() {
+            self.consume_token();
+            let analyze = self.expect_keyword("ANALYZE").is_ok();
+            if analyze {
+                self.consume_token();
+            }
+            let query_node = self.parse_query()?;
+            let mut explain_node = ASTNode::new(GrammarItem::Explain { analyze }, Some(Box::new(query_node)), None);
+            explain_node.span = self.span_from(statement_start);
+            return Ok(explain_node);
+        }
+
+        self.parse_query()
+    }
+
+    fn parse_query(&mut self) -> Result<ASTNode, ParseError> {
+        let query_start = self.token_index;
+        self.expect_keyword("SELECT")?;
         self.consume_token();
 
-        let log_file_node = try!(self.parse_log_file());
-        let condition;
-        let limit;
+        let mut log_file_node = self.parse_log_file()?;
+        log_file_node.span = self.span_from(query_start);
 
-        if let Ok(_) = self.expect_identifier(Some("WHERE".into())) {
-            condition = Some(Box::new(try!(self.parse_condition())));
+        let result_start = self.token_index;
+        let condition = if self.expect_keyword("WHERE").is_ok() {
+            let mut condition = self.parse_condition()?;
+            condition.span = self.span_from(result_start);
+            Some(Box::new(condition))
         } else {
-            condition = None;
-        }
+            None
+        };
 
-        if let Ok(_) = self.expect_identifier(Some("LIMIT".into())) {
-            limit = Some(Box::new(try!(self.parse_limit())));
+        let group_span = self.current_span();
+        let group_by = if self.expect_keyword("GROUP").is_ok() {
+            self.parse_group_by()?
+        } else {
+            vec!()
+        };
+
+        let order_span = self.current_span();
+        let order_by = if self.expect_keyword("ORDER").is_ok() {
+            self.parse_order_by()?
+        } else {
+            vec!()
+        };
+
+        let limit_start = self.token_index;
+        let limit = if self.expect_keyword("LIMIT").is_ok() {
+            let mut limit = self.parse_limit()?;
             self.consume_token();
+            limit.span = self.span_from(limit_start);
+            Some(Box::new(limit))
         } else {
-            limit = None;
+            None
+        };
+
+        let follow_span = self.current_span();
+        let follow = self.expect_keyword("FOLLOW").is_ok() || self.expect_keyword("TAIL").is_ok();
+        if follow {
+            self.consume_token();
+            if let GrammarItem::LogFile { ref fields, .. } = log_file_node.entry {
+                if fields.iter().any(|field| self.functions.contains_aggregate(&field.expression)) {
+                    return Err(self.invalid("Aggregate functions cannot be used with FOLLOW".into(), follow_span));
+                }
+            }
+            if !group_by.is_empty() {
+                return Err(self.invalid("GROUP BY cannot be used with FOLLOW".into(), follow_span));
+            }
+            if !order_by.is_empty() {
+                return Err(self.invalid("ORDER BY cannot be used with FOLLOW".into(), follow_span));
+            }
+        }
+        if let GrammarItem::LogFile { ref fields, .. } = log_file_node.entry {
+            if !order_by.is_empty() && fields.iter().any(|field| self.functions.contains_aggregate(&field.expression)) {
+                return Err(self.invalid("ORDER BY cannot be used with aggregate functions".into(), order_span));
+            }
+        }
+        if !order_by.is_empty() && !group_by.is_empty() {
+            return Err(self.invalid("ORDER BY cannot be used with GROUP BY".into(), group_span));
         }
 
-        try!(self.expect_eof());
+        if let Err(err) = self.expect_end_of_statement() {
+            let clauses: [(&str, &[&str], bool); 5] = [
+                ("WHERE", &["WHERE"], condition.is_some()),
+                ("GROUP BY", &["GROUP"], !group_by.is_empty()),
+                ("ORDER BY", &["ORDER"], !order_by.is_empty()),
+                ("LIMIT", &["LIMIT"], limit.is_some()),
+                ("FOLLOW", &["FOLLOW", "TAIL"], follow)
+            ];
+            let present: Vec<&str> = clauses.iter().filter(|&&(_, _, present)| present).map(|&(clause, _, _)| clause).collect();
+            // Only the clauses after the last one present can still come.
+            let next = clauses.iter().rposition(|&(_, _, present)| present).map_or(0, |last| last + 1);
+            let keywords: Vec<&str> = clauses[next..].iter().flat_map(|&(_, keywords, _)| keywords.iter().cloned()).collect();
+            return Err(match self.clause_hint(&present).or_else(|| self.misspelling_hint(&keywords)) {
+                Some(hint) => self.unexpected_with_hint("the end of the query", Some(hint)),
+                None => err
+            });
+        }
 
-        let log_result_node;
-        if condition.is_some() || limit.is_some() {
-            log_result_node = Some(Box::new(ASTNode::new(GrammarItem::LogResult, condition, limit)));
+        let log_result_node = if condition.is_some() || limit.is_some() || follow || !group_by.is_empty() || !order_by.is_empty() {
+            let mut log_result_node = ASTNode::new(GrammarItem::LogResult { follow, group_by, order_by }, condition, limit);
+            log_result_node.span = self.span_from(result_start);
+            Some(Box::new(log_result_node))
         } else {
-            log_result_node = None;
-        }
+            None
+        };
+
+        let mut query_node = ASTNode::new(GrammarItem::Query, Some(Box::new(log_file_node)), log_result_node);
+        query_node.span = self.span_from(query_start);
+        self.check_types(&query_node)?;
+
+        Ok(query_node)
+    }
+}
 
-        Ok(ASTNode::new(GrammarItem::Query, Some(Box::new(log_file_node)), log_result_node))
+/// `name` as written in a query: quoted unless it reads as an identifier.
+pub fn quote_identifier(name: &str) -> String {
+    if lexer::is_identifier(name) {
+        name.into()
+    } else {
+        lexer::quote(name, '"')
+    }
+}
+
+/// The number of insertions, deletions, substitutions and swaps of adjacent
+/// characters turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec!(vec!(0; b.len() + 1); a.len() + 1);
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1).min(distances[i][j - 1] + 1).min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
     }
+    distances[a.len()][b.len()]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn field(name: &str) -> SelectField {
+        SelectField::new(Expression::Field(name.into()), None)
+    }
+
     #[test]
     fn it_delivers_tree_for_simple_query() {
         let query = "SELECT title FROM 'app.log' WHERE severity = 'error'".into();
         let mut parser = Parser::new(query);
         let ast = parser.parse().unwrap();
         assert_eq!(ast.entry, GrammarItem::Query);
-        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filename: "app.log".into(), fields: vec!("title".into()) });
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: vec!(field("title")) });
         let right_node = ast.right.unwrap();
-        assert_eq!(right_node.left.unwrap().entry, GrammarItem::Condition { field: "severity".into(), mode: WhereComparator::StrictEquals, value: "error".into() });
+        assert_eq!(right_node.left.unwrap().entry, GrammarItem::Condition { field: Expression::Field("severity".into()), mode: WhereComparator::StrictEquals, value: Expression::Str("error".into()) });
     }
 
     #[test]
@@ -303,9 +925,9 @@ mod tests {
         let mut parser = Parser::new(query);
         let ast = parser.parse().unwrap();
         assert_eq!(ast.entry, GrammarItem::Query);
-        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filename: "app.log".into(), fields: vec!("title".into(), "severity".into(), "date".into()) });
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: vec!(field("title"), field("severity"), field("date")) });
         let right_node = ast.right.unwrap();
-        assert_eq!(right_node.left.unwrap().entry, GrammarItem::Condition { field: "severity".into(), mode: WhereComparator::StrictEquals, value: "error".into() });
+        assert_eq!(right_node.left.unwrap().entry, GrammarItem::Condition { field: Expression::Field("severity".into()), mode: WhereComparator::StrictEquals, value: Expression::Str("error".into()) });
     }
 
     #[test]
@@ -397,7 +1019,7 @@ mod tests {
         let left_result_node = &right_node.left.unwrap();
         let right_result_node = &right_node.right.unwrap();
 
-        assert_eq!(left_result_node.entry, GrammarItem::Condition { field: "title".into(), mode: WhereComparator::StrictEquals, value: "Network connection failed".into() });
+        assert_eq!(left_result_node.entry, GrammarItem::Condition { field: Expression::Field("title".into()), mode: WhereComparator::StrictEquals, value: Expression::Str("Network connection failed".into()) });
         assert_eq!(right_result_node.entry, GrammarItem::Limit { number_of_rows: 10, direction: LimitDirection::Last });
     }
 
@@ -426,7 +1048,7 @@ mod tests {
         let right_node = *ast.right.unwrap().clone();
         let conditional_node = &right_node.left.unwrap();
 
-        assert_eq!(conditional_node.entry, GrammarItem::Condition { field: "title".into(), mode: WhereComparator::Like, value: "dies, das".into() });
+        assert_eq!(conditional_node.entry, GrammarItem::Condition { field: Expression::Field("title".into()), mode: WhereComparator::Like, value: Expression::Str("dies, das".into()) });
     }
 
     #[test]
@@ -450,12 +1072,455 @@ mod tests {
     }
 
     #[test]
-    fn it_fails_when_where_does_have_like_in_lower_case() {
-        let query = "SELECT title, severity FROM 'app.log' WHERE title like 'dies, das'".into();
+    fn it_returns_ast_for_keywords_in_lower_case() {
+        let query = "select title, severity from 'app.log' where title like 'dies, das' limit last 3".into();
 
         let mut parser = Parser::new(query);
-        let ast = parser.parse();
+        let ast = parser.parse().unwrap();
 
-        assert!(ast.is_err());
+        let log_result = ast.right.unwrap();
+        assert_eq!(log_result.left.unwrap().entry, GrammarItem::Condition {
+            field: Expression::Field("title".into()),
+            mode: WhereComparator::Like,
+            value: Expression::Str("dies, das".into())
+        });
+        assert_eq!(log_result.right.unwrap().entry, GrammarItem::Limit { number_of_rows: 3, direction: LimitDirection::Last });
+    }
+
+    #[test]
+    fn it_returns_ast_for_select_with_wildcard() {
+        let query = "SELECT * FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: vec!(SelectField::new(Expression::Wildcard, None)) });
+    }
+
+    #[test]
+    fn it_returns_ast_for_select_fields_with_aliases_and_computed_expressions() {
+        let query = "SELECT upper(severity) AS sev, latency / 1000.0 AS seconds, date FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+
+        let expected_fields = vec!(
+            SelectField::new(Expression::FunctionCall { name: "upper".into(), arguments: vec!(Expression::Field("severity".into())) }, Some("sev".into())),
+            SelectField::new(Expression::BinaryOperation {
+                left: Box::new(Expression::Field("latency".into())),
+                operator: ArithmeticOperator::Divide,
+                right: Box::new(Expression::Float(1000.0))
+            }, Some("seconds".into())),
+            field("date")
+        );
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: expected_fields });
+    }
+
+    #[test]
+    fn it_respects_operator_precedence_and_parentheses() {
+        let query = "SELECT a + b * 2, (a + b) * 2 FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+
+        let sum = |left: Expression, right: Expression| Expression::BinaryOperation { left: Box::new(left), operator: ArithmeticOperator::Add, right: Box::new(right) };
+        let product = |left: Expression, right: Expression| Expression::BinaryOperation { left: Box::new(left), operator: ArithmeticOperator::Multiply, right: Box::new(right) };
+        let a = || Expression::Field("a".into());
+        let b = || Expression::Field("b".into());
+
+        let expected_fields = vec!(
+            SelectField::new(sum(a(), product(b(), Expression::Number(2))), None),
+            SelectField::new(product(sum(a(), b()), Expression::Number(2)), None)
+        );
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: expected_fields });
+    }
+
+    #[test]
+    fn it_fails_when_alias_is_missing_after_as() {
+        let query = "SELECT title AS FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_fails_when_parenthesis_is_not_closed() {
+        let query = "SELECT upper(title FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_returns_ast_for_where_clause_with_function_call() {
+        let query = "SELECT title FROM 'app.log' WHERE lower(severity) = 'error'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+        let right_node = ast.right.unwrap();
+        let field = Expression::FunctionCall { name: "lower".into(), arguments: vec!(Expression::Field("severity".into())) };
+        assert_eq!(right_node.left.unwrap().entry, GrammarItem::Condition { field, mode: WhereComparator::StrictEquals, value: Expression::Str("error".into()) });
+    }
+
+    #[test]
+    fn it_fails_when_function_is_unknown() {
+        let query = "SELECT shout(title) FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_fails_when_function_is_called_with_wrong_arity() {
+        let query = "SELECT title FROM 'app.log' WHERE substr(title) = 'Net'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_fails_when_function_is_called_with_wrong_argument_type() {
+        let query = "SELECT round('fast') FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_returns_ast_for_function_names_with_underscores() {
+        let query = "SELECT split_part(path, '/', 2) FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_returns_ast_for_count_wildcard() {
+        let query = "SELECT count(*) AS total FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+        let count = Expression::FunctionCall { name: "count".into(), arguments: vec!(Expression::Wildcard) };
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: vec!(SelectField::new(count, Some("total".into()))) });
+    }
+
+    #[test]
+    fn it_fails_when_where_clause_uses_an_aggregate() {
+        let query = "SELECT title FROM 'app.log' WHERE max(title) = 'x'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_returns_ast_for_where_clause_with_numeric_comparison() {
+        let query = "SELECT title FROM 'app.log' WHERE status >= 500".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+        let right_node = ast.right.unwrap();
+        assert_eq!(right_node.left.unwrap().entry, GrammarItem::Condition { field: Expression::Field("status".into()), mode: WhereComparator::GreaterThanOrEquals, value: Expression::Number(500) });
+    }
+
+    #[test]
+    fn it_returns_ast_for_case_expression() {
+        let query = "SELECT CASE WHEN status >= 500 THEN 'server' WHEN status >= 400 THEN 'client' ELSE 'ok' END AS category FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+
+        let status_at_least = |number| Expression::Comparison {
+            left: Box::new(Expression::Field("status".into())),
+            mode: WhereComparator::GreaterThanOrEquals,
+            right: Box::new(Expression::Number(number))
+        };
+        let case = Expression::Case {
+            branches: vec!(
+                (status_at_least(500), Expression::Str("server".into())),
+                (status_at_least(400), Expression::Str("client".into()))
+            ),
+            else_result: Some(Box::new(Expression::Str("ok".into())))
+        };
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("app.log".into()), fields: vec!(SelectField::new(case, Some("category".into()))) });
+    }
+
+    #[test]
+    fn it_returns_ast_for_case_expression_inside_function_and_where_clause() {
+        let query = "SELECT upper(CASE WHEN severity LIKE 'warn' THEN 'w' END) FROM 'app.log' WHERE CASE WHEN latency > 100 THEN 'slow' ELSE 'fast' END = 'slow'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_ok());
+
+        let ast = Parser::new("SELECT CASE WHEN x > 1 THEN 2 ELSE 3 END -1 FROM 'app.log'".into()).parse().unwrap();
+        let fields = match ast.left.unwrap().entry {
+            GrammarItem::LogFile { fields, .. } => fields,
+            _ => panic!("Expected LogFile")
+        };
+        assert!(matches!(fields[0].expression, Expression::BinaryOperation { operator: ArithmeticOperator::Subtract, .. }));
+        assert_eq!(fields.len(), 1);
+    }
+
+    #[test]
+    fn it_fails_when_case_has_no_when_branch() {
+        let query = "SELECT CASE ELSE 'ok' END FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_fails_when_case_is_not_terminated_with_end() {
+        let query = "SELECT CASE WHEN status >= 500 THEN 'server' FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_fails_when_case_branches_have_incompatible_types() {
+        let query = "SELECT CASE WHEN status >= 500 THEN 'server' ELSE 1 END FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_returns_ast_for_multiple_sources() {
+        let query = "SELECT title, _file, _line FROM 'logs/app-*.log', 'b.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("logs/app-*.log".into(), "b.log".into()), fields: vec!(field("title"), field("_file"), field("_line")) });
+    }
+
+    #[test]
+    fn it_returns_ast_for_stdin_source() {
+        for query in &["SELECT msg FROM STDIN", "SELECT msg FROM '-'"] {
+            let ast = Parser::new(query.to_string()).parse().unwrap();
+            assert_eq!(ast.left.unwrap().entry, GrammarItem::LogFile { filenames: vec!("-".into()), fields: vec!(field("msg")) });
+        }
+    }
+
+    #[test]
+    fn it_fails_when_source_list_ends_with_comma() {
+        let query = "SELECT title FROM 'a.log', WHERE title = 'x'".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_returns_ast_for_follow_queries() {
+        let ast = Parser::new("SELECT msg FROM 'app.log' WHERE level = 'error' LIMIT LAST 10 FOLLOW".into()).parse().unwrap();
+        assert!(ast.is_follow());
+        let right_node = ast.right.unwrap();
+        assert_eq!(right_node.entry, GrammarItem::LogResult { follow: true, group_by: vec!(), order_by: vec!() });
+        assert_eq!(right_node.right.unwrap().entry, GrammarItem::Limit { number_of_rows: 10, direction: LimitDirection::Last });
+
+        assert!(Parser::new("SELECT msg FROM 'app.log' TAIL".into()).parse().unwrap().is_follow());
+        assert!(!Parser::new("SELECT msg FROM 'app.log' LIMIT 1".into()).parse().unwrap().is_follow());
+    }
+
+    #[test]
+    fn it_fails_when_following_aggregates() {
+        let query = "SELECT count(*) FROM 'app.log' FOLLOW".into();
+        let mut parser = Parser::new(query);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn it_formats_expressions_back_into_query_syntax() {
+        let query = "SELECT upper(severity), (a + b) * 2, a - (b - c), -(a + 1), CASE WHEN status >= 500 THEN 'server' ELSE 'ok' END FROM 'app.log'".into();
+        let mut parser = Parser::new(query);
+        let ast = parser.parse().unwrap();
+        let fields = match ast.left.unwrap().entry {
+            GrammarItem::LogFile { fields, .. } => fields,
+            _ => panic!("Expected LogFile")
+        };
+        let formatted: Vec<String> = fields.iter().map(|field| field.expression.to_string()).collect();
+        assert_eq!(formatted, vec!("upper(severity)", "(a + b) * 2", "a - (b - c)", "-(a + 1)", "CASE WHEN status >= 500 THEN 'server' ELSE 'ok' END"));
+    }
+
+    #[test]
+    fn it_returns_ast_for_order_by() {
+        let ast = Parser::new("SELECT n FROM 'app.log' WHERE level = 'error' ORDER BY latency DESC, n ASC, ts LIMIT 3".into()).parse().unwrap();
+        let right_node = ast.right.unwrap();
+        assert_eq!(right_node.entry, GrammarItem::LogResult { follow: false, group_by: vec!(), order_by: vec!(
+            SortKey { expression: Expression::Field("latency".into()), descending: true },
+            SortKey { expression: Expression::Field("n".into()), descending: false },
+            SortKey { expression: Expression::Field("ts".into()), descending: false }
+        ) });
+        assert_eq!(right_node.right.unwrap().entry, GrammarItem::Limit { number_of_rows: 3, direction: LimitDirection::First });
+    }
+
+    #[test]
+    fn it_fails_when_order_by_is_misplaced_or_combined_with_aggregates() {
+        for query in &[
+            "SELECT n FROM 'app.log' ORDER n",
+            "SELECT n FROM 'app.log' ORDER BY",
+            "SELECT n FROM 'app.log' LIMIT 3 ORDER BY n",
+            "SELECT n FROM 'app.log' ORDER BY n FOLLOW",
+            "SELECT count(*) FROM 'app.log' ORDER BY n",
+            "SELECT n FROM 'app.log' ORDER BY count(*)"
+        ] {
+            assert!(Parser::new(query.to_string()).parse().is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn it_returns_ast_for_group_by() {
+        let ast = Parser::new("SELECT level, count(*) FROM 'app.log' WHERE n > 1 GROUP BY level, status / 100 LIMIT 3".into()).parse().unwrap();
+        let right_node = ast.right.unwrap();
+        assert_eq!(right_node.entry, GrammarItem::LogResult { follow: false, group_by: vec!(
+            Expression::Field("level".into()),
+            Expression::BinaryOperation {
+                left: Box::new(Expression::Field("status".into())),
+                operator: ArithmeticOperator::Divide,
+                right: Box::new(Expression::Number(100))
+            }
+        ), order_by: vec!() });
+        assert_eq!(right_node.right.unwrap().entry, GrammarItem::Limit { number_of_rows: 3, direction: LimitDirection::First });
+    }
+
+    #[test]
+    fn it_fails_when_group_by_is_misplaced_or_aggregated() {
+        for query in &[
+            "SELECT level FROM 'app.log' GROUP level",
+            "SELECT level FROM 'app.log' GROUP BY",
+            "SELECT level FROM 'app.log' LIMIT 3 GROUP BY level",
+            "SELECT level FROM 'app.log' GROUP BY level FOLLOW",
+            "SELECT level FROM 'app.log' GROUP BY level ORDER BY level",
+            "SELECT level FROM 'app.log' GROUP BY count(*)"
+        ] {
+            assert!(Parser::new(query.to_string()).parse().is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn it_wraps_explained_queries() {
+        let ast = Parser::new("EXPLAIN ANALYZE SELECT n FROM 'app.log' LIMIT 3".into()).parse().unwrap();
+        assert!(ast.is_explain());
+        assert_eq!(ast.entry, GrammarItem::Explain { analyze: true });
+        assert_eq!(ast.left.unwrap().entry, GrammarItem::Query);
+
+        let ast = Parser::new("EXPLAIN SELECT n FROM 'app.log' FOLLOW".into()).parse().unwrap();
+        assert_eq!(ast.entry, GrammarItem::Explain { analyze: false });
+        assert!(!ast.is_follow() && ast.left.unwrap().is_follow());
+
+        assert!(Parser::new("EXPLAIN".into()).parse().is_err());
+        assert!(Parser::new("EXPLAIN EXPLAIN SELECT n FROM 'app.log'".into()).parse().is_err());
+        assert!(Parser::new("SELECT n FROM 'app.log' EXPLAIN".into()).parse().is_err());
+    }
+
+    #[test]
+    fn it_reports_typed_errors_with_the_span_at_fault() {
+        let err = Parser::new("SELECT a WHERE a = 1".into()).parse().unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedToken {
+            expected: "`FROM`".into(), found: "`WHERE`".into(), span: Span { start: 9, end: 14, line: 1, column: 10 }, hint: None
+        });
+        assert_eq!(err.to_string(), "Expected `FROM`, got `WHERE` at line 1, column 10");
+
+        let err = Parser::new("SELECT a FROM 'app.log' WHERE".into()).parse().unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedEnd { expected: "an expression".into(), span: Span { start: 29, end: 29, line: 1, column: 30 } });
+        assert_eq!(err.to_string(), "Expected an expression, got the end of the query");
+
+        let err = Parser::new("SELECT a FROM 'app.log' ?".into()).parse().unwrap_err();
+        assert_eq!(err.span(), Span { start: 24, end: 25, line: 1, column: 25 });
+
+        let err = Parser::new("SELECT count(*) FROM 'app.log' FOLLOW".into()).parse().unwrap_err();
+        assert_eq!(err, ParseError::InvalidQuery {
+            message: "Aggregate functions cannot be used with FOLLOW".into(), span: Span { start: 31, end: 37, line: 1, column: 32 }
+        });
+
+        let err = Parser::new("SELECT a FROM 'app.log' WHERE count(*) = 1".into()).parse().unwrap_err();
+        assert_eq!(err.span(), Span { start: 24, end: 42, line: 1, column: 25 });
+        assert_eq!(String::from(err), "Aggregate functions are not allowed in WHERE");
+    }
+
+    #[test]
+    fn it_hints_at_misspelled_keywords_and_misplaced_clauses() {
+        let hint = |query: &str| Parser::new(query.into()).parse().unwrap_err().hint().map(String::from);
+        assert_eq!(hint("SELECT a FORM 'app.log'"), Some("did you mean `FROM`?".into()));
+        assert_eq!(hint("SELECT a FROM 'app.log' WHERE a LIK 'x%'"), Some("did you mean `LIKE`?".into()));
+        assert_eq!(hint("SELECT desc FROM 'app.log'"), Some("quote keywords used as names, like \"desc\"".into()));
+        assert_eq!(hint("SELECT a AS End FROM 'app.log'"), Some("quote keywords used as names, like \"End\"".into()));
+        assert_eq!(hint("SELECT FROM 'app.log'"), None);
+        assert_eq!(hint("SELECT a FROM 'app.log' WHRE a = 1"), Some("did you mean `WHERE`?".into()));
+        assert_eq!(hint("SELECT a FROM 'app.log' LIMIT 3 WHERE a = 1"), Some("`LIMIT` must come after `WHERE`".into()));
+        assert_eq!(hint("SELECT a FROM 'app.log' WHERE a = 1 WHERE a = 2"), Some("a query can only have one `WHERE` clause".into()));
+        assert_eq!(hint("SELECT a FROM \"app.log\""), Some("file names are quoted with single quotes, like 'app.log'".into()));
+        assert_eq!(hint("SELECT a FROM 'app.log' WHERE a = b c"), None);
+        assert_eq!(hint("SELECT a FROM 'app.log' WHERE a = 1 AND b = 2"), None);
+        assert_eq!(hint("SELECT a FROM 'app.log' WHERE a = 1 LIMT 3"), Some("did you mean `LIMIT`?".into()));
+        assert_eq!(hint("SELECT a FROM 'app.log' LIMIT 3 FOLOW"), Some("did you mean `FOLLOW`?".into()));
+        assert_eq!(hint("SELECT a FROM 'app.log' LIMIT 3 WHRE a = 1"), None);
+        assert_eq!(edit_distance("FORM", "FROM"), 1);
+        assert_eq!(edit_distance("", "AS"), 2);
+    }
+
+    #[test]
+    fn it_renders_errors_with_the_span_underlined() {
+        let query = "SELECT a FROM 'app.log' LIMIT 3 WHERE a = 1";
+        let err = Parser::new(query.into()).parse().unwrap_err();
+        assert_eq!(err.render(query), [
+            "Expected the end of the query, got `WHERE` at line 1, column 33",
+            "  |",
+            "1 | SELECT a FROM 'app.log' LIMIT 3 WHERE a = 1",
+            "  |                                 ^^^^^",
+            "  = hint: `LIMIT` must come after `WHERE`"
+        ].join("\n"));
+
+        let query = "SELECT a FROM 'app.log' WHERE";
+        let err = Parser::new(query.into()).parse().unwrap_err();
+        assert_eq!(err.render(query).lines().last(), Some("  |                              ^"));
+
+        let query = "SELECT a -- the field\r\nFROM 'app.log'\r\n\tLIMIT x";
+        let err = Parser::new(query.into()).parse().unwrap_err();
+        assert_eq!(err.render(query), [
+            "Expected a non-negative number, got `x` at line 3, column 8",
+            "  |",
+            "3 | \tLIMIT x",
+            "  | \t      ^"
+        ].join("\n"));
+    }
+
+    #[test]
+    fn it_keeps_the_span_of_each_node() {
+        let query = "EXPLAIN SELECT a, b FROM 'app.log' WHERE a = 'x' ORDER BY b LIMIT 3";
+        let text = |node: &ASTNode| query[node.span.start..node.span.end].to_string();
+        let explain = Parser::new(query.into()).parse().unwrap();
+        assert_eq!(text(&explain), query);
+
+        let query_node = explain.left.unwrap();
+        assert_eq!(text(&query_node), &query[8..]);
+        assert_eq!(text(query_node.left.as_ref().unwrap()), "SELECT a, b FROM 'app.log'");
+        let log_result = query_node.right.unwrap();
+        assert_eq!(text(&log_result), "WHERE a = 'x' ORDER BY b LIMIT 3");
+        assert_eq!(text(log_result.left.as_ref().unwrap()), "WHERE a = 'x'");
+        assert_eq!(text(log_result.right.as_ref().unwrap()), "LIMIT 3");
+        assert_eq!(log_result.right.unwrap().span.column, 61);
+    }
+
+    #[test]
+    fn it_returns_ast_for_quoted_field_names_and_escaped_strings() {
+        let ast = Parser::new(r#"SELECT "user id" AS "FROM" FROM 'app.log' WHERE "request-id" = 'it''s'"#.into()).parse().unwrap();
+        match ast.left.unwrap().entry {
+            GrammarItem::LogFile { ref fields, .. } => {
+                assert_eq!(fields, &vec!(SelectField::new(Expression::Field("user id".into()), Some("FROM".into()))));
+            },
+            ref entry => panic!("unexpected {:?}", entry)
+        }
+        assert_eq!(ast.right.unwrap().left.unwrap().entry, GrammarItem::Condition {
+            field: Expression::Field("request-id".into()),
+            mode: WhereComparator::StrictEquals,
+            value: Expression::Str("it's".into())
+        });
+
+        let expression = Expression::Comparison {
+            left: Box::new(Expression::Field("request-id".into())),
+            mode: WhereComparator::StrictEquals,
+            right: Box::new(Expression::Str("it's".into()))
+        };
+        assert_eq!(expression.to_string(), r#""request-id" = 'it''s'"#);
+        assert_eq!(quote_identifier("level"), "level");
+        assert_eq!(quote_identifier("LIMIT"), "\"LIMIT\"");
+    }
+
+    #[test]
+    fn it_returns_asts_for_scripts_of_several_statements() {
+        let script = "SELECT a FROM 'app.log';\n-- then\nEXPLAIN SELECT b FROM 'app.log' LIMIT 1;;\nSELECT c FROM 'app.log' FOLLOW;";
+        let statements = Parser::new(script.into()).parse_script().unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(&script[statements[1].span.start..statements[1].span.end], "EXPLAIN SELECT b FROM 'app.log' LIMIT 1");
+        assert!(statements[1].is_explain() && statements[2].is_follow());
+
+        assert!(Parser::new("SELECT a FROM 'app.log';".into()).parse().is_ok());
+        assert!(Parser::new("SELECT a FROM 'app.log'; SELECT b FROM 'app.log'".into()).parse().is_err());
+        assert!(Parser::new(" ; -- nothing".into()).parse_script().is_err());
+        assert!(Parser::new("SELECT a FROM 'app.log' SELECT b FROM 'app.log'".into()).parse_script().is_err());
+
+        let err = Parser::new("SELECT a FROM 'app.log' FOLLOW; SELECT b FROM 'app.log'".into()).parse_script().unwrap_err();
+        assert_eq!(err, ParseError::InvalidQuery {
+            message: "FOLLOW can only be used in the last statement".into(), span: Span { start: 0, end: 30, line: 1, column: 1 }
+        });
+    }
+}
@@ -0,0 +1,451 @@
+//! Turns a parsed query into the steps that run it.
+//!
+//! The logical plan states what a query computes as a chain of stages, from
+//! scanning the logs to limiting the result. The physical plan decides how
+//! to compute it: how the files are read, which work is pushed down into
+//! reading them and how the stages are combined.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use ast::Query;
+use functions::FunctionRegistry;
+use index::Index;
+use optimizer::Prefilter;
+use parser::{self, ASTNode, Expression, LimitDirection, SelectField, SortKey, WhereComparator};
+use record::LINE_FIELD;
+use source::{self, Compression};
+
+/// Files are only scanned in parallel if every thread gets at least this many bytes.
+pub const PARALLEL_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// The comparison of a `WHERE` clause.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Condition {
+    pub field: Expression,
+    pub mode: WhereComparator,
+    pub value: Expression
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.field, self.mode, self.value)
+    }
+}
+
+/// What a query computes, as stages each reading the rows of its `input`.
+///
+/// Records are scanned, filtered, then either aggregated, one row per group
+/// of `GROUP BY`, or sorted and projected, and the result is limited: `LIMIT`
+/// applies to rows in the order of `ORDER BY`.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub enum LogicalPlan {
+    Scan { sources: Vec<String>, follow: bool },
+    Filter { input: Box<LogicalPlan>, condition: Condition },
+    Aggregate { input: Box<LogicalPlan>, group_by: Vec<Expression>, fields: Vec<SelectField> },
+    Sort { input: Box<LogicalPlan>, keys: Vec<SortKey> },
+    Project { input: Box<LogicalPlan>, fields: Vec<SelectField> },
+    Limit { input: Box<LogicalPlan>, rows: usize, direction: LimitDirection }
+}
+
+impl LogicalPlan {
+    pub fn from_ast(ast: &ASTNode, functions: &FunctionRegistry) -> Result<LogicalPlan, String> {
+        LogicalPlan::from_query(&Query::try_from(ast)?, functions)
+    }
+
+    pub fn from_query(query: &Query, functions: &FunctionRegistry) -> Result<LogicalPlan, String> {
+        let (fields, order_by) = (&query.select, &query.order_by);
+
+        let mut plan = LogicalPlan::Scan { sources: query.from.clone(), follow: query.follow };
+        if let Some(ref clause) = query.where_clause {
+            let condition = Condition { field: clause.field.clone(), mode: clause.mode.clone(), value: clause.value.clone() };
+            plan = LogicalPlan::Filter { input: Box::new(plan), condition };
+        }
+        if !query.group_by.is_empty() || fields.iter().any(|field| functions.contains_aggregate(&field.expression)) {
+            if !order_by.is_empty() {
+                return Err("ORDER BY cannot be used with aggregate functions".into());
+            }
+            let group_by = resolve_group_by(query, functions)?;
+            plan = LogicalPlan::Aggregate { input: Box::new(plan), group_by, fields: fields.clone() };
+        } else {
+            if !order_by.is_empty() {
+                plan = LogicalPlan::Sort { input: Box::new(plan), keys: order_by.clone() };
+            }
+            plan = LogicalPlan::Project { input: Box::new(plan), fields: fields.clone() };
+        }
+        if let Some(ref limit) = query.limit {
+            plan = LogicalPlan::Limit { input: Box::new(plan), rows: limit.rows, direction: limit.direction.clone() };
+        }
+
+        Ok(plan)
+    }
+
+    /// The stage whose rows `self` reads, `None` for the scan.
+    pub fn input(&self) -> Option<&LogicalPlan> {
+        match *self {
+            LogicalPlan::Scan { .. } => None,
+            LogicalPlan::Filter { ref input, .. } | LogicalPlan::Aggregate { ref input, .. } | LogicalPlan::Sort { ref input, .. } |
+            LogicalPlan::Project { ref input, .. } | LogicalPlan::Limit { ref input, .. } => Some(input)
+        }
+    }
+}
+
+/// The expressions of `GROUP BY`, where a name given to a selected field with
+/// `AS` stands for the expression of that field.
+fn resolve_group_by(query: &Query, functions: &FunctionRegistry) -> Result<Vec<Expression>, String> {
+    let mut group_by = vec!();
+    for expression in &query.group_by {
+        let selected = match *expression {
+            Expression::Field(ref name) => query.select.iter().find(|field| field.alias.as_ref() == Some(name)),
+            _ => None
+        };
+        match selected {
+            Some(field) if functions.contains_aggregate(&field.expression) => {
+                return Err(format!("{} cannot be used in GROUP BY, it is computed from aggregate functions", expression));
+            },
+            Some(field) => group_by.push(field.expression.clone()),
+            None => group_by.push(expression.clone())
+        }
+    }
+    Ok(group_by)
+}
+
+/// Renders the stages one per line, each indented below the stage reading its rows.
+impl fmt::Display for LogicalPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut stage = Some(self);
+        let mut depth = 0;
+        while let Some(plan) = stage {
+            if depth > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:width$}", "", width = depth * 2)?;
+            match *plan {
+                LogicalPlan::Scan { ref sources, follow } => {
+                    write!(f, "Scan {}{}", describe_sources(sources), if follow { " (follow)" } else { "" })?;
+                },
+                LogicalPlan::Filter { ref condition, .. } => write!(f, "Filter {}", condition)?,
+                LogicalPlan::Aggregate { ref group_by, ref fields, .. } => {
+                    write!(f, "Aggregate {}", describe_fields(fields))?;
+                    if !group_by.is_empty() {
+                        let keys: Vec<String> = group_by.iter().map(|key| key.to_string()).collect();
+                        write!(f, " by {}", keys.join(", "))?;
+                    }
+                },
+                LogicalPlan::Sort { ref keys, .. } => {
+                    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                    write!(f, "Sort {}", keys.join(", "))?;
+                },
+                LogicalPlan::Project { ref fields, .. } => write!(f, "Project {}", describe_fields(fields))?,
+                LogicalPlan::Limit { rows, ref direction, .. } => {
+                    write!(f, "Limit {}{}", if *direction == LimitDirection::Last { "last " } else { "" }, rows)?;
+                }
+            }
+            stage = plan.input();
+            depth += 1;
+        }
+        Ok(())
+    }
+}
+
+fn describe_sources(sources: &[String]) -> String {
+    let sources: Vec<String> = sources.iter()
+        .map(|source| if source == source::STDIN { "STDIN".into() } else { format!("'{}'", source) })
+        .collect();
+    sources.join(", ")
+}
+
+fn describe_fields(fields: &[SelectField]) -> String {
+    let fields: Vec<String> = fields.iter()
+        .map(|field| match field.alias {
+            Some(ref alias) => format!("{} AS {}", field.expression, parser::quote_identifier(alias)),
+            None => field.expression.to_string()
+        })
+        .collect();
+    fields.join(", ")
+}
+
+/// How the records of the files are read.
+pub enum Access {
+    /// From the end of uncompressed files, for `LIMIT LAST` queries whose
+    /// result does not depend on reading the earlier records.
+    Backwards,
+    /// From the columns the files were converted into, see `Cache`.
+    Cached,
+    /// Only the blocks of a mapped file its index does not rule out, on up to `threads` threads.
+    Indexed { index: Index, threads: usize },
+    /// A single uncompressed file mapped into memory, in parts scanned on `threads` threads.
+    Mapped { threads: usize },
+    /// Every file from the start, decompressing and merging them by timestamp as needed.
+    Sequential
+}
+
+/// `ORDER BY` keys and, when the sorted rows are limited, how many of them to keep.
+pub struct Sort {
+    pub keys: Vec<SortKey>,
+    /// Only the first or last `n` rows are kept while sorting rather than all of them.
+    pub top: Option<(usize, LimitDirection)>
+}
+
+/// How a `LogicalPlan` is run against the files it reads.
+pub struct PhysicalPlan {
+    pub sources: Vec<String>,
+    pub access: Access,
+    pub condition: Option<Condition>,
+    /// Literals records need to contain to match `condition`, checked in
+    /// their text before parsing them when it is at hand.
+    pub prefilter: Option<Prefilter>,
+    /// The fields the query reads, or `None` if it selects all of them.
+    pub columns: Option<Vec<String>>,
+    pub fields: Vec<SelectField>,
+    /// Whether `fields` are computed from aggregates over all matching records,
+    /// or over those of each group of `group_by`.
+    pub aggregate: bool,
+    pub group_by: Vec<Expression>,
+    pub sort: Option<Sort>,
+    pub limit: Option<(usize, LimitDirection)>,
+    /// Whether new records are read as they are appended, in which case
+    /// `access` tells how the existing records are read.
+    pub follow: bool
+}
+
+impl PhysicalPlan {
+    /// Chooses how to run `plan`, looking at the files it reads. `cached` tells
+    /// whether the files can be read from a cache of columns.
+    pub fn new(plan: &LogicalPlan, cached: bool) -> Result<PhysicalPlan, String> {
+        let mut physical = PhysicalPlan {
+            sources: vec!(),
+            access: Access::Sequential,
+            condition: None,
+            prefilter: None,
+            columns: None,
+            fields: vec!(),
+            aggregate: false,
+            group_by: vec!(),
+            sort: None,
+            limit: None,
+            follow: false
+        };
+
+        let mut stage = Some(plan);
+        while let Some(plan) = stage {
+            match *plan {
+                LogicalPlan::Scan { ref sources, follow } => {
+                    physical.sources = sources.clone();
+                    physical.follow = follow;
+                },
+                LogicalPlan::Filter { ref condition, .. } => physical.condition = Some(condition.clone()),
+                LogicalPlan::Aggregate { ref group_by, ref fields, .. } => {
+                    physical.fields = fields.clone();
+                    physical.aggregate = true;
+                    physical.group_by = group_by.clone();
+                },
+                LogicalPlan::Sort { ref keys, .. } => physical.sort = Some(Sort { keys: keys.clone(), top: None }),
+                LogicalPlan::Project { ref fields, .. } => physical.fields = fields.clone(),
+                LogicalPlan::Limit { rows, ref direction, .. } => physical.limit = Some((rows, direction.clone()))
+            }
+            stage = plan.input();
+        }
+
+        // A limit right after sorting bounds the rows the sort has to keep.
+        if let Some(sort) = physical.sort.as_mut() {
+            sort.top = physical.limit.clone();
+        }
+        physical.columns = physical.read_columns();
+        physical.access = physical.choose_access(cached)?;
+        // Only mapped files keep the text of records to look for literals in before parsing.
+        if let Access::Indexed { .. } | Access::Mapped { .. } = physical.access {
+            physical.prefilter = physical.condition.as_ref()
+                .and_then(|condition| Prefilter::from_condition(&condition.field, &condition.mode, &condition.value));
+        }
+
+        Ok(physical)
+    }
+
+    pub fn condition(&self) -> Option<(&Expression, &WhereComparator, &Expression)> {
+        self.condition.as_ref().map(|condition| (&condition.field, &condition.mode, &condition.value))
+    }
+
+    fn read_columns(&self) -> Option<Vec<String>> {
+        if self.fields.iter().any(|field| field.expression == Expression::Wildcard) {
+            None
+        } else {
+            Some(self.referenced_fields())
+        }
+    }
+
+    /// The fields named anywhere in the query, which does not include those selected by `*`.
+    fn referenced_fields(&self) -> Vec<String> {
+        let mut fields = vec!();
+        let expressions = self.fields.iter().map(|field| &field.expression)
+            .chain(self.group_by.iter())
+            .chain(self.condition.iter().flat_map(|condition| vec!(&condition.field, &condition.value)))
+            .chain(self.sort.iter().flat_map(|sort| sort.keys.iter().map(|key| &key.expression)));
+        for expression in expressions {
+            collect_fields(expression, &mut fields);
+        }
+        fields
+    }
+
+    fn choose_access(&self, cached: bool) -> Result<Access, String> {
+        let paths = source::resolve(&self.sources)?;
+        let stdin = paths.iter().any(|path| path == Path::new(source::STDIN));
+        let mut compressed = false;
+        for path in paths.iter().filter(|path| *path != Path::new(source::STDIN)) {
+            compressed |= source::detect_compression(path)? != Compression::None;
+        }
+
+        if let Some((_, LimitDirection::Last)) = self.limit {
+            if self.can_read_backwards() && !stdin && !compressed {
+                return Ok(Access::Backwards);
+            }
+        }
+        if cached && !stdin {
+            return Ok(Access::Cached);
+        }
+        if paths.len() != 1 || stdin || compressed {
+            return Ok(Access::Sequential);
+        }
+
+        let threads = thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1);
+        if let Some(index) = Index::load(&paths[0])? {
+            return Ok(Access::Indexed { index, threads });
+        }
+        let length = fs::metadata(&paths[0]).map_err(|err| format!("Cannot read {:?}: {}", paths[0], err))?.len();
+        let threads = threads.min((length / PARALLEL_CHUNK_SIZE as u64) as usize).max(1);
+        Ok(Access::Mapped { threads })
+    }
+
+    /// Whether the result does not change when the files are read from the end.
+    /// Aggregates and sorting need every record anyway, and line numbers are
+    /// only known when reading from the start.
+    fn can_read_backwards(&self) -> bool {
+        let reads_line = self.referenced_fields().iter().any(|field| field == LINE_FIELD);
+        !self.aggregate && self.sort.is_none() && !reads_line
+    }
+}
+
+/// Adds the fields read by `expression` to `fields`, each once.
+fn collect_fields(expression: &Expression, fields: &mut Vec<String>) {
+    match *expression {
+        Expression::Field(ref name) => {
+            if !fields.contains(name) {
+                fields.push(name.clone());
+            }
+        },
+        Expression::Wildcard | Expression::Str(_) | Expression::Number(_) | Expression::Float(_) => {},
+        Expression::Negate(ref operand) => collect_fields(operand, fields),
+        Expression::BinaryOperation { ref left, ref right, .. } | Expression::Comparison { ref left, ref right, .. } => {
+            collect_fields(left, fields);
+            collect_fields(right, fields);
+        },
+        Expression::FunctionCall { ref arguments, .. } => {
+            for argument in arguments {
+                collect_fields(argument, fields);
+            }
+        },
+        Expression::Case { ref branches, ref else_result } => {
+            for (condition, result) in branches {
+                collect_fields(condition, fields);
+                collect_fields(result, fields);
+            }
+            if let Some(ref else_result) = *else_result {
+                collect_fields(else_result, fields);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    use index;
+    use parser::Parser;
+
+    fn logical(query: &str) -> LogicalPlan {
+        let ast = Parser::new(query.into()).parse().unwrap();
+        LogicalPlan::from_ast(&ast, &FunctionRegistry::new()).unwrap()
+    }
+
+    fn write_log(directory: &TempDir, name: &str, content: &[u8]) -> String {
+        let path = directory.path().join(name);
+        File::create(&path).unwrap().write_all(content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn physical(query: &str, cached: bool) -> PhysicalPlan {
+        PhysicalPlan::new(&logical(query), cached).unwrap()
+    }
+
+    #[test]
+    fn it_builds_the_stages_of_a_query() {
+        assert_eq!(
+            logical("SELECT n, latency / 1000 AS seconds FROM 'app.log' WHERE level = 'error' ORDER BY latency DESC LIMIT LAST 10").to_string(),
+            "Limit last 10\n  Project n, latency / 1000 AS seconds\n    Sort latency DESC\n      Filter level = 'error'\n        Scan 'app.log'"
+        );
+        assert_eq!(
+            logical("SELECT count(*) FROM 'app.log', STDIN").to_string(),
+            "Aggregate count(*)\n  Scan 'app.log', STDIN"
+        );
+        assert_eq!(
+            logical("SELECT CASE WHEN latency > 100 THEN 'slow' ELSE 'fast' END AS speed, count(*) FROM 'app.log' GROUP BY speed, level").to_string(),
+            "Aggregate CASE WHEN latency > 100 THEN 'slow' ELSE 'fast' END AS speed, count(*) by CASE WHEN latency > 100 THEN 'slow' ELSE 'fast' END, level\n  Scan 'app.log'"
+        );
+        assert_eq!(
+            logical("SELECT * FROM 'app.log' FOLLOW"),
+            LogicalPlan::Project {
+                input: Box::new(LogicalPlan::Scan { sources: vec!("app.log".into()), follow: true }),
+                fields: vec!(SelectField::new(Expression::Wildcard, None))
+            }
+        );
+    }
+
+    #[test]
+    fn it_fails_to_group_by_aggregated_fields() {
+        let ast = Parser::new("SELECT count(*) AS requests FROM 'app.log' GROUP BY requests".into()).parse().unwrap();
+        assert!(LogicalPlan::from_ast(&ast, &FunctionRegistry::new()).is_err());
+    }
+
+    #[test]
+    fn it_pushes_the_limit_into_sorting_and_literals_into_reading() {
+        let directory = TempDir::new().unwrap();
+        let path = write_log(&directory, "app.log", b"n=1\n");
+
+        let plan = physical(&format!("SELECT n FROM '{}' WHERE level = 'error' ORDER BY latency LIMIT 3", path), false);
+        assert!(plan.prefilter.is_some());
+        assert_eq!(plan.sort.unwrap().top, Some((3, LimitDirection::First)));
+        assert_eq!(plan.columns, Some(vec!("n".to_string(), "level".to_string(), "latency".to_string())));
+
+        let plan = physical(&format!("SELECT * FROM '{}' WHERE _line > 3", path), false);
+        assert!(plan.prefilter.is_none() && plan.sort.is_none() && plan.columns.is_none());
+    }
+
+    #[test]
+    fn it_chooses_how_to_read_the_files() {
+        let directory = TempDir::new().unwrap();
+        let path = write_log(&directory, "app.log", b"n=1\n");
+        write_log(&directory, "old.log.gz", &[0x1f, 0x8b, 0x08, 0x00]);
+
+        assert!(matches!(physical(&format!("SELECT n FROM '{}'", path), false).access, Access::Mapped { threads: 1 }));
+        assert!(matches!(physical(&format!("SELECT n FROM '{}' LIMIT LAST 3", path), false).access, Access::Backwards));
+        assert!(matches!(physical(&format!("SELECT n, _line FROM '{}' LIMIT LAST 3", path), false).access, Access::Mapped { .. }));
+        assert!(matches!(physical(&format!("SELECT n FROM '{}' ORDER BY n LIMIT LAST 3", path), false).access, Access::Mapped { .. }));
+        assert!(matches!(physical(&format!("SELECT n FROM '{}'", path), true).access, Access::Cached));
+        assert!(matches!(physical(&format!("SELECT n FROM '{}/*.log*' LIMIT LAST 3", directory.path().display()), false).access, Access::Sequential));
+        assert!(matches!(physical("SELECT n FROM STDIN", true).access, Access::Sequential));
+
+        index::build(Path::new(&path)).unwrap();
+        assert!(matches!(physical(&format!("SELECT n FROM '{}'", path), false).access, Access::Indexed { .. }));
+    }
+}
@@ -0,0 +1,448 @@
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde_json;
+
+use functions::{json_to_value, Value};
+
+/// Fields consulted, in order, to find the time a record was logged at.
+pub const TIMESTAMP_FIELDS: [&str; 5] = ["timestamp", "@timestamp", "time", "ts", "date"];
+
+/// Virtual column holding the path of the file a record was read from.
+pub const FILE_FIELD: &str = "_file";
+/// Virtual column holding the 1-based line number of a record within its file.
+pub const LINE_FIELD: &str = "_line";
+
+/// Access to the fields of a log entry, whether parsed up front or on demand.
+pub trait Fields {
+    /// The value of the field `name`, or NULL if the entry has no such field.
+    fn get(&self, name: &str) -> Value;
+
+    /// All fields of the entry in order, used to expand `*`.
+    fn pairs(&self) -> Vec<(String, Value)>;
+
+    /// The length of the entry's text and whether it had no fields, as
+    /// reported by `EXPLAIN ANALYZE`.
+    fn size(&self) -> (usize, bool);
+
+    /// The unparsed text of the entry, if it is still at hand.
+    fn text(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// A single log entry, split into its fields.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct Record {
+    pub fields: Vec<(String, Value)>,
+    pub file: Arc<String>,
+    pub line: usize,
+    /// Bytes of text the entry was parsed from, without its line ending.
+    pub length: usize,
+    /// Whether the text had no fields, so it is only available as `message`.
+    pub unparsed: bool
+}
+
+impl Record {
+    /// Parses `text` as a JSON object if it looks like one and as `key=value`
+    /// pairs (logfmt) otherwise. Lines without any pairs become a `message` field.
+    pub fn parse(text: &str, file: Arc<String>, line: usize) -> Record {
+        let fields = parse_structured(text);
+        Record {
+            unparsed: fields.is_none(),
+            fields: fields.unwrap_or_else(|| message_field(text)),
+            file,
+            line,
+            length: text.len()
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Value {
+        match name {
+            FILE_FIELD => Value::Str(self.file.to_string()),
+            LINE_FIELD => Value::Integer(self.line as i64),
+            _ => {
+                self.fields.iter()
+                    .find(|&(field, _)| field == name)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or(Value::Null)
+            }
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        TIMESTAMP_FIELDS.iter()
+            .map(|field| self.get(field))
+            .find(|value| !value.is_null())
+            .and_then(|value| value.as_timestamp().ok())
+    }
+}
+
+/// The format a log line is written in, telling how its fields are parsed.
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone, Copy)]
+pub enum Format {
+    Json,
+    Logfmt,
+    /// Lines without any field, only available as `message`.
+    Text
+}
+
+impl Format {
+    pub fn of(text: &str) -> Format {
+        let trimmed = text.trim();
+        if parse_json(trimmed).is_some() {
+            Format::Json
+        } else if LogfmtPairs::new(trimmed).next().is_some() {
+            Format::Logfmt
+        } else {
+            Format::Text
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::Json => write!(f, "JSON"),
+            Format::Logfmt => write!(f, "logfmt"),
+            Format::Text => write!(f, "text")
+        }
+    }
+}
+
+/// Indented lines, like the frames of a stack trace, continue the record on
+/// the line before them instead of starting a new one.
+pub fn is_continuation(line: &[u8]) -> bool {
+    line.first().is_some_and(|&byte| byte == b' ' || byte == b'\t')
+}
+
+impl Fields for Record {
+    fn get(&self, name: &str) -> Value {
+        Record::get(self, name)
+    }
+
+    fn pairs(&self) -> Vec<(String, Value)> {
+        self.fields.clone()
+    }
+
+    fn size(&self) -> (usize, bool) {
+        (self.length, self.unparsed)
+    }
+}
+
+/// A log entry borrowing its text, e.g. from a memory-mapped file, that is
+/// only parsed as far as needed: a logfmt line is scanned for just the
+/// requested field, a JSON line is parsed once on first access.
+pub struct LineRecord<'a> {
+    text: Cow<'a, str>,
+    file: &'a Arc<String>,
+    line: usize,
+    json: OnceCell<Option<Vec<(String, Value)>>>
+}
+
+impl<'a> LineRecord<'a> {
+    pub fn new(text: Cow<'a, str>, file: &'a Arc<String>, line: usize) -> LineRecord<'a> {
+        LineRecord {
+            text,
+            file,
+            line,
+            json: OnceCell::new()
+        }
+    }
+
+    fn json(&self) -> Option<&Vec<(String, Value)>> {
+        self.json.get_or_init(|| parse_json(self.text.trim())).as_ref()
+    }
+
+    /// Parses the whole entry into an owned `Record`.
+    pub fn to_record(&self) -> Record {
+        Record::parse(&self.text, self.file.clone(), self.line)
+    }
+}
+
+impl<'a> Fields for LineRecord<'a> {
+    fn get(&self, name: &str) -> Value {
+        match name {
+            FILE_FIELD => return Value::Str(self.file.to_string()),
+            LINE_FIELD => return Value::Integer(self.line as i64),
+            _ => {}
+        }
+
+        if let Some(fields) = self.json() {
+            return fields.iter().find(|(field, _)| field == name).map(|(_, value)| value.clone()).unwrap_or(Value::Null);
+        }
+
+        let text = self.text.trim();
+        let mut pairs = LogfmtPairs::new(text).peekable();
+        if name == "message" && pairs.peek().is_none() {
+            return Value::Str(text.to_string());
+        }
+        pairs.find(|&(key, _)| key == name).map(|(_, value)| value.into_value()).unwrap_or(Value::Null)
+    }
+
+    fn pairs(&self) -> Vec<(String, Value)> {
+        match self.json() {
+            Some(fields) => fields.clone(),
+            None => parse_fields(&self.text)
+        }
+    }
+
+    fn size(&self) -> (usize, bool) {
+        let text = self.text.trim_end_matches(&['\n', '\r'][..]);
+        (text.len(), Format::of(text) == Format::Text)
+    }
+
+    fn text(&self) -> Option<&str> {
+        Some(&self.text)
+    }
+}
+
+fn parse_json(text: &str) -> Option<Vec<(String, Value)>> {
+    if !text.starts_with('{') {
+        return None;
+    }
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(object)) => Some(object.into_iter().map(|(key, value)| (key, json_to_value(&value))).collect()),
+        _ => None
+    }
+}
+
+fn parse_fields(text: &str) -> Vec<(String, Value)> {
+    parse_structured(text).unwrap_or_else(|| message_field(text))
+}
+
+/// The JSON or logfmt fields of `text`, or `None` if it has neither.
+fn parse_structured(text: &str) -> Option<Vec<(String, Value)>> {
+    let trimmed = text.trim();
+    parse_json(trimmed).or_else(|| Some(parse_logfmt(trimmed)).filter(|fields| !fields.is_empty()))
+}
+
+fn message_field(text: &str) -> Vec<(String, Value)> {
+    vec!(("message".to_string(), Value::Str(text.trim().to_string())))
+}
+
+/// Collects `key=value` and `key="quoted value"` pairs; words that are not pairs are skipped.
+fn parse_logfmt(text: &str) -> Vec<(String, Value)> {
+    LogfmtPairs::new(text).map(|(key, value)| (key.to_string(), value.into_value())).collect()
+}
+
+/// A logfmt value as written in the line, borrowed unless it contained escapes.
+struct LogfmtValue<'a> {
+    raw: Cow<'a, str>,
+    quoted: bool
+}
+
+impl<'a> LogfmtValue<'a> {
+    fn into_value(self) -> Value {
+        if self.quoted { Value::Str(self.raw.into_owned()) } else { infer_value(self.raw.into_owned()) }
+    }
+}
+
+/// Iterates over the `key=value` pairs of a logfmt line without copying them.
+struct LogfmtPairs<'a> {
+    text: &'a str,
+    position: usize
+}
+
+impl<'a> LogfmtPairs<'a> {
+    fn new(text: &'a str) -> LogfmtPairs<'a> {
+        LogfmtPairs { text, position: 0 }
+    }
+
+    fn skip_while<P: Fn(char) -> bool>(&mut self, predicate: P) -> &'a str {
+        let rest = &self.text[self.position..];
+        let length = rest.find(|ch: char| !predicate(ch)).unwrap_or(rest.len());
+        self.position += length;
+        &rest[..length]
+    }
+
+    fn quoted_value(&mut self) -> Cow<'a, str> {
+        let rest = &self.text[self.position..];
+        match rest.find(['"', '\\']) {
+            Some(end) if rest.as_bytes()[end] == b'"' => {
+                self.position += end + 1;
+                Cow::Borrowed(&rest[..end])
+            },
+            _ => {
+                let mut value = String::new();
+                let mut chars = rest.char_indices();
+                let mut consumed = rest.len();
+                while let Some((index, ch)) = chars.next() {
+                    match ch {
+                        '"' => {
+                            consumed = index + 1;
+                            break;
+                        },
+                        '\\' => {
+                            if let Some((_, escaped)) = chars.next() {
+                                value.push(escaped);
+                            }
+                        },
+                        _ => value.push(ch)
+                    }
+                }
+                self.position += consumed;
+                Cow::Owned(value)
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for LogfmtPairs<'a> {
+    type Item = (&'a str, LogfmtValue<'a>);
+
+    fn next(&mut self) -> Option<(&'a str, LogfmtValue<'a>)> {
+        while self.position < self.text.len() {
+            self.skip_while(char::is_whitespace);
+            let key = self.skip_while(|ch| ch != '=' && !ch.is_whitespace());
+
+            let has_value = self.text[self.position..].starts_with('=');
+            if has_value {
+                self.position += 1;
+            }
+            if !has_value || key.is_empty() {
+                continue;
+            }
+
+            let value = if self.text[self.position..].starts_with('"') {
+                self.position += 1;
+                LogfmtValue { raw: self.quoted_value(), quoted: true }
+            } else {
+                LogfmtValue { raw: Cow::Borrowed(self.skip_while(|ch| !ch.is_whitespace())), quoted: false }
+            };
+            return Some((key, value));
+        }
+        None
+    }
+}
+
+/// Unquoted logfmt values that are plain decimal numbers are read as numbers.
+/// Integers with leading zeros (like IDs) are kept as strings.
+fn infer_value(raw: String) -> Value {
+    let digits = raw.trim_start_matches('-');
+    let is_decimal = !digits.is_empty() &&
+        digits.chars().all(|ch| ch.is_ascii_digit() || ch == '.') &&
+        digits.matches('.').count() <= 1 &&
+        !digits.starts_with('.') && !digits.ends_with('.');
+
+    if !is_decimal {
+        return Value::Str(raw);
+    }
+    if !digits.contains('.') {
+        if digits.len() > 1 && digits.starts_with('0') {
+            return Value::Str(raw);
+        }
+        return raw.parse::<i64>().map(Value::Integer).unwrap_or(Value::Str(raw));
+    }
+    raw.parse::<f64>().map(Value::Float).unwrap_or(Value::Str(raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Record {
+        Record::parse(text, Arc::new("app.log".into()), 3)
+    }
+
+    #[test]
+    fn it_parses_logfmt_lines() {
+        let record = parse("time=2018-03-04T10:17:42Z level=error msg=\"Network connection \\\"eth0\\\" failed\" status=503");
+        assert_eq!(record.get("level"), Value::Str("error".into()));
+        assert_eq!(record.get("msg"), Value::Str("Network connection \"eth0\" failed".into()));
+        assert_eq!(record.get("status"), Value::Integer(503));
+        assert_eq!(record.get("missing"), Value::Null);
+    }
+
+    #[test]
+    fn it_parses_json_lines() {
+        let record = parse("{\"level\": \"warn\", \"status\": 404, \"latency\": 1.5, \"request\": {\"path\": \"/\"}}");
+        assert_eq!(record.get("level"), Value::Str("warn".into()));
+        assert_eq!(record.get("status"), Value::Integer(404));
+        assert_eq!(record.get("latency"), Value::Float(1.5));
+        assert_eq!(record.get("request"), Value::Str("{\"path\":\"/\"}".into()));
+    }
+
+    #[test]
+    fn it_keeps_plain_lines_as_message() {
+        let record = parse("Server started");
+        assert_eq!(record.fields, vec!(("message".to_string(), Value::Str("Server started".into()))));
+    }
+
+    #[test]
+    fn it_exposes_virtual_file_and_line_columns() {
+        let record = parse("level=info");
+        assert_eq!(record.get("_file"), Value::Str("app.log".into()));
+        assert_eq!(record.get("_line"), Value::Integer(3));
+    }
+
+    #[test]
+    fn it_finds_the_record_timestamp() {
+        assert_eq!(parse("ts=2018-03-04T10:17:42Z").timestamp().map(|t| t.to_rfc3339()), Some("2018-03-04T10:17:42+00:00".into()));
+        assert_eq!(parse("level=info").timestamp(), None);
+    }
+
+    #[test]
+    fn it_recognizes_continuation_lines() {
+        assert!(is_continuation(b"  at main.rs:10"));
+        assert!(is_continuation(b"\tat lib.rs:2"));
+        assert!(!is_continuation(b"level=info"));
+        assert!(!is_continuation(b""));
+    }
+
+    #[test]
+    fn it_skips_words_that_are_not_pairs() {
+        let record = parse("=oops key= GET /index a=\"x\\\"y\" b=\"unterminated");
+        assert_eq!(record.fields, vec!(
+            ("key".to_string(), Value::Str("".into())),
+            ("a".to_string(), Value::Str("x\"y".into())),
+            ("b".to_string(), Value::Str("unterminated".into()))
+        ));
+    }
+
+    #[test]
+    fn it_parses_line_records_lazily_like_records() {
+        let file = Arc::new("app.log".to_string());
+        let lines = [
+            "time=2018-03-04T10:17:42Z level=error msg=\"Network \\\"eth0\\\" failed\" status=503",
+            "{\"level\": \"warn\", \"status\": 404}",
+            "Server started"
+        ];
+
+        for line in &lines {
+            let record = parse(line);
+            let lazy = LineRecord::new(Cow::Borrowed(line), &file, 3);
+            for name in &["time", "level", "msg", "status", "message", "missing", "_file", "_line"] {
+                assert_eq!(Fields::get(&lazy, name), record.get(name), "{} in {}", name, line);
+            }
+            assert_eq!(lazy.pairs(), record.fields);
+            assert_eq!(lazy.to_record(), record);
+        }
+    }
+
+    #[test]
+    fn it_infers_numbers_in_unquoted_logfmt_values() {
+        let record = parse("a=-12 b=1.5 c=\"42\" d=0042 e=1.2.3 f=12ms");
+        assert_eq!(record.get("a"), Value::Integer(-12));
+        assert_eq!(record.get("b"), Value::Float(1.5));
+        assert_eq!(record.get("c"), Value::Str("42".into()));
+        assert_eq!(record.get("d"), Value::Str("0042".into()));
+        assert_eq!(record.get("e"), Value::Str("1.2.3".into()));
+        assert_eq!(record.get("f"), Value::Str("12ms".into()));
+    }
+
+    #[test]
+    fn it_detects_the_format_of_lines() {
+        assert_eq!(Format::of("{\"level\": \"error\"}"), Format::Json);
+        assert_eq!(Format::of("level=error msg=boom"), Format::Logfmt);
+        assert_eq!(Format::of("{\"level\": "), Format::Text);
+        assert_eq!(Format::of("  Connection refused"), Format::Text);
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use record::{self, Record};
+
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Reads the lines of a file from last to first.
+///
+/// Blocks are read from the end of the file towards its start and split on
+/// `\n`. As that byte never occurs inside a multi-byte UTF-8 sequence, block
+/// boundaries falling in the middle of a character are harmless: a line is
+/// only decoded once it is complete.
+pub struct ReverseLines {
+    file: File,
+    position: u64,
+    block_size: u64,
+    partial: Vec<u8>,
+    finished: bool
+}
+
+impl ReverseLines {
+    pub fn open(path: &Path) -> Result<ReverseLines, String> {
+        let file = File::open(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+        ReverseLines::new(file, BLOCK_SIZE).map_err(|err| format!("Cannot read {:?}: {}", path, err))
+    }
+
+    fn new(mut file: File, block_size: u64) -> Result<ReverseLines, io::Error> {
+        let length = file.seek(SeekFrom::End(0))?;
+        let mut position = length;
+
+        // The newline ending the last line does not start another, empty line.
+        if length > 0 {
+            let mut last_byte = [0; 1];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last_byte)?;
+            if last_byte[0] == b'\n' {
+                position -= 1;
+            }
+        }
+
+        Ok(ReverseLines {
+            file,
+            position,
+            block_size,
+            partial: vec!(),
+            finished: length == 0
+        })
+    }
+
+    fn read_previous_block(&mut self) -> Result<(), io::Error> {
+        let size = self.block_size.min(self.position);
+        self.position -= size;
+        self.file.seek(SeekFrom::Start(self.position))?;
+
+        let mut block = vec!(0; size as usize);
+        self.file.read_exact(&mut block)?;
+        block.extend_from_slice(&self.partial);
+        self.partial = block;
+        Ok(())
+    }
+}
+
+impl Iterator for ReverseLines {
+    type Item = Result<Vec<u8>, String>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, String>> {
+        if self.finished {
+            return None;
+        }
+
+        loop {
+            if let Some(newline) = self.partial.iter().rposition(|&byte| byte == b'\n') {
+                let mut line = self.partial.split_off(newline + 1);
+                self.partial.truncate(newline);
+                strip_carriage_return(&mut line);
+                return Some(Ok(line));
+            }
+
+            if self.position == 0 {
+                self.finished = true;
+                let mut line = mem::take(&mut self.partial);
+                strip_carriage_return(&mut line);
+                return Some(Ok(line));
+            }
+
+            if let Err(err) = self.read_previous_block() {
+                self.finished = true;
+                return Some(Err(err.to_string()));
+            }
+        }
+    }
+}
+
+fn strip_carriage_return(line: &mut Vec<u8>) {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+}
+
+/// Reads the records of an uncompressed file from last to first, joining
+/// continuation lines with the line they belong to just like `FileRecords`.
+///
+/// Line numbers are not known when reading backwards, so records are
+/// numbered 0.
+pub struct ReverseFileRecords {
+    file: Arc<String>,
+    lines: ReverseLines
+}
+
+impl ReverseFileRecords {
+    pub fn open(path: &Path) -> Result<ReverseFileRecords, String> {
+        Ok(ReverseFileRecords {
+            file: Arc::new(path.to_string_lossy().into_owned()),
+            lines: ReverseLines::open(path)?
+        })
+    }
+
+    /// Builds the record starting at `line`, with its continuation lines in the order they were read.
+    fn record(&self, mut line: Vec<u8>, continuations: &[Vec<u8>]) -> Record {
+        for continuation in continuations.iter().rev() {
+            line.push(b'\n');
+            line.extend_from_slice(continuation);
+        }
+        Record::parse(&String::from_utf8_lossy(&line), self.file.clone(), 0)
+    }
+}
+
+impl Iterator for ReverseFileRecords {
+    type Item = Result<Record, String>;
+
+    fn next(&mut self) -> Option<Result<Record, String>> {
+        let mut continuations: Vec<Vec<u8>> = vec!();
+
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(err)) => return Some(Err(format!("Cannot read {}: {}", self.file, err))),
+                None if continuations.is_empty() => return None,
+                // Continuation lines at the very start of the file have nothing to attach to.
+                None => return Some(Ok(self.record(vec!(), &continuations)))
+            };
+
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            if record::is_continuation(&line) {
+                continuations.push(line);
+                continue;
+            }
+            return Some(Ok(self.record(line, &continuations)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    use functions::Value;
+
+    fn file_with(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn reverse_lines(content: &str, block_size: u64) -> Vec<String> {
+        let file = file_with(content);
+        ReverseLines::new(File::open(file.path()).unwrap(), block_size).unwrap()
+            .map(|line| String::from_utf8(line.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn it_reads_lines_backwards_across_block_boundaries() {
+        let content = "first line\nsecond\n\nthird line is longer\n";
+        for block_size in 1..12 {
+            assert_eq!(reverse_lines(content, block_size), vec!("third line is longer", "", "second", "first line"));
+        }
+    }
+
+    #[test]
+    fn it_reads_files_without_trailing_newline_or_content() {
+        assert_eq!(reverse_lines("a\r\nb", 2), vec!("b", "a"));
+        assert_eq!(reverse_lines("", 2), Vec::<String>::new());
+        assert_eq!(reverse_lines("\n", 2), vec!(""));
+    }
+
+    #[test]
+    fn it_keeps_multi_byte_characters_split_across_blocks_intact() {
+        assert_eq!(reverse_lines("größe=1\nnachricht=überlauf\n", 3), vec!("nachricht=überlauf", "größe=1"));
+    }
+
+    #[test]
+    fn it_joins_continuation_lines_into_their_record() {
+        let file = file_with("level=info n=1\nlevel=error n=2 msg=boom\n  at main.rs:10\n\tat lib.rs:2\n\nlevel=info n=3\n");
+        let records: Vec<Record> = ReverseFileRecords::open(file.path()).unwrap().map(Result::unwrap).collect();
+
+        assert_eq!(records.iter().map(|record| record.get("n")).collect::<Vec<Value>>(), vec!(Value::Integer(3), Value::Integer(2), Value::Integer(1)));
+        assert_eq!(records[1].get("msg"), Value::Str("boom".into()));
+    }
+}
@@ -0,0 +1,540 @@
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bzip2::read::MultiBzDecoder;
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use glob;
+use zstd;
+
+use index;
+use mapped::MappedFile;
+use record::{self, Record};
+use reverse::ReverseFileRecords;
+
+/// The source name standing for the standard input, written `STDIN` or `'-'` in queries.
+pub const STDIN: &str = "-";
+
+const COMPRESSION_EXTENSIONS: [&str; 4] = [".gz", ".zst", ".bz2", ".zstd"];
+
+/// Expands the sources of a `FROM` clause into the files to read.
+///
+/// Each source is a file, a directory (all files directly inside it) or a
+/// glob pattern. Files are returned in the order the sources were given,
+/// with directory entries and glob matches sorted by path. Rotated copies of
+/// a log (`app.log.2.gz`, `app.log.1`, `app.log`) are ordered oldest first.
+/// The source `-` (`STDIN`) stands for the standard input and is kept as is.
+/// Index files built next to logs are not logs themselves and are skipped.
+pub fn resolve(sources: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec!();
+
+    for source in sources {
+        let mut matches = vec!();
+
+        if source == STDIN {
+            if sources.iter().filter(|&other| other == STDIN).count() > 1 {
+                return Err("STDIN can only be read once".into());
+            }
+            files.push(PathBuf::from(STDIN));
+            continue;
+        }
+
+        if is_glob_pattern(source) {
+            let paths = glob::glob(source).map_err(|err| format!("Invalid pattern {:?}: {}", source, err))?;
+            for path in paths {
+                let path = path.map_err(|err| format!("Cannot read {:?}: {}", source, err))?;
+                if path.is_file() && !index::is_index(&path) {
+                    matches.push(path);
+                }
+            }
+        } else {
+            let path = Path::new(source);
+            if path.is_dir() {
+                let entries = fs::read_dir(path).map_err(|err| format!("Cannot read directory {:?}: {}", source, err))?;
+                for entry in entries {
+                    let entry_path = entry.map_err(|err| format!("Cannot read directory {:?}: {}", source, err))?.path();
+                    if entry_path.is_file() && !index::is_index(&entry_path) {
+                        matches.push(entry_path);
+                    }
+                }
+            } else if path.is_file() {
+                matches.push(path.to_path_buf());
+            } else {
+                return Err(format!("No such file or directory: {:?}", source));
+            }
+        }
+
+        if matches.is_empty() {
+            return Err(format!("No files match {:?}", source));
+        }
+        matches.sort_by_key(|path| rotation_order(path));
+        for path in matches {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn is_glob_pattern(source: &str) -> bool {
+    source.contains(['*', '?', '['])
+}
+
+/// Position of a file in its log's rotation history, older files first.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Rotation {
+    /// Rotated with a date suffix, like `app.log-20180304`.
+    Dated(String),
+    /// Rotated with a counter, like `app.log.1`; higher numbers are older.
+    Numbered(Reverse<u64>),
+    /// The file currently written to.
+    Current
+}
+
+/// Sort key grouping the rotated copies of a log under its name, ordered chronologically.
+fn rotation_order(path: &Path) -> (PathBuf, Rotation) {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = COMPRESSION_EXTENSIONS.iter()
+        .find(|extension| name.ends_with(*extension))
+        .map(|extension| name[..name.len() - extension.len()].to_string())
+        .unwrap_or(name);
+
+    let (base, rotation) = if let Some((base, number)) = name.rsplit_once('.').and_then(|(base, suffix)| suffix.parse::<u64>().ok().map(|number| (base, number))) {
+        (base.to_string(), Rotation::Numbered(Reverse(number)))
+    } else if let Some((base, date)) = name.rsplit_once('-').filter(|(_, suffix)| suffix.len() >= 8 && suffix.chars().all(|ch| ch.is_ascii_digit())) {
+        (base.to_string(), Rotation::Dated(date.to_string()))
+    } else {
+        (name, Rotation::Current)
+    };
+
+    (path.with_file_name(base), rotation)
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2
+}
+
+impl Compression {
+    /// Detects the compression format from the first bytes of a file.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if header.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Opens `path` for reading, transparently decompressing it if needed.
+pub fn open_file(path: &Path) -> Result<Box<dyn BufRead + Send>, String> {
+    let file = File::open(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+    decompress(Box::new(file), path)
+}
+
+/// Wraps `input` in a decoder matching the compression of its first bytes.
+fn decompress(input: Box<dyn Read + Send>, path: &Path) -> Result<Box<dyn BufRead + Send>, String> {
+    let mut reader = BufReader::new(input);
+    let compression = Compression::detect(reader.fill_buf().map_err(|err| format!("Cannot read {:?}: {}", path, err))?);
+
+    let decompressed: Box<dyn Read + Send> = match compression {
+        Compression::None => return Ok(Box::new(reader)),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader).map_err(|err| format!("Cannot read {:?}: {}", path, err))?),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(reader))
+    };
+    Ok(Box::new(BufReader::new(decompressed)))
+}
+
+/// Reads the records of a single, possibly compressed, file, one per line.
+///
+/// Indented lines following a record (see `record::is_continuation`) are
+/// joined to it, so a stack trace stays part of the entry that logged it.
+pub struct FileRecords {
+    file: Arc<String>,
+    reader: Box<dyn BufRead + Send>,
+    line: usize,
+    /// The record being read, with its line number, until a line shows it is complete.
+    pending: Option<(Vec<u8>, usize)>
+}
+
+impl FileRecords {
+    /// Opens the file at `path`, or the standard input if `path` is `-`.
+    pub fn open(path: &Path) -> Result<FileRecords, String> {
+        let reader = if path == Path::new(STDIN) {
+            decompress(Box::new(io::stdin()), path)?
+        } else {
+            open_file(path)?
+        };
+        Ok(FileRecords::from_reader(&path.to_string_lossy(), reader))
+    }
+
+    /// Reads records from any stream, like a pipe, naming it `name` in the `_file` column.
+    pub fn from_reader(name: &str, reader: Box<dyn BufRead + Send>) -> FileRecords {
+        FileRecords {
+            file: Arc::new(name.to_string()),
+            reader,
+            line: 0,
+            pending: None
+        }
+    }
+
+    fn record(&self, (text, line): (Vec<u8>, usize)) -> Record {
+        Record::parse(&String::from_utf8_lossy(&text), self.file.clone(), line)
+    }
+}
+
+impl Iterator for FileRecords {
+    type Item = Result<Record, String>;
+
+    fn next(&mut self) -> Option<Result<Record, String>> {
+        let mut buffer = vec!();
+        loop {
+            buffer.clear();
+            match self.reader.read_until(b'\n', &mut buffer) {
+                Ok(0) => return self.pending.take().map(|pending| Ok(self.record(pending))),
+                Ok(_) => {
+                    self.line += 1;
+                    while buffer.last().is_some_and(|&byte| byte == b'\n' || byte == b'\r') {
+                        buffer.pop();
+                    }
+                    if buffer.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+
+                    match &mut self.pending {
+                        Some((text, _)) if record::is_continuation(&buffer) => {
+                            text.push(b'\n');
+                            text.extend_from_slice(&buffer);
+                        },
+                        _ => {
+                            if let Some(complete) = self.pending.replace((buffer.clone(), self.line)) {
+                                return Some(Ok(self.record(complete)));
+                            }
+                        }
+                    }
+                },
+                Err(err) => return Some(Err(format!("Cannot read {}: {}", self.file, err)))
+            }
+        }
+    }
+}
+
+struct MergeInput<I: Iterator<Item = Result<Record, String>>> {
+    records: Peekable<I>,
+    last_timestamp: Option<DateTime<Utc>>
+}
+
+/// Interleaves the records of several inputs ordered by their timestamp.
+///
+/// Each input is expected to be in chronological order already, or in
+/// reverse chronological order when merging with `MergedRecords::reversed`.
+/// Records without a timestamp (e.g. plain text lines) keep their position
+/// after the preceding record of the same input.
+pub struct MergedRecords<I: Iterator<Item = Result<Record, String>>> {
+    inputs: Vec<MergeInput<I>>,
+    reversed: bool
+}
+
+impl<I: Iterator<Item = Result<Record, String>>> MergedRecords<I> {
+    pub fn new(inputs: Vec<I>) -> MergedRecords<I> {
+        MergedRecords {
+            inputs: inputs.into_iter().map(|records| MergeInput { records: records.peekable(), last_timestamp: None }).collect(),
+            reversed: false
+        }
+    }
+
+    /// Merges inputs that yield their records newest first, producing the
+    /// exact reverse of what `new` produces for the same files read forwards.
+    pub fn reversed(inputs: Vec<I>) -> MergedRecords<I> {
+        MergedRecords {
+            reversed: true,
+            ..MergedRecords::new(inputs)
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Record, String>>> Iterator for MergedRecords<I> {
+    type Item = Result<Record, String>;
+
+    fn next(&mut self) -> Option<Result<Record, String>> {
+        let mut next: Option<(usize, Option<DateTime<Utc>>)> = None;
+
+        for (index, input) in self.inputs.iter_mut().enumerate() {
+            let timestamp = match input.records.peek() {
+                Some(Ok(record)) => record.timestamp().or(input.last_timestamp),
+                Some(Err(_)) => return input.records.next(),
+                None => continue
+            };
+            let comes_first = match next {
+                None => true,
+                Some((_, next_timestamp)) if self.reversed => timestamp >= next_timestamp,
+                Some((_, next_timestamp)) => timestamp < next_timestamp
+            };
+            if comes_first {
+                next = Some((index, timestamp));
+            }
+        }
+
+        let (index, timestamp) = next?;
+        let input = &mut self.inputs[index];
+        input.last_timestamp = timestamp;
+        input.records.next()
+    }
+}
+
+/// Opens every file matched by `sources`, merging them by timestamp when there is more than one.
+pub fn open(sources: &[String]) -> Result<MergedRecords<FileRecords>, String> {
+    let mut inputs = vec!();
+    for path in resolve(sources)? {
+        inputs.push(FileRecords::open(&path)?);
+    }
+    Ok(MergedRecords::new(inputs))
+}
+
+/// Like `open`, but yields the records newest first by reading the files
+/// backwards. Returns `None` when a file is compressed or the standard input
+/// is read, as those can only be read from the start.
+pub fn open_reversed(sources: &[String]) -> Result<Option<MergedRecords<ReverseFileRecords>>, String> {
+    let mut inputs = vec!();
+    for path in resolve(sources)? {
+        if path == Path::new(STDIN) || detect_compression(&path)? != Compression::None {
+            return Ok(None);
+        }
+        inputs.push(ReverseFileRecords::open(&path)?);
+    }
+    Ok(Some(MergedRecords::reversed(inputs)))
+}
+
+/// Maps the file selected by `sources` into memory for scanning it without
+/// copying. Returns `None` unless `sources` resolve to a single uncompressed
+/// file, as merging several files or decompressing needs owned records.
+pub fn map(sources: &[String]) -> Result<Option<MappedFile>, String> {
+    let paths = resolve(sources)?;
+    match paths.first() {
+        Some(path) if paths.len() == 1 && path != Path::new(STDIN) && detect_compression(path)? == Compression::None => {
+            Ok(Some(MappedFile::open(path)?))
+        },
+        _ => Ok(None)
+    }
+}
+
+/// Detects the compression of the file at `path` from its first bytes.
+pub fn detect_compression(path: &Path) -> Result<Compression, String> {
+    let file = File::open(path).map_err(|err| format!("Cannot open {:?}: {}", path, err))?;
+    let mut header = vec!();
+    file.take(4).read_to_end(&mut header).map_err(|err| format!("Cannot read {:?}: {}", path, err))?;
+    Ok(Compression::detect(&header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    use functions::Value;
+
+    fn write_file(directory: &TempDir, name: &str, lines: &[&str]) -> String {
+        let path = directory.path().join(name);
+        let mut file = File::create(&path).unwrap();
+        for line in lines {
+            writeln!(file, "{}", line).unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    fn path_of(directory: &TempDir, name: &str) -> PathBuf {
+        directory.path().join(name)
+    }
+
+    #[test]
+    fn it_resolves_files_directories_and_glob_patterns() {
+        let directory = TempDir::new().unwrap();
+        let a = write_file(&directory, "app-a.log", &["a"]);
+        write_file(&directory, "app-b.log", &["b"]);
+        write_file(&directory, "other.txt", &["c"]);
+
+        assert_eq!(resolve(std::slice::from_ref(&a)).unwrap(), vec!(path_of(&directory, "app-a.log")));
+
+        let pattern = directory.path().join("app-*.log").to_string_lossy().into_owned();
+        assert_eq!(resolve(&[pattern]).unwrap(), vec!(path_of(&directory, "app-a.log"), path_of(&directory, "app-b.log")));
+
+        let whole_directory = directory.path().to_string_lossy().into_owned();
+        assert_eq!(resolve(&[a, whole_directory]).unwrap(), vec!(path_of(&directory, "app-a.log"), path_of(&directory, "app-b.log"), path_of(&directory, "other.txt")));
+    }
+
+    #[test]
+    fn it_fails_for_missing_files_and_patterns_without_matches() {
+        let directory = TempDir::new().unwrap();
+        assert!(resolve(&[directory.path().join("missing.log").to_string_lossy().into_owned()]).is_err());
+        assert!(resolve(&[directory.path().join("*.gz").to_string_lossy().into_owned()]).is_err());
+    }
+
+    #[test]
+    fn it_reads_records_with_line_numbers_skipping_blank_lines() {
+        let directory = TempDir::new().unwrap();
+        let path = write_file(&directory, "app.log", &["level=info", "", "level=error"]);
+        let records: Vec<Record> = FileRecords::open(Path::new(&path)).unwrap().map(Result::unwrap).collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].get("level"), Value::Str("error".into()));
+        assert_eq!(records[1].line, 3);
+        assert_eq!(*records[1].file, path);
+    }
+
+    #[test]
+    fn it_joins_continuation_lines_into_their_record() {
+        let directory = TempDir::new().unwrap();
+        let path = write_file(&directory, "app.log", &["level=error msg=boom", "  at main.rs:10", "", "\tat lib.rs:2", "level=info msg=ok"]);
+        let records: Vec<Record> = FileRecords::open(Path::new(&path)).unwrap().map(Result::unwrap).collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].get("msg"), Value::Str("boom".into()));
+        assert_eq!(records[0].get("at"), Value::Null);
+        assert_eq!((records[0].line, records[1].line), (1, 5));
+    }
+
+    #[test]
+    fn it_reads_records_from_streams() {
+        let directory = TempDir::new().unwrap();
+        let path = write_file(&directory, "app.log", &["n=2"]);
+        let stream = io::Cursor::new(b"n=1\nn=3 msg=boom\n  at main.rs:10\n".to_vec());
+
+        let records = MergedRecords::new(vec!(FileRecords::open(Path::new(&path)).unwrap(), FileRecords::from_reader(STDIN, Box::new(stream))));
+        let files: Vec<(Value, Value)> = records.map(|record| record.unwrap()).map(|record| (record.get("_file"), record.get("n"))).collect();
+        assert_eq!(files, vec!(
+            (Value::Str(path), Value::Integer(2)),
+            (Value::Str("-".into()), Value::Integer(1)),
+            (Value::Str("-".into()), Value::Integer(3))
+        ));
+    }
+
+    #[test]
+    fn it_keeps_stdin_among_the_sources() {
+        let directory = TempDir::new().unwrap();
+        let path = write_file(&directory, "app.log", &["n=1"]);
+
+        assert_eq!(resolve(&[STDIN.to_string(), path.clone()]).unwrap(), vec!(PathBuf::from("-"), path_of(&directory, "app.log")));
+        assert!(resolve(&[STDIN.to_string(), STDIN.to_string()]).is_err());
+        assert!(open_reversed(&[STDIN.to_string()]).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_merges_files_by_timestamp() {
+        let directory = TempDir::new().unwrap();
+        let a = write_file(&directory, "a.log", &["ts=2018-03-04T10:00:00Z n=1", "ts=2018-03-04T10:00:03Z n=4", "n=5"]);
+        let b = write_file(&directory, "b.log", &["ts=2018-03-04T10:00:01Z n=2", "ts=2018-03-04T10:00:02Z n=3", "ts=2018-03-04T10:00:04Z n=6"]);
+
+        let order: Vec<Value> = open(&[a, b]).unwrap().map(|record| record.unwrap().get("n")).collect();
+        let expected: Vec<Value> = (1..7).map(Value::Integer).collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn it_merges_files_read_backwards_in_reverse_order() {
+        let directory = TempDir::new().unwrap();
+        let a = write_file(&directory, "a.log", &["ts=2018-03-04T10:00:00Z n=1", "ts=2018-03-04T10:00:02Z n=3", "ts=2018-03-04T10:00:04Z n=5"]);
+        let b = write_file(&directory, "b.log", &["ts=2018-03-04T10:00:01Z n=2", "ts=2018-03-04T10:00:03Z n=4", "ts=2018-03-04T10:00:04Z n=6"]);
+
+        let forwards: Vec<Value> = open(&[a.clone(), b.clone()]).unwrap().map(|record| record.unwrap().get("n")).collect();
+        let mut backwards: Vec<Value> = open_reversed(&[a, b]).unwrap().unwrap().map(|record| record.unwrap().get("n")).collect();
+        backwards.reverse();
+        assert_eq!(backwards, forwards);
+    }
+
+    #[test]
+    fn it_only_reads_uncompressed_files_backwards() {
+        let directory = TempDir::new().unwrap();
+        write_compressed(&directory, "app.log", Compression::None, &["n=2"]);
+        write_compressed(&directory, "app.log.1.gz", Compression::Gzip, &["n=1"]);
+
+        assert!(open_reversed(&[path_of(&directory, "app.log").to_string_lossy().into_owned()]).unwrap().is_some());
+        assert!(open_reversed(&[directory.path().to_string_lossy().into_owned()]).unwrap().is_none());
+    }
+
+    fn write_compressed(directory: &TempDir, name: &str, compression: Compression, lines: &[&str]) {
+        let content: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        let file = File::create(directory.path().join(name)).unwrap();
+        match compression {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                encoder.write_all(content.as_bytes()).unwrap();
+                encoder.finish().unwrap();
+            },
+            Compression::Zstd => {
+                zstd::stream::copy_encode(content.as_bytes(), file, 0).unwrap();
+            },
+            Compression::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(file, bzip2::Compression::default());
+                encoder.write_all(content.as_bytes()).unwrap();
+                encoder.finish().unwrap();
+            },
+            Compression::None => {
+                let mut file = file;
+                file.write_all(content.as_bytes()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn it_detects_compression_from_magic_bytes() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 0x08]), Compression::Gzip);
+        assert_eq!(Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd]), Compression::Zstd);
+        assert_eq!(Compression::detect(b"BZh91AY"), Compression::Bzip2);
+        assert_eq!(Compression::detect(b"level=info"), Compression::None);
+        assert_eq!(Compression::detect(b""), Compression::None);
+    }
+
+    #[test]
+    fn it_reads_compressed_files_regardless_of_extension() {
+        let directory = TempDir::new().unwrap();
+        write_compressed(&directory, "a.log", Compression::Gzip, &["n=1"]);
+        write_compressed(&directory, "b.log", Compression::Zstd, &["n=2"]);
+        write_compressed(&directory, "c.log", Compression::Bzip2, &["n=3", "n=4"]);
+
+        let records: Vec<Value> = open(&[directory.path().to_string_lossy().into_owned()]).unwrap().map(|record| record.unwrap().get("n")).collect();
+        assert_eq!(records, (1..5).map(Value::Integer).collect::<Vec<Value>>());
+    }
+
+    #[test]
+    fn it_orders_rotated_files_chronologically() {
+        let directory = TempDir::new().unwrap();
+        write_compressed(&directory, "app.log", Compression::None, &["n=5"]);
+        write_compressed(&directory, "app.log.1", Compression::None, &["n=4"]);
+        write_compressed(&directory, "app.log.2.gz", Compression::Gzip, &["n=3"]);
+        write_compressed(&directory, "app.log.10.zst", Compression::Zstd, &["n=1", "n=2"]);
+
+        let pattern = directory.path().join("app.log*").to_string_lossy().into_owned();
+        let names: Vec<PathBuf> = resolve(std::slice::from_ref(&pattern)).unwrap();
+        assert_eq!(names, vec!(path_of(&directory, "app.log.10.zst"), path_of(&directory, "app.log.2.gz"), path_of(&directory, "app.log.1"), path_of(&directory, "app.log")));
+
+        let records: Vec<Value> = open(&[pattern]).unwrap().map(|record| record.unwrap().get("n")).collect();
+        assert_eq!(records, (1..6).map(Value::Integer).collect::<Vec<Value>>());
+    }
+
+    #[test]
+    fn it_orders_date_suffixed_rotations_before_the_current_file() {
+        let directory = TempDir::new().unwrap();
+        write_compressed(&directory, "app.log", Compression::None, &["n=3"]);
+        write_compressed(&directory, "app.log-20180305.gz", Compression::Gzip, &["n=2"]);
+        write_compressed(&directory, "app.log-20180304", Compression::None, &["n=1"]);
+
+        let pattern = directory.path().join("app.log*").to_string_lossy().into_owned();
+        assert_eq!(resolve(&[pattern]).unwrap(), vec!(path_of(&directory, "app.log-20180304"), path_of(&directory, "app.log-20180305.gz"), path_of(&directory, "app.log")));
+    }
+}